@@ -0,0 +1,118 @@
+//! Installing a third-party repository's signing key (eg. for NVIDIA's CUDA repository at
+//! `developer.download.nvidia.com`) before the package index is refreshed (`--apt-key`)
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::Ordering;
+
+use crate::config::Config;
+use crate::error::UpdateNvidiaError;
+use crate::log::log;
+use crate::ppa::apt_source_paths;
+use crate::process::{check_call, check_output, DRY_RUN};
+
+/// Where APT looks for the keys of additional repositories
+const TRUSTED_KEYS_DIR: &str = "/etc/apt/trusted.gpg.d";
+
+/// The line an ASCII-armored key starts with, which APT can't read from a `.gpg` file
+const ARMOR_HEADER: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----";
+
+/// The path [`setup_apt_key`] should install `key_path` at (`<name>.gpg` in
+/// [`TRUSTED_KEYS_DIR`])
+pub fn keyring_path(key_path: &Path) -> PathBuf {
+    let name = key_path.file_stem().unwrap_or(key_path.as_os_str());
+    Path::new(TRUSTED_KEYS_DIR).join(name).with_extension("gpg")
+}
+
+/// Install the key in `key_path` as `keyring_path`, dearmoring it with `gpg` if necessary
+///
+/// Before anything is written, at least one of the key's user IDs must have an email address at
+/// (or above) the host of an APT source, so a key which was mixed up with another repository's
+/// (or swapped for a malicious one) isn't trusted for everything. Returns whether the keyring is
+/// new, in which case the package index needs refreshing regardless of how fresh it otherwise is.
+pub fn setup_apt_key(
+    config: &Config,
+    key_path: &Path,
+    keyring_path: &Path,
+) -> Result<bool, UpdateNvidiaError> {
+    let key = fs::read(key_path)?;
+    let domains = parse_key_domains(&check_output!(
+        Command::new(&config.gpg_path)
+            .args(["--batch", "--with-colons", "--show-keys"])
+            .arg(key_path),
+        UpdateNvidiaError::GpgFailed
+    )?);
+    let hosts = apt_source_hosts(config)?;
+    let matches = |domain: &String| {
+        hosts.iter().any(|host| {
+            host == domain || host.strip_suffix(domain.as_str()).is_some_and(|s| s.ends_with('.'))
+        })
+    };
+    if !domains.iter().any(matches) {
+        return Err(UpdateNvidiaError::AptKeyMismatch {
+            path: key_path.to_owned(),
+            domains: domains.into_iter().collect(),
+        });
+    }
+
+    let is_new = !keyring_path.exists();
+    if String::from_utf8_lossy(&key).contains(ARMOR_HEADER) {
+        log!("Installing {} as {} (dearmored)", key_path.display(), keyring_path.display());
+        check_call!(
+            Command::new(&config.gpg_path)
+                .args(["--batch", "--yes", "--dearmor", "--output"])
+                .arg(keyring_path)
+                .arg(key_path),
+            UpdateNvidiaError::GpgFailed
+        )?;
+    } else if !is_new && fs::read(keyring_path)? == key {
+        log!("{} is already installed as {}.", key_path.display(), keyring_path.display());
+    } else if DRY_RUN.load(Ordering::Relaxed) {
+        log!("[DRY RUN] would copy {} to {}", key_path.display(), keyring_path.display());
+    } else {
+        log!("Installing {} as {}", key_path.display(), keyring_path.display());
+        fs::copy(key_path, keyring_path)?;
+    }
+    Ok(is_new)
+}
+
+/// Extract the (lowercased) email domains of the user IDs in `gpg --with-colons` output
+fn parse_key_domains(colons: &str) -> BTreeSet<String> {
+    colons
+        .lines()
+        .filter_map(|line| line.strip_prefix("uid:"))
+        // The user ID is field 10, and the `uid` prefix was field 1
+        .filter_map(|fields| fields.split(':').nth(8))
+        .filter_map(|user_id| user_id.rsplit_once('@'))
+        .map(|(_, domain)| domain.trim_end_matches('>').to_ascii_lowercase())
+        .filter(|domain| !domain.is_empty())
+        .collect()
+}
+
+/// The hosts of every URL in the APT sources (or just `sources_list`, if that's set)
+fn apt_source_hosts(config: &Config) -> io::Result<BTreeSet<String>> {
+    let paths = match &config.sources_list {
+        Some(path) => vec![path.clone()],
+        None => apt_source_paths()?,
+    };
+    let mut hosts = BTreeSet::new();
+    for path in paths {
+        let text = match fs::read_to_string(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            result => result?,
+        };
+        // Covers both `deb http://host/path ...` lines and deb822 `URIs: http://host/path` ones
+        for line in text.lines().map(str::trim_start).filter(|line| !line.starts_with('#')) {
+            for (_, rest) in line.split_whitespace().filter_map(|word| word.split_once("://")) {
+                let authority = rest.split(['/', ']']).next().unwrap_or(rest);
+                let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+                let host = host.split(':').next().unwrap_or(host);
+                hosts.insert(host.to_ascii_lowercase());
+            }
+        }
+    }
+    Ok(hosts)
+}
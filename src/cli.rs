@@ -0,0 +1,1059 @@
+//! Command-line argument parsing, `--help` output, and shell completion generation
+//!
+//! Hand-written rather than derived with `clap` to keep this tool free of non-std dependencies.
+//! [`OPTIONS`] and [`SUBCOMMANDS`] are the single source of truth for `--help` and the completion
+//! scripts, so any new flag or subcommand must be added there as well as in [`Args::parse`].
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::config::DEFAULT_CONFIG_PATH;
+use crate::email::is_email_address;
+use crate::exit_code;
+use crate::log::LogFormat;
+use crate::packages::{is_apt_option, parse_apt_option_args, NvidiaVariant, PackageManagerKind};
+use crate::ppa::parse_ppa;
+use crate::process::is_env_var_name;
+use crate::{
+    ADD_APT_REPOSITORY_PATH, APT_CACHE_PATH, APT_GET_PATH, APT_MARK_PATH, APT_UPDATE_MTIME_PATH,
+    DKMS_PATH, DNF_PATH, DPKG_QUERY_PATH, GPG_PATH, MODPROBE_PATH, MOKUTIL_PATH, NOTIFY_SEND_PATH,
+    NVIDIA_SMI_PATH, PACMAN_PATH, REBOOT_PATH, RMMOD_PATH, RPM_PATH, SENDMAIL_PATH, SHUTDOWN_PATH,
+    SYSTEMCTL_PATH, SYSTEMD_INHIBIT_PATH, UBUNTU_DRIVERS_PATH, ZYPPER_PATH,
+};
+
+/// The name completion scripts should be registered for
+const BIN_NAME: &str = env!("CARGO_PKG_NAME");
+
+/// What kind of value an option takes, for use by completion scripts
+#[derive(Debug, Clone, Copy)]
+pub enum ValueKind {
+    /// A filesystem path
+    Path,
+    /// One of a fixed list of words
+    Choice(&'static [&'static str]),
+    /// Free-form text (including numbers) which can't be usefully completed
+    Text,
+}
+
+/// Description of a single command-line option
+#[derive(Debug)]
+pub struct OptionSpec {
+    /// Single-character alias (without the leading `-`)
+    pub short: Option<char>,
+    /// Long name (without the leading `--`)
+    pub long: &'static str,
+    /// Placeholder name and kind of the option's argument, if it takes one
+    pub value: Option<(&'static str, ValueKind)>,
+    /// One-line description for `--help`
+    pub help: &'static str,
+}
+
+/// Every option [`Args::parse`] accepts, in the order they should appear in `--help`
+pub const OPTIONS: &[OptionSpec] = &[
+    OptionSpec { short: Some('h'), long: "help", value: None, help: "Show this message" },
+    OptionSpec {
+        short: None,
+        long: "accept-new-packages",
+        value: None,
+        help: "Let the upgrade install new NVIDIA packages even if the config file forbids it",
+    },
+    OptionSpec {
+        short: None,
+        long: "allow-display-interrupt",
+        value: None,
+        help: "Reload the module even if an X server or Wayland compositor is running",
+    },
+    OptionSpec {
+        short: None,
+        long: "apt-option",
+        value: Some(("key=value", ValueKind::Text)),
+        help: "Pass -o <key=value> (eg. Acquire::Retries=3) to every apt-get command (repeatable)",
+    },
+    OptionSpec {
+        short: None,
+        long: "apt-options",
+        value: Some(("extra_args", ValueKind::Text)),
+        help: "Pass space-separated -o <key=value> pairs to every apt-get command",
+    },
+    OptionSpec {
+        short: None,
+        long: "apt-key",
+        value: Some(("path", ValueKind::Path)),
+        help: "Install a third-party repository's signing key before refreshing the package index",
+    },
+    OptionSpec {
+        short: None,
+        long: "assume-yes",
+        value: None,
+        help: "Upgrade without asking for confirmation (required without a terminal)",
+    },
+    OptionSpec {
+        short: None,
+        long: "backup-settings-dir",
+        value: Some(("path", ValueKind::Path)),
+        help: "Back up GPU settings files under <path> before upgrading",
+    },
+    OptionSpec {
+        short: None,
+        long: "check-interval",
+        value: Some(("hours", ValueKind::Text)),
+        help: "Keep running, checking for upgrades every <hours> until SIGTERM or SIGINT",
+    },
+    OptionSpec {
+        short: None,
+        long: "check-only",
+        value: None,
+        help: "Exit 0 if an NVIDIA upgrade is available, 1 if not, or 2 on error",
+    },
+    OptionSpec {
+        short: None,
+        long: "clean-cache",
+        value: None,
+        help: "Delete the downloaded packages after a successful upgrade",
+    },
+    OptionSpec {
+        short: None,
+        long: "config",
+        value: Some(("path", ValueKind::Path)),
+        help: "Load settings from <path> instead of the default config file",
+    },
+    OptionSpec {
+        short: None,
+        long: "cuda-compat",
+        value: None,
+        help: "Also upgrade any cuda-compat-* packages along with the driver",
+    },
+    OptionSpec {
+        short: None,
+        long: "daily",
+        value: None,
+        help: "With --schedule, run daily via a systemd timer",
+    },
+    OptionSpec {
+        short: None,
+        long: "driver-variant",
+        value: Some(("variant", ValueKind::Choice(NvidiaVariant::NAMES))),
+        help: "Manage the prop(rietary) or open kernel module's packages instead of detecting it",
+    },
+    OptionSpec {
+        short: None,
+        long: "dry-run",
+        value: None,
+        help: "Show commands that would alter the system instead of running them",
+    },
+    OptionSpec {
+        short: None,
+        long: "email",
+        value: Some(("address", ValueKind::Text)),
+        help: "Email a report to <address> via sendmail when an upgrade succeeds or fails",
+    },
+    OptionSpec {
+        short: None,
+        long: "force-reload",
+        value: None,
+        help: "Reload the kernel module even if nothing was upgraded (disruptive)",
+    },
+    OptionSpec {
+        short: None,
+        long: "force-update",
+        value: None,
+        help: "Refresh the package index even if it was updated recently",
+    },
+    OptionSpec {
+        short: None,
+        long: "full-env",
+        value: None,
+        help: "Pass this process's whole environment to commands instead of a minimal one",
+    },
+    OptionSpec {
+        short: None,
+        long: "generate-completions",
+        value: Some(("shell", ValueKind::Choice(Shell::NAMES))),
+        help: "Print a completion script for bash, zsh, or fish to stdout",
+    },
+    OptionSpec {
+        short: None,
+        long: "hold-new-packages",
+        value: None,
+        help: "Verify every package is held after upgrading, retrying any that aren't",
+    },
+    OptionSpec {
+        short: None,
+        long: "hold-pattern",
+        value: Some(("glob", ValueKind::Text)),
+        help: "Also hold and unhold packages matching <glob> (may be repeated)",
+    },
+    OptionSpec {
+        short: None,
+        long: "ignore-packages",
+        value: Some(("names", ValueKind::Text)),
+        help: "Leave the comma-separated packages out of holding and upgrading (may be repeated)",
+    },
+    OptionSpec {
+        short: None,
+        long: "ignore-virtualization",
+        value: None,
+        help: "Reload the module even when running in a container or a VM without a GPU",
+    },
+    OptionSpec {
+        short: Some('i'),
+        long: "interactive",
+        value: None,
+        help: "Ask before unholding, upgrading, unloading, loading, or rebooting",
+    },
+    OptionSpec {
+        short: None,
+        long: "json",
+        value: None,
+        help: "Print a JSON summary of the results to stdout on completion",
+    },
+    OptionSpec {
+        short: None,
+        long: "keep-apt-cache",
+        value: None,
+        help: "Keep the downloaded packages, even if clean_apt_cache is set",
+    },
+    OptionSpec {
+        short: None,
+        long: "kernel-check",
+        value: None,
+        help: "Warn before upgrading if the running kernel's headers aren't installed",
+    },
+    OptionSpec {
+        short: None,
+        long: "kill-users",
+        value: None,
+        help: "SIGTERM processes using the GPU if they prevent a module reload",
+    },
+    OptionSpec {
+        short: None,
+        long: "list-available",
+        value: None,
+        help: "Print the installed and candidate versions of upgradable NVIDIA packages",
+    },
+    OptionSpec {
+        short: None,
+        long: "log-file",
+        value: Some(("path", ValueKind::Path)),
+        help: "Also append timestamped status messages to <path>",
+    },
+    OptionSpec {
+        short: None,
+        long: "mark-only",
+        value: None,
+        help: "Don't actually update packages. Just re-hold packages.",
+    },
+    OptionSpec {
+        short: None,
+        long: "max-version",
+        value: Some(("version", ValueKind::Text)),
+        help: "Don't upgrade if the driver would go past <version> (eg. 520 or 1:520.61)",
+    },
+    OptionSpec {
+        short: None,
+        long: "migrate-hold",
+        value: None,
+        help: "Hold any NVIDIA packages an interrupted run left unheld, without upgrading",
+    },
+    OptionSpec {
+        short: None,
+        long: "no-dist-upgrade",
+        value: None,
+        help: "Use apt-get upgrade (never removes or adds packages) instead of dist-upgrade",
+    },
+    OptionSpec {
+        short: None,
+        long: "no-module-reload",
+        value: None,
+        help: "Never reload the module or reboot. Record in the state file that a reboot is due.",
+    },
+    OptionSpec {
+        short: None,
+        long: "no-reboot",
+        value: None,
+        help: "Exit with an error instead of rebooting if the module can't be reloaded",
+    },
+    OptionSpec {
+        short: None,
+        long: "nvidia-module",
+        value: Some(("name", ValueKind::Text)),
+        help: "Unload and reload <name> instead of the nvidia kernel module",
+    },
+    OptionSpec {
+        short: None,
+        long: "on-boot",
+        value: None,
+        help: "With --schedule, run at every boot",
+    },
+    OptionSpec {
+        short: None,
+        long: "only-if-running",
+        value: None,
+        help: "Do nothing (successfully) unless the nvidia kernel module is loaded",
+    },
+    OptionSpec {
+        short: None,
+        long: "output-format",
+        value: Some(("format", ValueKind::Choice(LogFormat::NAMES))),
+        help: "Write status messages as text (default) or json on stderr, or to syslog",
+    },
+    OptionSpec {
+        short: None,
+        long: "package-manager",
+        value: Some(("name", ValueKind::Choice(PackageManagerKind::NAMES))),
+        help: "Use apt, dnf, zypper, or pacman instead of auto-detecting which is installed",
+    },
+    OptionSpec {
+        short: None,
+        long: "package-versions-file",
+        value: Some(("path", ValueKind::Path)),
+        help: "Append each run's old and new package versions to <path>",
+    },
+    OptionSpec {
+        short: None,
+        long: "packages-only",
+        value: None,
+        help: "Upgrade the packages but leave reloading the module (or rebooting) to you",
+    },
+    OptionSpec {
+        short: None,
+        long: "ppa",
+        value: Some(("repo", ValueKind::Text)),
+        help: "Add <repo> (eg. ppa:graphics-drivers/ppa) to the APT sources and upgrade from it",
+    },
+    OptionSpec {
+        short: None,
+        long: "preserve-env",
+        value: Some(("var", ValueKind::Text)),
+        help: "Also pass <var> (eg. GNUPGHOME) to commands (may be repeated)",
+    },
+    OptionSpec {
+        short: None,
+        long: "pretend-version",
+        // It takes two values, so the placeholder names both for `--help`
+        value: Some(("from> <to", ValueKind::Text)),
+        help: "Dry-run as if package:version <from> were upgraded to <to>, for testing",
+    },
+    OptionSpec {
+        short: None,
+        long: "reboot-delay",
+        value: Some(("seconds", ValueKind::Text)),
+        help: "Reboot via shutdown -r with a warning to logged-in users (default: 0)",
+    },
+    OptionSpec {
+        short: None,
+        long: "reboot-on-verify-failure",
+        value: None,
+        help: "Check the reloaded driver with nvidia-smi -L, and reboot if it sees no GPU",
+    },
+    OptionSpec {
+        short: None,
+        long: "restore-settings",
+        value: None,
+        help: "Restore backed-up GPU settings files if an upgrade changed them",
+    },
+    OptionSpec {
+        short: None,
+        long: "rollback",
+        value: None,
+        help: "Downgrade to the versions from before the last run (or --rollback=<time>)",
+    },
+    OptionSpec {
+        short: None,
+        long: "schedule",
+        value: None,
+        help: "Install systemd units to run this at boot (--on-boot) and/or daily (--daily)",
+    },
+    OptionSpec {
+        short: None,
+        long: "secure-boot-check",
+        value: None,
+        help: "Refuse to upgrade DKMS drivers under Secure Boot without a signing key",
+    },
+    OptionSpec {
+        short: None,
+        long: "show-history",
+        value: None,
+        help: "Print the runs recorded by --package-versions-file as a table",
+    },
+    OptionSpec {
+        short: None,
+        long: "simulate",
+        value: None,
+        help: "Ask the package manager what an upgrade would change, but change nothing",
+    },
+    OptionSpec {
+        short: None,
+        long: "simulate-reboot",
+        value: None,
+        help: "Write /tmp/update_nvidia_reboot_simulated instead of rebooting (test builds only)",
+    },
+    OptionSpec {
+        short: None,
+        long: "skip-update",
+        value: None,
+        help: "Don't refresh the package index (eg. because a calling script just did)",
+    },
+    OptionSpec {
+        short: None,
+        long: "sources-list",
+        value: Some(("path", ValueKind::Path)),
+        help: "Update and upgrade only from the APT sources listed in <path>",
+    },
+    OptionSpec {
+        short: None,
+        long: "state-file",
+        value: Some(("path", ValueKind::Path)),
+        help: "Record the results of each run in <path> as JSON",
+    },
+    OptionSpec {
+        short: None,
+        long: "systemd-notify",
+        value: None,
+        help: "Report progress to systemd via $NOTIFY_SOCKET (for systemctl status)",
+    },
+    OptionSpec {
+        short: None,
+        long: "timeout",
+        value: Some(("seconds", ValueKind::Text)),
+        help: "Kill any command (eg. a hung apt-get or rmmod) which runs longer than this",
+    },
+    OptionSpec {
+        short: None,
+        long: "ubuntu-drivers",
+        value: None,
+        help: "Install the recommended driver with ubuntu-drivers autoinstall before upgrading",
+    },
+    OptionSpec {
+        short: None,
+        long: "unschedule",
+        value: None,
+        help: "Disable and remove the systemd units --schedule installed",
+    },
+    OptionSpec {
+        short: Some('v'),
+        long: "verbose",
+        value: None,
+        help: "Print every command, its output, and its exit status as it runs",
+    },
+];
+
+/// Every subcommand [`Args::parse`] accepts, with its one-line description for `--help`
+pub const SUBCOMMANDS: &[(&str, &str)] = &[
+    ("report", "Print everything useful for a bug report without changing anything"),
+    ("status", "Report package versions, holds, and module state without changing anything"),
+];
+
+/// An alternative to the default behaviour of upgrading the packages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subcommand {
+    /// Report the current state of the system without making any changes
+    Status,
+    /// Print a detailed diagnostic report for bug reports without making any changes
+    Report,
+}
+
+impl FromStr for Subcommand {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(Subcommand::Report),
+            "status" => Ok(Subcommand::Status),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Shells [`Shell::completion_script`] can generate completions for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    /// The values accepted by `FromStr`
+    const NAMES: &'static [&'static str] = &["bash", "zsh", "fish"];
+
+    /// Generate a completion script for this tool's options
+    pub fn completion_script(self) -> String {
+        match self {
+            Shell::Bash => bash_completions(),
+            Shell::Zsh => zsh_completions(),
+            Shell::Fish => fish_completions(),
+        }
+    }
+}
+
+impl FromStr for Shell {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The parsed command line
+#[derive(Debug, Default)]
+pub struct Args {
+    /// `-h`/`--help`: Print usage information and exit
+    pub help: bool,
+    /// `--accept-new-packages`: Override `accept_new_packages = false` in the config file
+    pub accept_new_packages: bool,
+    /// `--allow-display-interrupt`: Reload the module even if it'd end a graphical session
+    pub allow_display_interrupt: bool,
+    /// `--apt-option <key=value>`: An APT setting to pass to every `apt-get` command with `-o`
+    pub apt_extra_options: Vec<String>,
+    /// `--apt-key <path>`: Trust a third-party repository's key (eg. NVIDIA's CUDA repository)
+    pub apt_key: Option<PathBuf>,
+    /// `--assume-yes`: Upgrade without showing the changes and asking for confirmation
+    pub assume_yes: bool,
+    /// `--backup-settings-dir <path>`: Copy GPU settings files somewhere else before upgrading
+    pub backup_settings_dir: Option<PathBuf>,
+    /// `--check-interval <hours>`: Re-run every `<hours>` hours instead of exiting after one run
+    pub check_interval: Option<u64>,
+    /// `--check-only`: Print available NVIDIA upgrades and report them via the exit code
+    pub check_only: bool,
+    /// `--clean-cache`: Delete the downloaded packages once they've been installed
+    pub clean_cache: bool,
+    /// `--config <path>`: Load settings from a non-default path
+    pub config: Option<PathBuf>,
+    /// `--cuda-compat`: Hold, unhold, and upgrade `cuda-compat-*` along with the driver
+    pub cuda_compat: bool,
+    /// `--daily`: Have `--schedule` install a timer to run daily
+    pub daily: bool,
+    /// `--driver-variant <variant>`: Override detection of which kernel module is installed
+    pub driver_variant: Option<NvidiaVariant>,
+    /// `--dry-run`: Report commands which would alter the system instead of running them
+    pub dry_run: bool,
+    /// `--email <address>`: Send upgrade reports to an administrator's mailbox
+    pub email: Option<String>,
+    /// `--force-reload`: Reload the kernel module (or reboot) even if nothing was upgraded
+    pub force_reload: bool,
+    /// `--force-update`: Refresh the package index regardless of how recently it was updated
+    pub force_update: bool,
+    /// `--full-env`: Don't strip the environment commands inherit down to a minimal set
+    pub full_env: bool,
+    /// `--generate-completions <shell>`: Print a completion script and exit
+    pub generate_completions: Option<Shell>,
+    /// `--hold-new-packages`: Double-check the holds (including on new packages) after upgrading
+    pub hold_new_packages: bool,
+    /// `--hold-pattern <glob>`: Extra packages (eg. `cuda-toolkit-*`) to hold along with the driver
+    pub hold_patterns: Vec<String>,
+    /// `--ignore-packages <names>`: Packages (eg. `nvidia-prime`) to leave unmanaged
+    pub ignore_packages: Vec<String>,
+    /// `--ignore-virtualization`: Reload the module even if there seems to be no GPU to use it
+    pub ignore_virtualization: bool,
+    /// `-i`/`--interactive`: Pause for Enter before each command which could disrupt the system
+    pub interactive: bool,
+    /// `--json`: Print a machine-readable summary on stdout at completion
+    pub json: bool,
+    /// `--keep-apt-cache`: Override `clean_apt_cache` to leave the downloaded packages alone
+    pub keep_apt_cache: bool,
+    /// `--kernel-check`: Make sure DKMS will be able to build the module for the running kernel
+    pub kernel_check: bool,
+    /// `--kill-users`: `SIGTERM` processes keeping the module in use before giving up and rebooting
+    pub kill_users: bool,
+    /// `--list-available`: Print the versions an upgrade would install instead of upgrading
+    pub list_available: bool,
+    /// `--log-file <path>`: Tee status messages into a timestamped log file
+    pub log_file: Option<PathBuf>,
+    /// `--mark-only`: Don't update anything. Just refresh the package holds.
+    pub mark_only: bool,
+    /// `--migrate-hold`: Hold whatever an interrupted run left unheld, without upgrading
+    pub migrate_hold: bool,
+    /// `--max-version <version>`: Refuse to upgrade the driver past this version or series
+    pub max_version: Option<String>,
+    /// `--no-dist-upgrade`: Upgrade with `apt-get upgrade` rather than `apt-get dist-upgrade`
+    pub no_dist_upgrade: bool,
+    /// `--no-module-reload`: Leave the module for a reboot scheduled by something else
+    pub no_module_reload: bool,
+    /// `--no-reboot`: Fail rather than rebooting if the module can't be reloaded
+    pub no_reboot: bool,
+    /// `--nvidia-module <name>`: Reload a differently-named kernel module (eg. `nvidia-current`)
+    pub nvidia_module: Option<String>,
+    /// `--on-boot`: Have `--schedule` enable the service to run at every boot
+    pub on_boot: bool,
+    /// `--only-if-running`: Skip the upgrade unless the kernel module is currently loaded
+    pub only_if_running: bool,
+    /// `--output-format <format>`: Write status messages as JSON or to syslog instead of text
+    pub output_format: Option<LogFormat>,
+    /// `--package-manager <name>`: Override auto-detection of the package management backend
+    pub package_manager: Option<PackageManagerKind>,
+    /// `--package-versions-file <path>`: Keep a history of package versions for auditing
+    pub package_versions_file: Option<PathBuf>,
+    /// `--packages-only`: Upgrade without reloading the module or rebooting, and report whether
+    /// that's still needed
+    pub packages_only: bool,
+    /// `--ppa <repo>`: Add a PPA with newer drivers than the distro provides
+    pub ppa: Option<String>,
+    /// `--preserve-env <var>`: Extra environment variables for commands to inherit
+    pub preserve_env: Vec<String>,
+    /// `--pretend-version <from> <to>`: Fake `<package>:<version>` pairs to report before and
+    /// after the upgrade, as the `(from, to)` maps
+    pub pretend_versions: (BTreeMap<String, String>, BTreeMap<String, String>),
+    /// `--reboot-delay <seconds>`: Warn logged-in users and wait before rebooting
+    pub reboot_delay: Option<u64>,
+    /// `--reboot-on-verify-failure`: Verify the reloaded driver and reboot if it isn't working
+    pub reboot_on_verify_failure: bool,
+    /// `--restore-settings`: Put back GPU settings files which an upgrade changed
+    pub restore_settings: bool,
+    /// `--rollback[=<timestamp>]`: Downgrade to the versions recorded before an earlier run
+    pub rollback: bool,
+    /// The `<timestamp>` in `--rollback=<timestamp>`, to roll back the last run at or before it
+    pub rollback_before: Option<String>,
+    /// `--schedule`: Install and enable systemd units to run this tool, then exit
+    pub schedule: bool,
+    /// `--secure-boot-check`: Don't upgrade into a DKMS module the kernel would refuse to load
+    pub secure_boot_check: bool,
+    /// `--show-history`: Print the versions file instead of upgrading
+    pub show_history: bool,
+    /// `--simulate`: Report what the package manager says an upgrade would change
+    pub simulate: bool,
+    /// `--simulate-reboot`: Write a marker file instead of rebooting (`simulation` feature only)
+    pub simulate_reboot: bool,
+    /// `--skip-update`: Upgrade from the package index as it is, without refreshing it
+    pub skip_update: bool,
+    /// `--sources-list <path>`: Use a dedicated `sources.list` (eg. for a local mirror) with APT
+    pub sources_list: Option<PathBuf>,
+    /// `--state-file <path>`: Record run results somewhere other than the default path
+    pub state_file: Option<PathBuf>,
+    /// `--systemd-notify`: Send `STATUS=` and `READY=1` messages to the service manager
+    pub systemd_notify: bool,
+    /// `--timeout <seconds>`: Kill hung subprocesses (eg. a stuck postinst) instead of waiting
+    pub timeout: Option<u64>,
+    /// `--ubuntu-drivers`: Run `ubuntu-drivers autoinstall` before the upgrade
+    pub ubuntu_drivers: bool,
+    /// `--unschedule`: Disable and remove the units installed by `--schedule`, then exit
+    pub unschedule: bool,
+    /// `-v`/`--verbose`: Echo every command and its exit status
+    pub verbose: bool,
+    /// `<command>`: Do something other than upgrading the packages (eg. `status`)
+    pub subcommand: Option<Subcommand>,
+}
+
+impl Args {
+    /// Parse the given arguments (not including `argv[0]`)
+    ///
+    /// Option values may be given either as `--name value` or `--name=value`.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, CliError> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (name, mut inline_value) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => {
+                    (name.to_owned(), Some(value.to_owned()))
+                },
+                _ => (arg, None),
+            };
+            let mut value = || {
+                inline_value
+                    .take()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| CliError::MissingValue(name.clone()))
+            };
+
+            match name.as_str() {
+                "-h" | "--help" => parsed.help = true,
+                "--accept-new-packages" => parsed.accept_new_packages = true,
+                "--allow-display-interrupt" => parsed.allow_display_interrupt = true,
+                "--apt-option" => {
+                    let option = value()?;
+                    if !is_apt_option(&option) {
+                        return Err(CliError::InvalidValue {
+                            option: name.clone(),
+                            value: option,
+                            expected: "Key::SubKey=Value".to_owned(),
+                        });
+                    }
+                    parsed.apt_extra_options.push(option);
+                },
+                "--apt-options" => {
+                    let args = value()?;
+                    let words: Vec<String> = args.split_whitespace().map(str::to_owned).collect();
+                    let options =
+                        parse_apt_option_args(&words).map_err(|_| CliError::InvalidValue {
+                            option: name.clone(),
+                            value: args,
+                            expected: "-o Key::SubKey=Value pairs".to_owned(),
+                        })?;
+                    parsed.apt_extra_options.extend(options);
+                },
+                "--apt-key" => parsed.apt_key = Some(value()?.into()),
+                "--assume-yes" => parsed.assume_yes = true,
+                "--backup-settings-dir" => parsed.backup_settings_dir = Some(value()?.into()),
+                "--check-interval" => {
+                    let hours = value()?;
+                    parsed.check_interval =
+                        Some(hours.parse().ok().filter(|&hours| hours > 0).ok_or_else(|| {
+                            CliError::InvalidValue {
+                                option: name.clone(),
+                                value: hours,
+                                expected: "a whole number of hours greater than 0".to_owned(),
+                            }
+                        })?);
+                },
+                "--check-only" => parsed.check_only = true,
+                "--clean-cache" => parsed.clean_cache = true,
+                "--config" => parsed.config = Some(value()?.into()),
+                "--cuda-compat" => parsed.cuda_compat = true,
+                "--daily" => parsed.daily = true,
+                "--driver-variant" => {
+                    let variant = value()?;
+                    parsed.driver_variant =
+                        Some(variant.parse().map_err(|_| CliError::InvalidValue {
+                            option: name.clone(),
+                            value: variant,
+                            expected: format!("one of: {}", NvidiaVariant::NAMES.join(", ")),
+                        })?);
+                },
+                "--dry-run" => parsed.dry_run = true,
+                "--email" => {
+                    let address = value()?;
+                    if !is_email_address(&address) {
+                        return Err(CliError::InvalidValue {
+                            option: name.clone(),
+                            value: address,
+                            expected: "an address like admin@example.com".to_owned(),
+                        });
+                    }
+                    parsed.email = Some(address);
+                },
+                "--force-reload" => parsed.force_reload = true,
+                "--force-update" => parsed.force_update = true,
+                "--full-env" => parsed.full_env = true,
+                "--generate-completions" => {
+                    let shell = value()?;
+                    parsed.generate_completions =
+                        Some(shell.parse().map_err(|_| CliError::InvalidValue {
+                            option: name.clone(),
+                            value: shell,
+                            expected: format!("one of: {}", Shell::NAMES.join(", ")),
+                        })?);
+                },
+                "--hold-new-packages" => parsed.hold_new_packages = true,
+                "--hold-pattern" => parsed.hold_patterns.push(value()?),
+                "--ignore-packages" => parsed.ignore_packages.extend(
+                    value()?
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .map(str::to_owned),
+                ),
+                "--ignore-virtualization" => parsed.ignore_virtualization = true,
+                "-i" | "--interactive" => parsed.interactive = true,
+                "--json" => parsed.json = true,
+                "--keep-apt-cache" => parsed.keep_apt_cache = true,
+                "--kernel-check" => parsed.kernel_check = true,
+                "--kill-users" => parsed.kill_users = true,
+                "--list-available" => parsed.list_available = true,
+                "--log-file" => parsed.log_file = Some(value()?.into()),
+                "--mark-only" => parsed.mark_only = true,
+                "--migrate-hold" => parsed.migrate_hold = true,
+                "--max-version" => parsed.max_version = Some(value()?),
+                "--no-dist-upgrade" => parsed.no_dist_upgrade = true,
+                "--no-module-reload" => parsed.no_module_reload = true,
+                "--no-reboot" => parsed.no_reboot = true,
+                "--nvidia-module" => parsed.nvidia_module = Some(value()?),
+                "--on-boot" => parsed.on_boot = true,
+                "--only-if-running" => parsed.only_if_running = true,
+                "--output-format" => {
+                    let format = value()?;
+                    parsed.output_format =
+                        Some(format.parse().map_err(|_| CliError::InvalidValue {
+                            option: name.clone(),
+                            value: format,
+                            expected: format!("one of: {}", LogFormat::NAMES.join(", ")),
+                        })?);
+                },
+                "--package-manager" => {
+                    let kind = value()?;
+                    parsed.package_manager =
+                        Some(kind.parse().map_err(|_| CliError::InvalidValue {
+                            option: name.clone(),
+                            value: kind,
+                            expected: format!("one of: {}", PackageManagerKind::NAMES.join(", ")),
+                        })?);
+                },
+                "--package-versions-file" => parsed.package_versions_file = Some(value()?.into()),
+                "--packages-only" => parsed.packages_only = true,
+                "--ppa" => {
+                    let ppa = value()?;
+                    if parse_ppa(&ppa).is_none() {
+                        return Err(CliError::InvalidValue {
+                            option: name.clone(),
+                            value: ppa,
+                            expected: "ppa:<user>/<name>".to_owned(),
+                        });
+                    }
+                    parsed.ppa = Some(ppa);
+                },
+                "--preserve-env" => {
+                    let var = value()?;
+                    if !is_env_var_name(&var) {
+                        return Err(CliError::InvalidValue {
+                            option: name.clone(),
+                            value: var,
+                            expected: "an environment variable name".to_owned(),
+                        });
+                    }
+                    parsed.preserve_env.push(var);
+                },
+                "--pretend-version" => {
+                    for versions in [&mut parsed.pretend_versions.0, &mut parsed.pretend_versions.1]
+                    {
+                        let (package, version) = parse_package_version(&name, value()?)?;
+                        versions.insert(package, version);
+                    }
+                },
+                "--reboot-delay" => parsed.reboot_delay = Some(parse_number(&name, value()?)?),
+                "--reboot-on-verify-failure" => parsed.reboot_on_verify_failure = true,
+                "--restore-settings" => parsed.restore_settings = true,
+                "--rollback" => {
+                    // The value is optional, so it's only accepted in its `--name=value` form
+                    parsed.rollback = true;
+                    parsed.rollback_before = inline_value.take();
+                },
+                "--schedule" => parsed.schedule = true,
+                "--secure-boot-check" => parsed.secure_boot_check = true,
+                "--show-history" => parsed.show_history = true,
+                "--simulate" => parsed.simulate = true,
+                "--simulate-reboot" => parsed.simulate_reboot = true,
+                "--skip-update" => parsed.skip_update = true,
+                "--sources-list" => parsed.sources_list = Some(value()?.into()),
+                "--state-file" => parsed.state_file = Some(value()?.into()),
+                "--systemd-notify" => parsed.systemd_notify = true,
+                "--timeout" => parsed.timeout = Some(parse_number(&name, value()?)?),
+                "--ubuntu-drivers" => parsed.ubuntu_drivers = true,
+                "--unschedule" => parsed.unschedule = true,
+                "-v" | "--verbose" => parsed.verbose = true,
+                _ if !name.starts_with('-') && parsed.subcommand.is_none() => {
+                    parsed.subcommand =
+                        Some(name.parse().map_err(|_| CliError::Unrecognized(name.clone()))?);
+                },
+                _ => return Err(CliError::Unrecognized(name)),
+            }
+
+            if inline_value.is_some() {
+                return Err(CliError::UnexpectedValue(name));
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// A command line which couldn't be parsed
+#[derive(Debug)]
+pub enum CliError {
+    /// An option which doesn't exist
+    Unrecognized(String),
+    /// An option which requires a value was the last argument
+    MissingValue(String),
+    /// An option which doesn't take a value was given one via `--name=value`
+    UnexpectedValue(String),
+    /// An option's value wasn't one of the accepted choices
+    InvalidValue { option: String, value: String, expected: String },
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unrecognized(arg) => write!(f, "unrecognized argument {:?}", arg),
+            Self::MissingValue(opt) => write!(f, "{} requires a value", opt),
+            Self::UnexpectedValue(opt) => write!(f, "{} does not take a value", opt),
+            Self::InvalidValue { option, value, expected } => {
+                write!(f, "invalid value {:?} for {} (expected {})", value, option, expected)
+            },
+        }
+    }
+}
+impl Error for CliError {}
+
+/// Parse the value of an option which takes a non-negative integer
+fn parse_number(option: &str, value: String) -> Result<u64, CliError> {
+    value.parse().map_err(|_| CliError::InvalidValue {
+        option: option.to_owned(),
+        value,
+        expected: "a non-negative integer".to_owned(),
+    })
+}
+
+/// Parse the `<package>:<version>` value of `--pretend-version`
+///
+/// Only the first `:` separates them, since a version may start with an epoch (eg. `1:2.0-1`).
+fn parse_package_version(option: &str, value: String) -> Result<(String, String), CliError> {
+    match value.split_once(':') {
+        Some((package, version)) if !package.is_empty() && !version.is_empty() => {
+            Ok((package.to_owned(), version.to_owned()))
+        },
+        _ => Err(CliError::InvalidValue {
+            option: option.to_owned(),
+            value,
+            expected: "<package>:<version>".to_owned(),
+        }),
+    }
+}
+
+/// Render the left-hand column of `--help` for an option (eg. `-h | --help`)
+fn option_synopsis(opt: &OptionSpec) -> String {
+    let mut synopsis = match opt.short {
+        Some(short) => format!("-{} | --{}", short, opt.long),
+        None => format!("--{}", opt.long),
+    };
+    if let Some((name, _)) = opt.value {
+        synopsis.push_str(&format!(" <{}>", name));
+    }
+    synopsis
+}
+
+/// Print the `--help` output, using `cmd` (ie. `argv[0]`) as the program name
+pub fn print_help(cmd: &str) {
+    println!("Usage: {} [OPTIONS] [COMMAND]\n", cmd);
+    for opt in OPTIONS {
+        println!("    {:<30} {}", option_synopsis(opt), opt.help);
+    }
+    println!("\nCommands (the default is to upgrade the packages):\n");
+    for (name, help) in SUBCOMMANDS {
+        println!("    {:<30} {}", name, help);
+    }
+    println!("\nRequired external dependencies:\n");
+    println!("    - {}", APT_GET_PATH);
+    println!("    - {}", APT_MARK_PATH);
+    println!("    - {}", DPKG_QUERY_PATH);
+    println!("    - {} (or {})", MODPROBE_PATH, REBOOT_PATH);
+    println!("    - {} (or {})", RMMOD_PATH, REBOOT_PATH);
+    println!("    - {} (instead of {} with --reboot-delay)", SHUTDOWN_PATH, REBOOT_PATH);
+    println!("\nOn Fedora/RHEL, these replace apt-get, apt-mark, and dpkg-query:\n");
+    println!("    - {} (with the versionlock plugin)", DNF_PATH);
+    println!("    - {}", RPM_PATH);
+    println!("\nOn openSUSE/SLES, these replace apt-get, apt-mark, and dpkg-query:\n");
+    println!("    - {}", ZYPPER_PATH);
+    println!("    - {}", RPM_PATH);
+    println!("\nOn Arch Linux, this replaces apt-get, apt-mark, and dpkg-query:\n");
+    println!("    - {}", PACMAN_PATH);
+    println!("\nOptional external dependencies:\n");
+    println!("    - {} (mtime is checked to judge package index staleness)", APT_UPDATE_MTIME_PATH);
+    println!("    - {} (to build the module when using nvidia-dkms)", DKMS_PATH);
+    println!("    - {} (for --max-version)", APT_CACHE_PATH);
+    println!("    - {} (for --ppa)", ADD_APT_REPOSITORY_PATH);
+    println!("    - {} (for --apt-key)", GPG_PATH);
+    println!("    - {} (for --ubuntu-drivers)", UBUNTU_DRIVERS_PATH);
+    println!("    - {} (for the notify_send config option)", NOTIFY_SEND_PATH);
+    println!("    - {} (for --email)", SENDMAIL_PATH);
+    println!("    - {} (for the report subcommand)", NVIDIA_SMI_PATH);
+    println!("    - {} (to check for Secure Boot if efivarfs isn't mounted)", MOKUTIL_PATH);
+    println!("    - {} (for --schedule and --unschedule)", SYSTEMCTL_PATH);
+    println!("    - {} (to block shutdown and sleep during the upgrade)", SYSTEMD_INHIBIT_PATH);
+    println!("\nOptional configuration file:\n");
+    println!("    - {} (overrides any of the above paths)", DEFAULT_CONFIG_PATH);
+    println!("\nPath overrides for testing (insecure in production):\n");
+    println!(
+        "    - $UPDATE_NVIDIA_<COMMAND> (eg. $UPDATE_NVIDIA_APT_GET, overriding the config file)"
+    );
+    println!("\nExit codes:\n");
+    for (code, meaning) in exit_code::DESCRIPTIONS {
+        println!("    {:<5} {}", code, meaning);
+    }
+    println!("\nExit codes with --check-only:\n");
+    for (code, meaning) in exit_code::CHECK_DESCRIPTIONS {
+        println!("    {:<5} {}", code, meaning);
+    }
+}
+
+fn bash_completions() -> String {
+    let func = format!("_{}", BIN_NAME);
+    let mut script = format!("{}() {{\n", func);
+    script.push_str(
+        "    local cur=\"${COMP_WORDS[COMP_CWORD]}\" prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n",
+    );
+    script.push_str("    case \"$prev\" in\n");
+    for opt in OPTIONS {
+        let reply = match opt.value {
+            Some((_, ValueKind::Path)) => "$(compgen -f -- \"$cur\")".to_owned(),
+            Some((_, ValueKind::Choice(words))) => {
+                format!("$(compgen -W \"{}\" -- \"$cur\")", words.join(" "))
+            },
+            Some((_, ValueKind::Text)) => String::new(),
+            None => continue,
+        };
+        script.push_str(&format!("        --{}) COMPREPLY=({}); return ;;\n", opt.long, reply));
+    }
+    script.push_str("    esac\n");
+    let words: Vec<String> = OPTIONS
+        .iter()
+        .flat_map(|opt| {
+            opt.short.map(|c| format!("-{}", c)).into_iter().chain([format!("--{}", opt.long)])
+        })
+        .chain(SUBCOMMANDS.iter().map(|(name, _)| (*name).to_owned()))
+        .collect();
+    script
+        .push_str(&format!("    COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n", words.join(" ")));
+    script.push_str(&format!("}}\ncomplete -F {} {}\n", func, BIN_NAME));
+    script
+}
+
+fn zsh_completions() -> String {
+    let mut script = format!("#compdef {}\n\n_arguments \\\n", BIN_NAME);
+    for opt in OPTIONS {
+        // Escape for a single-quoted zsh string and for _arguments' own [description] syntax
+        let help = opt.help.replace('\'', r"'\''").replace('[', r"\[").replace(']', r"\]");
+        let action = match opt.value {
+            Some((name, ValueKind::Path)) => format!(":{}:_files", name),
+            Some((name, ValueKind::Choice(words))) => format!(":{}:({})", name, words.join(" ")),
+            Some((name, ValueKind::Text)) => format!(":{}: ", name),
+            None => String::new(),
+        };
+        let names = match opt.short {
+            Some(c) => format!("(-{c} --{long})'{{-{c},--{long}}}'", c = c, long = opt.long),
+            None => format!("--{}", opt.long),
+        };
+        script.push_str(&format!("    '{}[{}]{}' \\\n", names, help, action));
+    }
+    let commands: Vec<&str> = SUBCOMMANDS.iter().map(|(name, _)| *name).collect();
+    script.push_str(&format!("    '::command:({})' \\\n", commands.join(" ")));
+    script.truncate(script.len() - " \\\n".len());
+    script.push('\n');
+    script
+}
+
+fn fish_completions() -> String {
+    let mut script = String::new();
+    for opt in OPTIONS {
+        script.push_str(&format!("complete -c {} -l {}", BIN_NAME, opt.long));
+        if let Some(short) = opt.short {
+            script.push_str(&format!(" -s {}", short));
+        }
+        match opt.value {
+            Some((_, ValueKind::Path)) => script.push_str(" -r -F"),
+            Some((_, ValueKind::Choice(words))) => {
+                script.push_str(&format!(" -x -a '{}'", words.join(" ")));
+            },
+            Some((_, ValueKind::Text)) => script.push_str(" -x"),
+            None => {},
+        }
+        script.push_str(&format!(" -d '{}'\n", fish_quote(opt.help)));
+    }
+    for (name, help) in SUBCOMMANDS {
+        script.push_str(&format!(
+            "complete -c {} -n __fish_use_subcommand -f -a {} -d '{}'\n",
+            BIN_NAME,
+            name,
+            fish_quote(help)
+        ));
+    }
+    script
+}
+
+/// Escape text for use inside a single-quoted fish string
+fn fish_quote(text: &str) -> String {
+    text.replace('\\', r"\\").replace('\'', r"\'")
+}
@@ -0,0 +1,39 @@
+//! `--check-only`: Report whether an NVIDIA driver upgrade is available, for scripts and CI
+
+use crate::config::Config;
+use crate::error::UpdateNvidiaError;
+use crate::log::log;
+use crate::packages::{is_eligible, PackageManager};
+use crate::refresh_index;
+
+/// Refresh the package index (if stale) and print the eligible packages an upgrade would change
+/// to stdout as `name new_version` lines
+///
+/// Returns whether there were any. Under `--dry-run` (or if the network is down), the index is
+/// left as-is, as it is without `can_refresh`, since only root can update it and the check
+/// shouldn't need root.
+pub fn check_nvidia_upgrade_available(
+    config: &Config,
+    package_manager: &dyn PackageManager,
+    can_refresh: bool,
+) -> Result<bool, UpdateNvidiaError> {
+    if can_refresh {
+        refresh_index(config, package_manager)?;
+    } else {
+        log!("WARNING: Not running as root, so checking against the package index as it is.");
+    }
+
+    log!("Checking for NVIDIA package upgrades...");
+    let changes: Vec<_> = package_manager
+        .simulate_upgrade()?
+        .into_iter()
+        .filter(|change| is_eligible(config, &change.name))
+        .collect();
+    for change in &changes {
+        println!("{} {}", change.name, change.new_version);
+    }
+    if changes.is_empty() {
+        log!("No NVIDIA package upgrades are available.");
+    }
+    Ok(!changes.is_empty())
+}
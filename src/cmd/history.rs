@@ -0,0 +1,47 @@
+//! `--show-history`: Print the versions file written by `--package-versions-file` as a table
+
+use std::path::Path;
+
+use crate::error::UpdateNvidiaError;
+use crate::history::read_history;
+
+/// Print one row per recorded run, with one line per package whose version changed
+pub fn run(path: &Path) -> Result<(), UpdateNvidiaError> {
+    let entries = read_history(path)?;
+    if entries.is_empty() {
+        println!("No runs have been recorded in {} yet.", path.display());
+        return Ok(());
+    }
+
+    let action_width =
+        entries.iter().map(|entry| entry.action.len()).chain(["ACTION".len()]).max().unwrap_or(0);
+    println!("{:<20}  {:<aw$}  CHANGES", "TIME", "ACTION", aw = action_width);
+    for entry in &entries {
+        let mut changes: Vec<String> = entry
+            .new_versions
+            .iter()
+            .filter(|(name, new)| entry.old_versions.get(*name) != Some(*new))
+            .map(|(name, new)| match entry.old_versions.get(name) {
+                Some(old) => format!("{} {} -> {}", name, old, new),
+                None => format!("{} (new) -> {}", name, new),
+            })
+            .collect();
+        changes.extend(
+            entry
+                .old_versions
+                .iter()
+                .filter(|(name, _)| !entry.new_versions.contains_key(*name))
+                .map(|(name, old)| format!("{} {} -> (removed)", name, old)),
+        );
+        if changes.is_empty() {
+            changes.push("(no changes)".to_owned());
+        }
+
+        for (idx, change) in changes.iter().enumerate() {
+            let (time, action) =
+                if idx == 0 { (entry.timestamp.as_str(), entry.action.as_str()) } else { ("", "") };
+            println!("{:<20}  {:<aw$}  {}", time, action, change, aw = action_width);
+        }
+    }
+    Ok(())
+}
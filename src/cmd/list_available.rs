@@ -0,0 +1,86 @@
+//! `--list-available`: Show which driver versions an upgrade would install, without holding,
+//! unholding, or (unless `--force-update` is also given) refreshing anything
+
+use std::cmp::Ordering;
+
+use crate::config::Config;
+use crate::error::UpdateNvidiaError;
+use crate::log::log;
+use crate::packages::{dpkg_version_cmp, is_eligible, PackageManager};
+use crate::refresh_index;
+
+/// An installed NVIDIA package with a newer version available
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AvailableUpgrade {
+    pub package: String,
+    pub installed: String,
+    pub candidate: String,
+}
+
+/// Ask `apt-cache policy` for the candidate version of each installed eligible package, keeping
+/// the ones newer than what's installed
+///
+/// Holds don't affect `apt-cache policy`'s candidates, so this reports what an upgrade would
+/// install once the packages have been unheld.
+pub fn list_available_nvidia_upgrades(
+    config: &Config,
+    package_manager: &dyn PackageManager,
+) -> Result<Vec<AvailableUpgrade>, UpdateNvidiaError> {
+    let mut upgrades = Vec::new();
+    for (package, installed) in package_manager.get_nvidia_packages()? {
+        if !is_eligible(config, &package) {
+            continue;
+        }
+        let Some(candidate) = package_manager.candidate_version(std::slice::from_ref(&package))?
+        else {
+            continue;
+        };
+        if dpkg_version_cmp(&candidate, &installed) == Ordering::Greater {
+            upgrades.push(AvailableUpgrade { package, installed, candidate });
+        }
+    }
+    Ok(upgrades)
+}
+
+/// Print [`list_available_nvidia_upgrades`] as a table on stdout, refreshing the package index
+/// first if `update` (`--force-update`) is set
+pub fn run(
+    config: &Config,
+    package_manager: &dyn PackageManager,
+    update: bool,
+) -> Result<(), UpdateNvidiaError> {
+    if update {
+        refresh_index(config, package_manager)?;
+    }
+
+    log!("Checking for available NVIDIA package versions...");
+    let upgrades = list_available_nvidia_upgrades(config, package_manager)?;
+    if upgrades.is_empty() {
+        println!("No NVIDIA package upgrades are available.");
+        return Ok(());
+    }
+
+    let width = |header: &str, column: fn(&AvailableUpgrade) -> &str| {
+        upgrades.iter().map(|upgrade| column(upgrade).len()).max().unwrap_or(0).max(header.len())
+    };
+    let package_width = width("Package", |upgrade| &upgrade.package);
+    let installed_width = width("Installed", |upgrade| &upgrade.installed);
+    println!(
+        "{:<pw$}  {:<iw$}  Candidate",
+        "Package",
+        "Installed",
+        pw = package_width,
+        iw = installed_width
+    );
+    for upgrade in &upgrades {
+        println!(
+            "{:<pw$}  {:<iw$}  {}",
+            upgrade.package,
+            upgrade.installed,
+            upgrade.candidate,
+            pw = package_width,
+            iw = installed_width
+        );
+    }
+    Ok(())
+}
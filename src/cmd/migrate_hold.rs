@@ -0,0 +1,36 @@
+//! `--migrate-hold`: Re-hold packages left unheld by a run which was killed partway through
+//! (eg. by a power failure) before it could re-hold them
+
+use std::sync::atomic::Ordering;
+
+use crate::config::Config;
+use crate::error::UpdateNvidiaError;
+use crate::get_extra_hold_packages;
+use crate::lock::LockFile;
+use crate::log::log;
+use crate::packages::PackageManager;
+use crate::process::DRY_RUN;
+
+/// Hold every installed package a normal run would hold which isn't currently held
+///
+/// Nothing is upgraded or un-held, so this is safe to run at any time. The lock file is still
+/// taken, so packages a concurrent run un-held on purpose aren't re-held out from under it.
+pub fn run(config: &Config, package_manager: &dyn PackageManager) -> Result<(), UpdateNvidiaError> {
+    let _lock = if DRY_RUN.load(Ordering::Relaxed) {
+        None
+    } else {
+        Some(LockFile::acquire(&config.lock_file_path)?)
+    };
+
+    let packages = package_manager.get_nvidia_packages()?;
+    let mut names: Vec<String> = packages.keys().cloned().collect();
+    names.extend(get_extra_hold_packages(config, package_manager, &packages)?);
+    let held = package_manager.get_held_packages(&names)?;
+    let unheld: Vec<String> = names.into_iter().filter(|name| !held.contains(name)).collect();
+    if unheld.is_empty() {
+        log!("Every NVIDIA package is already held. Nothing to recover.");
+        return Ok(());
+    }
+    log!("Re-holding packages left unheld: {}", unheld.join(" "));
+    package_manager.hold_packages(&unheld)
+}
@@ -0,0 +1,10 @@
+//! Subcommands which can be run instead of the default upgrade
+
+pub mod check;
+pub mod history;
+pub mod list_available;
+pub mod migrate_hold;
+pub mod report;
+pub mod rollback;
+pub mod simulate;
+pub mod status;
@@ -0,0 +1,113 @@
+//! `report`: Gather everything useful for a bug report into one labelled dump on stdout, without
+//! changing anything
+
+use std::path::Path;
+use std::process::Command;
+
+use crate::cmd::status::{print_index_age, print_last_run, print_package_table};
+use crate::config::Config;
+use crate::disk::available_bytes;
+use crate::dkms::running_kernel;
+use crate::error::UpdateNvidiaError;
+use crate::packages::{PackageManager, PackageManagerKind};
+use crate::process::{redact_credentials, run_output};
+use crate::procfs::list_loaded_nvidia_modules;
+use crate::secure_boot::{check_secure_boot_status, SecureBootStatus};
+
+/// The filesystem packages are downloaded and unpacked on
+const VAR_PATH: &str = "/var";
+
+/// Print the state of the packages, the kernel module, and this tool, one section at a time
+///
+/// A section which can't be gathered says why, rather than cutting the rest of the report short.
+pub fn run(config: &Config, package_manager: &dyn PackageManager) -> Result<(), UpdateNvidiaError> {
+    println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+
+    heading("Packages");
+    println!("Package manager: {}", package_manager.kind().as_str());
+    let packages = package_manager
+        .get_nvidia_packages()
+        .and_then(|packages| Ok((packages, package_manager.get_held_nvidia_packages()?)));
+    match packages {
+        Ok((packages, held)) => print_package_table(&packages, &held),
+        Err(e) => println!("Could not list the NVIDIA packages: {}", e),
+    }
+
+    heading("Kernel");
+    match running_kernel() {
+        Ok(kernel) => println!("Running kernel: {}", kernel),
+        Err(e) => println!("Running kernel: unknown ({})", e),
+    }
+    let modules = list_loaded_nvidia_modules();
+    if modules.is_empty() {
+        println!("Loaded NVIDIA modules: none");
+    } else {
+        println!("Loaded NVIDIA modules:");
+    }
+    for module in modules {
+        println!("    - {}", module);
+    }
+    match check_secure_boot_status(config) {
+        Ok(SecureBootStatus::Enabled) => println!("Secure Boot: enabled"),
+        Ok(SecureBootStatus::Disabled) => println!("Secure Boot: disabled"),
+        Ok(SecureBootStatus::NotEfi) => println!("Secure Boot: not applicable (booted via BIOS)"),
+        Err(e) => println!("Secure Boot: unknown ({})", e),
+    }
+
+    heading("DKMS");
+    print_command_output(Command::new(&config.dkms_path).arg("status"), |line| {
+        line.starts_with("nvidia")
+    });
+
+    heading("nvidia-smi");
+    print_command_output(&mut Command::new(&config.nvidia_smi_path), |_| true);
+
+    heading("Disk space");
+    match available_bytes(Path::new(VAR_PATH)) {
+        Ok(bytes) => println!("Available on {}: {} MiB", VAR_PATH, bytes / (1024 * 1024)),
+        Err(e) => println!("Available on {}: unknown ({})", VAR_PATH, e),
+    }
+
+    heading("Package index");
+    if package_manager.kind() == PackageManagerKind::Apt {
+        print_index_age(config);
+    } else {
+        println!(
+            "Only tracked for apt, since the other package managers refresh their own metadata"
+        );
+    }
+
+    heading("Last run");
+    print_last_run(config);
+
+    heading("Configuration");
+    println!("{}", redact_credentials(&format!("{:#?}", config)));
+    Ok(())
+}
+
+/// Print a section heading, preceded by a blank line to separate it from the previous section
+fn heading(title: &str) {
+    println!("\n=== {} ===", title);
+}
+
+/// Print the lines of `cmd`'s stdout which satisfy `filter`, or why there aren't any
+fn print_command_output(cmd: &mut Command, filter: impl Fn(&str) -> bool) {
+    let program = Path::new(cmd.get_program()).display().to_string();
+    match run_output(cmd) {
+        Ok(output) if output.status.success() => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let lines: Vec<&str> = stdout.lines().filter(|line| filter(line)).collect();
+            if lines.is_empty() {
+                println!("(nothing relevant in the output of {})", program);
+            }
+            for line in lines {
+                println!("{}", line);
+            }
+        },
+        Ok(output) => println!("{} failed ({})", program, output.status),
+        Err(UpdateNvidiaError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("(not available: {} is not installed)", program)
+        },
+        Err(e) => println!("Could not run {}: {}", program, e),
+    }
+}
@@ -0,0 +1,97 @@
+//! `--rollback`: Downgrade the NVIDIA packages to the versions from before an earlier run
+
+use std::collections::BTreeMap;
+
+use crate::config::Config;
+use crate::error::UpdateNvidiaError;
+use crate::history::read_history;
+use crate::log::log;
+use crate::packages::PackageManager;
+use crate::state::State;
+use crate::systemd;
+use crate::{confirm_changes, UnholdGuard, UpgradeOutcome};
+
+/// Pick the versions to roll back to: those installed before the most recent run that changed
+/// something (or the most recent one at or before `before`, an RFC 3339 timestamp)
+///
+/// Without a `package_versions_file`, the state file's record of the last run is used instead,
+/// which only allows undoing that one run.
+pub fn select_target(
+    config: &Config,
+    before: Option<&str>,
+) -> Result<BTreeMap<String, String>, UpdateNvidiaError> {
+    let Some(path) = &config.package_versions_file else {
+        if before.is_some() {
+            return Err(UpdateNvidiaError::NoRollbackTarget(
+                "rolling back to a timestamp requires package_versions_file".into(),
+            ));
+        }
+        let state = State::read(&config.state_file_path)?;
+        if state.old_versions == state.new_versions {
+            return Err(UpdateNvidiaError::NoRollbackTarget(format!(
+                "the last run ({}) didn't change anything",
+                state.last_run_utc
+            )));
+        }
+        return Ok(state.old_versions);
+    };
+
+    // RFC 3339 UTC timestamps in the same format sort correctly as strings
+    read_history(path)?
+        .into_iter()
+        .rev()
+        .filter(|entry| before.is_none_or(|before| entry.timestamp.as_str() <= before))
+        .find(|entry| entry.old_versions != entry.new_versions)
+        .map(|entry| {
+            log!("Rolling back the run recorded at {}", entry.timestamp);
+            entry.old_versions
+        })
+        .ok_or_else(|| {
+            UpdateNvidiaError::NoRollbackTarget(format!(
+                "no run which changed package versions is recorded in {}{}",
+                path.display(),
+                before.map(|before| format!(" at or before {}", before)).unwrap_or_default()
+            ))
+        })
+}
+
+/// Install `target_versions` with the packages temporarily unheld, just like an upgrade
+///
+/// Packages that are already at their target version or no longer installed are left alone.
+pub fn run(
+    config: &Config,
+    package_manager: &dyn PackageManager,
+    target_versions: &BTreeMap<String, String>,
+) -> Result<UpgradeOutcome, UpdateNvidiaError> {
+    let old_versions = package_manager.get_nvidia_packages()?;
+    let mut changes = target_versions.clone();
+    changes.retain(|name, version| old_versions.get(name).is_some_and(|old| old != version));
+    if changes.is_empty() {
+        log!("The installed versions already match the rollback target. Nothing to do.");
+        return Ok(UpgradeOutcome {
+            new_versions: old_versions.clone(),
+            old_versions,
+            settings_backup: None,
+            left_unheld: Vec::new(),
+        });
+    }
+
+    confirm_changes(config, "The rollback will make the following changes:", || {
+        Ok(changes
+            .iter()
+            .map(|(name, version)| format!("{} {} -> {}", name, old_versions[name], version))
+            .collect())
+    })?;
+
+    let mut unhold_guard =
+        UnholdGuard::new(package_manager, old_versions.keys().cloned().collect())?;
+    systemd::notify("READY=1\nSTATUS=Installing the previous package versions...");
+    log!("Installing the previous package versions...");
+    package_manager.install_versions(&changes)?;
+
+    log!("Getting updated list of eligible packages");
+    let new_versions = package_manager.get_nvidia_packages()?;
+    unhold_guard.extend(new_versions.keys().cloned());
+    let left_unheld = unhold_guard.previously_unheld.clone();
+    Ok(UpgradeOutcome { old_versions, new_versions, settings_backup: None, left_unheld })
+}
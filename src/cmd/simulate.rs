@@ -0,0 +1,53 @@
+//! `--simulate`: Ask the package manager what an upgrade would change without changing anything
+//!
+//! Unlike `--dry-run`, this actually runs the package manager (in its own simulation mode), so
+//! it can report real version numbers.
+
+use crate::config::Config;
+use crate::error::UpdateNvidiaError;
+use crate::log::log;
+use crate::output::format_version_diff;
+use crate::packages::{is_eligible, version_maps, PackageManager};
+
+/// Print every package an upgrade would change, marking the eligible ones, and whether a module
+/// reload would be needed as a result
+pub fn run(config: &Config, package_manager: &dyn PackageManager) -> Result<(), UpdateNvidiaError> {
+    // Refreshing the index would alter the system, so this is only as current as the last update
+    log!("Simulating upgrade against the current package index...");
+    let changes = package_manager.simulate_upgrade()?;
+    if changes.is_empty() {
+        println!("No packages would be upgraded.");
+        return Ok(());
+    }
+
+    let name_width = changes.iter().map(|change| change.name.len()).max().unwrap_or(0);
+    println!("The following packages would be upgraded (* = eligible NVIDIA package):\n");
+    for change in &changes {
+        let marker = if is_eligible(config, &change.name) { '*' } else { ' ' };
+        let old_version = change.old_version.as_deref().unwrap_or("(not installed)");
+        println!(
+            "  {} {:<w$}  {} -> {}",
+            marker,
+            change.name,
+            old_version,
+            change.new_version,
+            w = name_width
+        );
+    }
+
+    if changes.iter().any(|change| is_eligible(config, &change.name)) {
+        let (old, new) =
+            version_maps(changes.iter().filter(|change| is_eligible(config, &change.name)));
+        println!("\nNVIDIA package changes:\n");
+        for line in format_version_diff(&old, &new).lines() {
+            println!("  {}", line);
+        }
+        println!(
+            "\nA normal run would then need to reload {} (or reboot if it's in use).",
+            config.nvidia_kmod_name
+        );
+    } else {
+        println!("\nNo NVIDIA packages would be upgraded, so no module reload would be needed.");
+    }
+    Ok(())
+}
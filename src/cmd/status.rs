@@ -0,0 +1,190 @@
+//! `status`: Report on the system without holding, unholding, or upgrading anything
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, SystemTime};
+
+use crate::config::Config;
+use crate::error::UpdateNvidiaError;
+use crate::log::format_rfc3339;
+use crate::packages::{driver_variant, PackageManager, PackageManagerKind};
+use crate::procfs::{is_module_loaded, list_loaded_nvidia_modules};
+use crate::state::State;
+
+/// Print the eligible packages and their holds, the package index's age, the kernel module's
+/// state, and what a normal run would do about them
+pub fn run(config: &Config, package_manager: &dyn PackageManager) -> Result<(), UpdateNvidiaError> {
+    let packages = package_manager.get_nvidia_packages()?;
+    let held = package_manager.get_held_nvidia_packages()?;
+
+    print_package_table(&packages, &held);
+    print_ignored_packages(config, package_manager);
+    println!();
+
+    let kind = package_manager.kind();
+    println!("Package manager: {}", kind.as_str());
+    let stale = kind != PackageManagerKind::Apt || print_index_age(config);
+    let source = if config.driver_variant.is_some() { "set" } else { "detected" };
+    println!("Driver variant: {} ({})", driver_variant(config, &packages).as_str(), source);
+
+    let module = &config.nvidia_kmod_name;
+    let module_loaded = is_module_loaded(module).map_err(|e| e.to_string());
+    match &module_loaded {
+        Ok(true) => println!("Kernel module {}: loaded", module),
+        Ok(false) => println!("Kernel module {}: not loaded", module),
+        Err(e) => {
+            println!("Kernel module {}: unknown (could not read /proc/modules: {})", module, e)
+        },
+    }
+    for module in list_loaded_nvidia_modules() {
+        println!("    - {}", module);
+    }
+
+    print_last_run(config);
+
+    println!("\nA normal run would:");
+    match kind {
+        PackageManagerKind::Apt if stale => println!("    - Update the package index"),
+        PackageManagerKind::Apt => {},
+        PackageManagerKind::Dnf => {
+            println!("    - Refresh any package metadata dnf considers expired")
+        },
+        PackageManagerKind::Zypper => println!("    - Refresh the repository metadata"),
+        PackageManagerKind::Pacman => println!("    - Refresh the sync databases"),
+    }
+    if packages.is_empty() {
+        println!("    - Do nothing else, since there are no packages to upgrade");
+        return Ok(());
+    }
+    println!("    - Un-hold, upgrade, and re-hold {} package(s)", packages.len());
+    let unheld: Vec<&str> =
+        packages.keys().filter(|name| !held.contains(*name)).map(String::as_str).collect();
+    if !unheld.is_empty() {
+        println!("    - Leave these currently un-held packages un-held: {}", unheld.join(" "));
+    }
+    if module_loaded == Ok(false) {
+        println!("    - Leave {} unloaded, even if it was upgraded", module);
+    } else {
+        let fallback = if config.no_reboot { "exit with an error" } else { "reboot" };
+        println!(
+            "    - If anything was upgraded, reload {} (or {} if it's in use)",
+            module, fallback
+        );
+    }
+    Ok(())
+}
+
+/// Print the packages in `packages` as a table, marking which of them are in `held`
+pub fn print_package_table(packages: &BTreeMap<String, String>, held: &BTreeSet<String>) {
+    if packages.is_empty() {
+        println!("No NVIDIA packages are installed.");
+    } else {
+        let name_width = packages.keys().map(String::len).chain(["PACKAGE".len()]).max();
+        let version_width = packages.values().map(String::len).chain(["VERSION".len()]).max();
+        let (name_width, version_width) = (name_width.unwrap_or(0), version_width.unwrap_or(0));
+        println!(
+            "{:<nw$}  {:<vw$}  HELD",
+            "PACKAGE",
+            "VERSION",
+            nw = name_width,
+            vw = version_width
+        );
+        for (name, version) in packages {
+            let is_held = if held.contains(name) { "yes" } else { "no" };
+            println!(
+                "{:<nw$}  {:<vw$}  {}",
+                name,
+                version,
+                is_held,
+                nw = name_width,
+                vw = version_width
+            );
+        }
+    }
+}
+
+/// List whichever `ignore_packages` are installed, so it's clear they're there but unmanaged
+fn print_ignored_packages(config: &Config, package_manager: &dyn PackageManager) {
+    let mut ignored = BTreeMap::new();
+    for name in &config.ignore_packages {
+        match package_manager.get_packages_matching(name) {
+            Ok(matches) => ignored.extend(matches.into_iter().filter(|(n, _)| n == name)),
+            Err(e) => println!("Could not look up ignored package {}: {}", name, e),
+        }
+    }
+    if ignored.is_empty() {
+        return;
+    }
+    println!("\nIgnored (left unheld and upgraded along with everything else):");
+    for (name, version) in ignored {
+        println!("    - {} {}", name, version);
+    }
+}
+
+/// Print when the APT package index was last updated and return whether it's stale
+pub fn print_index_age(config: &Config) -> bool {
+    let last_update =
+        std::fs::metadata(&config.apt_update_mtime_path).and_then(|stat| stat.modified()).ok();
+    let age = last_update.map(|mtime| SystemTime::now().duration_since(mtime).unwrap_or_default());
+    // Match AptPackageManager::update_index, which assumes maximum staleness if the mtime can't
+    // be read
+    let stale = age.is_none_or(|age| age.as_secs() > config.apt_update_interval);
+    match (last_update, age) {
+        (Some(mtime), Some(age)) => println!(
+            "Package index last updated: {} ({} ago, {})",
+            format_rfc3339(mtime),
+            format_age(age),
+            if stale { "stale" } else { "fresh" }
+        ),
+        _ => println!(
+            "Package index last updated: unknown (could not stat {})",
+            config.apt_update_mtime_path.display()
+        ),
+    }
+    stale
+}
+
+/// Print what the state file says the last completed run did
+pub fn print_last_run(config: &Config) {
+    let path = &config.state_file_path;
+    let state = match State::read(path) {
+        Ok(state) => state,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("Last run: never (or not recorded in {})", path.display());
+            return;
+        },
+        Err(e) => {
+            println!("Last run: unknown (could not read {}: {})", path.display(), e);
+            return;
+        },
+    };
+    let changes: Vec<String> = state
+        .new_versions
+        .iter()
+        .filter(|(name, new)| state.old_versions.get(*name) != Some(*new))
+        .map(|(name, new)| match state.old_versions.get(name) {
+            Some(old) => format!("{} {} -> {}", name, old, new),
+            None => format!("{} {} (new)", name, new),
+        })
+        .collect();
+    println!("Last run: {} (action taken: {})", state.last_run_utc, state.action_taken);
+    if !state.upgraded {
+        println!("    - Nothing was upgraded");
+    }
+    for change in changes {
+        println!("    - {}", change);
+    }
+    if state.reboot_pending() {
+        println!("    - A reboot is required to load the upgraded kernel module");
+    }
+}
+
+/// Render a duration as a rough human-readable age (eg. `3h 25m` or `4d 2h`)
+fn format_age(age: Duration) -> String {
+    let (days, hours, minutes) =
+        (age.as_secs() / 86400, age.as_secs() % 86400 / 3600, age.as_secs() % 3600 / 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
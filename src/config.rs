@@ -0,0 +1,1011 @@
+//! Runtime configuration, loaded from a TOML file with a fallback to the compiled-in defaults
+//!
+//! To preserve the "no dependencies beyond the standard library" property, this uses a small
+//! hand-written parser for the subset of TOML a flat list of settings needs: top-level
+//! `key = value` pairs where values are strings, integers, booleans, or arrays of those.
+
+use std::error::Error;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+use std::time::Duration;
+
+use crate::cli::Args;
+use crate::email::is_email_address;
+use crate::error::UpdateNvidiaError;
+use crate::kmod::DEPENDENT_KMOD_NAMES;
+use crate::lock::LOCK_FILE_PATH;
+use crate::log::log;
+use crate::network::NETWORK_CHECK_HOSTS;
+use crate::packages::{
+    is_apt_option, parse_apt_option_args, AutoMarkPolicy, NvidiaVariant, PackageManagerKind,
+};
+use crate::ppa::parse_ppa;
+use crate::process::is_env_var_name;
+use crate::settings::BACKUP_SETTINGS_DIR;
+use crate::state::STATE_FILE_PATH;
+use crate::{
+    ADD_APT_REPOSITORY_PATH, APT_CACHE_PATH, APT_GET_PATH, APT_MARK_PATH, APT_UPDATE_INTERVAL,
+    APT_UPDATE_MTIME_PATH, DKMS_PATH, DNF_PATH, DPKG_QUERY_PATH, GPG_PATH, MODPROBE_PATH,
+    MOKUTIL_PATH, NOTIFY_SEND_PATH, NVIDIA_KMOD_NAME, NVIDIA_SMI_PATH, PACMAN_PATH, REBOOT_PATH,
+    RMMOD_PATH, RPM_PATH, SENDMAIL_PATH, SHUTDOWN_PATH, SYSTEMCTL_PATH, SYSTEMD_INHIBIT_PATH,
+    UBUNTU_DRIVERS_PATH, ZYPPER_PATH,
+};
+
+/// Path the config file is loaded from if `--config` isn't given
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/update_nvidia/config.toml";
+
+/// Every tuneable value, with the module-level constants as defaults
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Let the upgrade install NVIDIA packages which aren't installed yet
+    pub accept_new_packages: bool,
+    /// Path to use for invoking the `add-apt-repository` Command
+    pub add_apt_repository_path: PathBuf,
+    /// Reload the module even if an X server or Wayland compositor is running
+    pub allow_display_interrupt: bool,
+    /// Path to use for invoking the `apt-get` Command
+    pub apt_get_path: PathBuf,
+    /// A third-party repository's signing key to install into `/etc/apt/trusted.gpg.d` before
+    /// refreshing the package index
+    pub apt_key: Option<PathBuf>,
+    /// Path to use for invoking the `apt-cache` Command
+    pub apt_cache_path: PathBuf,
+    /// Path to use for invoking the `apt-mark` Command
+    pub apt_mark_path: PathBuf,
+    /// `Key::SubKey=Value` settings to pass to every `apt-get` command with `-o`
+    pub apt_extra_options: Vec<String>,
+    /// The most package names to pass to a single `apt-mark` command
+    pub apt_mark_chunk_size: usize,
+    /// Path to the file that should have its `mtime` used as a sign of when `apt-get update` ran
+    ///
+    /// (Also accepted as `cache_db_path`, for systems where `/var/cache/apt` is read-only)
+    pub apt_update_mtime_path: PathBuf,
+    /// Threshold (in seconds) beyond which the package cache is considered stale
+    pub apt_update_interval: u64,
+    /// Directory that GPU settings files are copied into before each upgrade
+    pub backup_settings_dir: PathBuf,
+    /// Upgrade without showing what will change and asking for confirmation first
+    pub assume_yes: bool,
+    /// Whether to mark the packages as manually or automatically installed after an upgrade
+    pub auto_mark_policy: AutoMarkPolicy,
+    /// Delete the downloaded packages (eg. with `apt-get clean`) after a successful upgrade
+    pub clean_apt_cache: bool,
+    /// Hold, unhold, and upgrade the `cuda-compat-*` packages along with the NVIDIA ones
+    pub cuda_compat: bool,
+    /// Path to use for invoking the `dkms` Command
+    pub dkms_path: PathBuf,
+    /// Path to use for invoking the `dnf` Command
+    pub dnf_path: PathBuf,
+    /// How long to wait for another package manager to release the dpkg lock before an
+    /// `apt-get` command which needs it
+    pub dpkg_lock_timeout: Duration,
+    /// Path to use for invoking the `dpkg-query` Command
+    pub dpkg_query_path: PathBuf,
+    /// Which kernel module's packages to manage, or `None` to detect it from what's installed
+    pub driver_variant: Option<NvidiaVariant>,
+    /// Address to email a report to whenever the driver is upgraded or an upgrade fails
+    pub email: Option<String>,
+    /// Glob patterns for installed packages which should never be held or upgraded
+    pub exclude_patterns: Vec<String>,
+    /// Refresh the package index even if `apt_update_mtime_path` says it's fresh enough
+    pub force_update: bool,
+    /// Let commands inherit this process's whole environment instead of a minimal one
+    pub full_env: bool,
+    /// Path to use for invoking the `gpg` Command
+    pub gpg_path: PathBuf,
+    /// Check `apt-mark showhold` after the upgrade and retry holding any package missing from it
+    pub hold_new_packages: bool,
+    /// Glob patterns for extra packages (eg. `libcudnn*`) to hold and unhold with the NVIDIA ones
+    pub hold_patterns: Vec<String>,
+    /// Exact names of installed packages to leave out of holding and upgrading, but still show
+    pub ignore_packages: Vec<String>,
+    /// Reload the module even in a container or in a VM without an NVIDIA GPU passed through
+    pub ignore_virtualization: bool,
+    /// Install the running kernel's headers if `kernel_check` finds them missing
+    pub install_kernel_headers: bool,
+    /// Check that the running kernel's headers are installed (for DKMS) before upgrading
+    pub kernel_check: bool,
+    /// Send `SIGTERM` to processes using the GPU if they prevent the module from unloading
+    pub kill_users: bool,
+    /// Path to the lock file used to prevent concurrent runs
+    pub lock_file_path: PathBuf,
+    /// Minimum free space (in bytes) required where packages are downloaded before upgrading
+    pub min_free_bytes: u64,
+    /// Path to use for invoking the `mokutil` Command
+    pub mokutil_path: PathBuf,
+    /// Hosts to test connectivity to (on port 443) before updating the package index
+    pub network_check_hosts: Vec<String>,
+    /// How long to wait for one of `network_check_hosts` to become reachable
+    pub network_timeout: Duration,
+    /// Upgrade with `apt-get upgrade`, which won't remove or newly install anything, rather than
+    /// `apt-get dist-upgrade`
+    pub no_dist_upgrade: bool,
+    /// Never reload the module or reboot, and record in the state file that a reboot is needed
+    pub no_module_reload: bool,
+    /// Fail instead of rebooting if the kernel module can't be reloaded
+    pub no_reboot: bool,
+    /// Send a desktop notification via `notify-send` when the driver is upgraded
+    pub notify_send: bool,
+    /// Path to use for invoking the `notify-send` Command
+    pub notify_send_path: PathBuf,
+    /// Path to use for invoking the `nvidia-smi` Command
+    pub nvidia_smi_path: PathBuf,
+    /// Refuse to upgrade a DKMS driver if Secure Boot is enabled and there's no key to sign the
+    /// rebuilt module with
+    pub secure_boot_check: bool,
+    /// Write a marker file instead of rebooting, to test the reboot path safely
+    ///
+    /// Only settable with `--simulate-reboot`, which is ignored outside of tests unless the
+    /// `simulation` feature is enabled.
+    pub simulate_reboot: bool,
+    /// Path to use for invoking the `sendmail` Command (for `email`)
+    pub sendmail_path: PathBuf,
+    /// Script to run after the upgrade and module reload (failure is only logged)
+    pub post_upgrade_hook: Option<PathBuf>,
+    /// Script to run before anything is unheld or upgraded (failure aborts the upgrade)
+    pub pre_upgrade_hook: Option<PathBuf>,
+    /// Never refresh the package index (eg. because the calling script just did)
+    pub skip_update: bool,
+    /// HTTP proxy URL for `apt-get` to use (falls back to `$HTTP_PROXY`)
+    pub proxy: Option<String>,
+    /// HTTPS proxy URL for `apt-get` to use (falls back to `$HTTPS_PROXY`)
+    pub https_proxy: Option<String>,
+    /// Launchpad PPA (eg. `ppa:graphics-drivers/ppa`) to add to the APT sources before upgrading
+    pub ppa: Option<String>,
+    /// Environment variables (eg. `GNUPGHOME`) to pass to commands besides `PATH`, `HOME`,
+    /// `LANG`, and the proxy settings
+    pub preserve_env: Vec<String>,
+    /// `sources.list` for `apt-get` to use instead of the system's configured APT sources
+    pub sources_list: Option<PathBuf>,
+    /// File to append each run's before and after package versions to, if any
+    pub package_versions_file: Option<PathBuf>,
+    /// Which package management backend to use, or `None` to auto-detect it
+    pub package_manager: Option<PackageManagerKind>,
+    /// Glob patterns selecting the packages to unhold, upgrade, and re-hold
+    pub package_patterns: Vec<String>,
+    /// Seconds to wait (rounded up to whole minutes) before rebooting. `0` reboots immediately.
+    pub reboot_delay: u64,
+    /// Reboot if `verify_after_reload` finds the reloaded driver isn't working
+    pub reboot_on_verify_failure: bool,
+    /// Path to use for invoking the `reboot` Command
+    pub reboot_path: PathBuf,
+    /// How many times to try refreshing the index or upgrading before giving up
+    pub retry_attempts: u32,
+    /// How long to wait after the first failed attempt (doubled after each further one)
+    pub retry_delay: Duration,
+    /// Skip upgrading (but still succeed) when running on battery power
+    pub require_ac_power: bool,
+    /// Copy the backed-up GPU settings files back if an upgrade changed them
+    pub restore_settings: bool,
+    /// Path to use for invoking the `rmmod` Command
+    pub rmmod_path: PathBuf,
+    /// Path to use for invoking the `rpm` Command
+    pub rpm_path: PathBuf,
+    /// Path to use for invoking the `zypper` Command
+    pub zypper_path: PathBuf,
+    /// Path to use for invoking the `pacman` Command
+    pub pacman_path: PathBuf,
+    /// Path to use for invoking the `modprobe` Command
+    pub modprobe_path: PathBuf,
+    /// Module parameters (eg. `NVreg_UsePageAttributeTable=1`) to load `nvidia_kmod_name` with
+    pub modprobe_options: Vec<String>,
+    /// Where to record the results of the last completed run
+    pub state_file_path: PathBuf,
+    /// Path to use for invoking the `shutdown` Command (for delayed reboots)
+    pub shutdown_path: PathBuf,
+    /// Path to use for invoking the `systemctl` Command
+    pub systemctl_path: PathBuf,
+    /// Path to use for invoking the `systemd-inhibit` Command
+    pub systemd_inhibit_path: PathBuf,
+    /// Path to use for invoking the `ubuntu-drivers` Command
+    pub ubuntu_drivers_path: PathBuf,
+    /// Run `ubuntu-drivers autoinstall` before each upgrade to install the recommended driver
+    pub use_ubuntu_drivers: bool,
+    /// Kill any command which alters the system if it's still running after this long
+    pub timeout: Option<Duration>,
+    /// Check with `nvidia-smi -L` that the driver can see a GPU after reloading the module
+    pub verify_after_reload: bool,
+    /// Name of the kernel module to unload and reload
+    pub nvidia_kmod_name: String,
+    /// Modules which depend on `nvidia_kmod_name` and must be unloaded before it (if loaded)
+    pub dependent_kmod_names: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            accept_new_packages: true,
+            add_apt_repository_path: ADD_APT_REPOSITORY_PATH.into(),
+            allow_display_interrupt: false,
+            apt_get_path: APT_GET_PATH.into(),
+            apt_key: None,
+            apt_cache_path: APT_CACHE_PATH.into(),
+            apt_mark_path: APT_MARK_PATH.into(),
+            apt_extra_options: Vec::new(),
+            apt_mark_chunk_size: 1000,
+            apt_update_mtime_path: APT_UPDATE_MTIME_PATH.into(),
+            apt_update_interval: APT_UPDATE_INTERVAL,
+            backup_settings_dir: BACKUP_SETTINGS_DIR.into(),
+            assume_yes: false,
+            auto_mark_policy: AutoMarkPolicy::DoNothing,
+            clean_apt_cache: false,
+            cuda_compat: false,
+            dkms_path: DKMS_PATH.into(),
+            dnf_path: DNF_PATH.into(),
+            dpkg_lock_timeout: Duration::from_secs(300),
+            dpkg_query_path: DPKG_QUERY_PATH.into(),
+            driver_variant: None,
+            email: None,
+            exclude_patterns: Vec::new(),
+            force_update: false,
+            full_env: false,
+            gpg_path: GPG_PATH.into(),
+            hold_new_packages: false,
+            hold_patterns: Vec::new(),
+            ignore_packages: Vec::new(),
+            ignore_virtualization: false,
+            install_kernel_headers: false,
+            kernel_check: false,
+            kill_users: false,
+            lock_file_path: LOCK_FILE_PATH.into(),
+            min_free_bytes: 512 * 1024 * 1024,
+            mokutil_path: MOKUTIL_PATH.into(),
+            network_check_hosts: NETWORK_CHECK_HOSTS.iter().map(|&host| host.into()).collect(),
+            network_timeout: Duration::from_secs(60),
+            no_dist_upgrade: false,
+            no_module_reload: false,
+            no_reboot: false,
+            notify_send: false,
+            notify_send_path: NOTIFY_SEND_PATH.into(),
+            nvidia_smi_path: NVIDIA_SMI_PATH.into(),
+            secure_boot_check: false,
+            simulate_reboot: false,
+            sendmail_path: SENDMAIL_PATH.into(),
+            post_upgrade_hook: None,
+            pre_upgrade_hook: None,
+            skip_update: false,
+            proxy: None,
+            https_proxy: None,
+            ppa: None,
+            preserve_env: Vec::new(),
+            sources_list: None,
+            package_versions_file: None,
+            package_manager: None,
+            package_patterns: vec!["*nvidia*".to_owned()],
+            reboot_delay: 0,
+            reboot_on_verify_failure: false,
+            reboot_path: REBOOT_PATH.into(),
+            require_ac_power: false,
+            restore_settings: false,
+            retry_attempts: 3,
+            retry_delay: Duration::from_secs(10),
+            rmmod_path: RMMOD_PATH.into(),
+            rpm_path: RPM_PATH.into(),
+            zypper_path: ZYPPER_PATH.into(),
+            pacman_path: PACMAN_PATH.into(),
+            modprobe_path: MODPROBE_PATH.into(),
+            modprobe_options: Vec::new(),
+            state_file_path: STATE_FILE_PATH.into(),
+            shutdown_path: SHUTDOWN_PATH.into(),
+            systemctl_path: SYSTEMCTL_PATH.into(),
+            systemd_inhibit_path: SYSTEMD_INHIBIT_PATH.into(),
+            ubuntu_drivers_path: UBUNTU_DRIVERS_PATH.into(),
+            use_ubuntu_drivers: false,
+            timeout: None,
+            verify_after_reload: false,
+            nvidia_kmod_name: NVIDIA_KMOD_NAME.into(),
+            dependent_kmod_names: DEPENDENT_KMOD_NAMES.iter().map(|&name| name.into()).collect(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the config file at `path`, falling back to the defaults if it doesn't exist
+    ///
+    /// If `required` is `true` (ie. the path was explicitly requested), a missing file is an
+    /// error rather than a reason to fall back to the defaults.
+    pub fn load(path: &Path, required: bool) -> Result<Self, UpdateNvidiaError> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => {
+                return Ok(Self::default());
+            },
+            Err(e) => return Err(UpdateNvidiaError::ConfigUnreadable(path.to_owned(), e)),
+        };
+        Self::from_toml_str(&text).map_err(|e| UpdateNvidiaError::ConfigInvalid(path.to_owned(), e))
+    }
+
+    /// Parse the contents of a config file, layering them on top of the defaults
+    pub fn from_toml_str(text: &str) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        let mut seen = Vec::new();
+        for (line, key, value) in parse_toml(text)? {
+            if seen.contains(&key) {
+                return Err(ConfigError { line, message: format!("duplicate key {:?}", key) });
+            }
+            config.set(&key, value).map_err(|message| ConfigError { line, message })?;
+            seen.push(key);
+        }
+        Ok(config)
+    }
+
+    /// Let `UPDATE_NVIDIA_<COMMAND>` environment variables (eg. `UPDATE_NVIDIA_APT_GET`) override
+    /// the path each command is run from, whether it's the default or from the config file
+    ///
+    /// This is for test environments and containers where the commands are elsewhere or mocked.
+    /// It's insecure in production, since anything which can set the environment can then choose
+    /// what runs as root, so each override is logged as a warning.
+    pub fn apply_env_overrides(&mut self) {
+        let paths = [
+            ("UPDATE_NVIDIA_ADD_APT_REPOSITORY", &mut self.add_apt_repository_path),
+            ("UPDATE_NVIDIA_APT_CACHE", &mut self.apt_cache_path),
+            ("UPDATE_NVIDIA_APT_GET", &mut self.apt_get_path),
+            ("UPDATE_NVIDIA_APT_MARK", &mut self.apt_mark_path),
+            ("UPDATE_NVIDIA_DKMS", &mut self.dkms_path),
+            ("UPDATE_NVIDIA_DNF", &mut self.dnf_path),
+            ("UPDATE_NVIDIA_DPKG_QUERY", &mut self.dpkg_query_path),
+            ("UPDATE_NVIDIA_GPG", &mut self.gpg_path),
+            ("UPDATE_NVIDIA_MODPROBE", &mut self.modprobe_path),
+            ("UPDATE_NVIDIA_MOKUTIL", &mut self.mokutil_path),
+            ("UPDATE_NVIDIA_NOTIFY_SEND", &mut self.notify_send_path),
+            ("UPDATE_NVIDIA_NVIDIA_SMI", &mut self.nvidia_smi_path),
+            ("UPDATE_NVIDIA_PACMAN", &mut self.pacman_path),
+            ("UPDATE_NVIDIA_REBOOT", &mut self.reboot_path),
+            ("UPDATE_NVIDIA_RMMOD", &mut self.rmmod_path),
+            ("UPDATE_NVIDIA_RPM", &mut self.rpm_path),
+            ("UPDATE_NVIDIA_SENDMAIL", &mut self.sendmail_path),
+            ("UPDATE_NVIDIA_SHUTDOWN", &mut self.shutdown_path),
+            ("UPDATE_NVIDIA_SYSTEMCTL", &mut self.systemctl_path),
+            ("UPDATE_NVIDIA_SYSTEMD_INHIBIT", &mut self.systemd_inhibit_path),
+            ("UPDATE_NVIDIA_UBUNTU_DRIVERS", &mut self.ubuntu_drivers_path),
+            ("UPDATE_NVIDIA_ZYPPER", &mut self.zypper_path),
+        ];
+        for (var, path) in paths {
+            let Some(value) = std::env::var_os(var).filter(|value| !value.is_empty()) else {
+                continue;
+            };
+            *path = value.into();
+            log!(
+                "WARNING: Using {} from ${}. Overriding the paths of commands run as root is \
+                 insecure outside of testing.",
+                path.display(),
+                var
+            );
+        }
+    }
+
+    /// Let command-line flags override whatever the config file specified
+    pub fn apply_args(&mut self, args: &Args) {
+        self.accept_new_packages |= args.accept_new_packages;
+        self.allow_display_interrupt |= args.allow_display_interrupt;
+        self.assume_yes |= args.assume_yes;
+        // --keep-apt-cache wins, so it can override the config file for a single run
+        self.clean_apt_cache = (self.clean_apt_cache || args.clean_cache) && !args.keep_apt_cache;
+        self.cuda_compat |= args.cuda_compat;
+        self.force_update |= args.force_update;
+        self.full_env |= args.full_env;
+        self.hold_new_packages |= args.hold_new_packages;
+        self.hold_patterns.extend(args.hold_patterns.iter().cloned());
+        self.ignore_packages.extend(args.ignore_packages.iter().cloned());
+        self.ignore_virtualization |= args.ignore_virtualization;
+        self.preserve_env.extend(args.preserve_env.iter().cloned());
+        self.kernel_check |= args.kernel_check;
+        self.kill_users |= args.kill_users;
+        self.restore_settings |= args.restore_settings;
+        self.secure_boot_check |= args.secure_boot_check;
+        self.simulate_reboot |= args.simulate_reboot && cfg!(any(test, feature = "simulation"));
+        self.skip_update |= args.skip_update;
+        self.use_ubuntu_drivers |= args.ubuntu_drivers;
+        // Rebooting over a failed verification implies there's a verification to fail
+        self.reboot_on_verify_failure |= args.reboot_on_verify_failure;
+        self.verify_after_reload |= self.reboot_on_verify_failure;
+        if let Some(path) = &args.backup_settings_dir {
+            self.backup_settings_dir = path.clone();
+        }
+        self.apt_extra_options.extend(args.apt_extra_options.iter().cloned());
+        self.no_dist_upgrade |= args.no_dist_upgrade;
+        self.no_module_reload |= args.no_module_reload;
+        self.no_reboot |= args.no_reboot;
+        if let Some(variant) = args.driver_variant {
+            self.driver_variant = Some(variant);
+        }
+        if let Some(kind) = args.package_manager {
+            self.package_manager = Some(kind);
+        }
+        if let Some(delay) = args.reboot_delay {
+            self.reboot_delay = delay;
+        }
+        if let Some(address) = &args.email {
+            self.email = Some(address.clone());
+        }
+        if let Some(path) = &args.package_versions_file {
+            self.package_versions_file = Some(path.clone());
+        }
+        if let Some(path) = &args.apt_key {
+            self.apt_key = Some(path.clone());
+        }
+        if let Some(ppa) = &args.ppa {
+            self.ppa = Some(ppa.clone());
+        }
+        if let Some(path) = &args.sources_list {
+            self.sources_list = Some(path.clone());
+        }
+        if let Some(path) = &args.state_file {
+            self.state_file_path = path.clone();
+        }
+        if let Some(name) = &args.nvidia_module {
+            self.nvidia_kmod_name = name.clone();
+        }
+        if let Some(secs) = args.timeout {
+            self.timeout = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+    }
+
+    /// Assign a single parsed value, rejecting unknown keys so typos don't go unnoticed
+    fn set(&mut self, key: &str, value: Value) -> Result<(), String> {
+        match key {
+            "accept_new_packages" => self.accept_new_packages = value.into_bool(key)?,
+            "add_apt_repository_path" => self.add_apt_repository_path = value.into_path(key)?,
+            "allow_display_interrupt" => self.allow_display_interrupt = value.into_bool(key)?,
+            "apt_get_path" => self.apt_get_path = value.into_path(key)?,
+            "apt_key" => self.apt_key = Some(value.into_path(key)?),
+            "apt_cache_path" => self.apt_cache_path = value.into_path(key)?,
+            "apt_mark_path" => self.apt_mark_path = value.into_path(key)?,
+            "apt_extra_options" => {
+                let options = value.into_string_vec(key)?;
+                if let Some(option) = options.iter().find(|option| !is_apt_option(option)) {
+                    return Err(format!(
+                        "{} entries must be of the form Key::SubKey=Value, not {:?}",
+                        key, option
+                    ));
+                }
+                self.apt_extra_options.extend(options);
+            },
+            "apt_mark_chunk_size" => {
+                self.apt_mark_chunk_size = usize::try_from(value.into_u64(key)?)
+                    .ok()
+                    .filter(|&size| size > 0)
+                    .ok_or_else(|| format!("{} must be between 1 and {}", key, usize::MAX))?;
+            },
+            // The `-o` pairs form, which ends up in the same place
+            "apt_options" => {
+                let options = parse_apt_option_args(&value.into_string_vec(key)?)
+                    .map_err(|e| format!("{}: {}", key, e))?;
+                self.apt_extra_options.extend(options);
+            },
+            "apt_update_mtime_path" | "cache_db_path" => {
+                self.apt_update_mtime_path = value.into_path(key)?
+            },
+            "apt_update_interval" => self.apt_update_interval = value.into_u64(key)?,
+            "backup_settings_dir" => self.backup_settings_dir = value.into_path(key)?,
+            "assume_yes" => self.assume_yes = value.into_bool(key)?,
+            "clean_apt_cache" => self.clean_apt_cache = value.into_bool(key)?,
+            "cuda_compat" => self.cuda_compat = value.into_bool(key)?,
+            "dkms_path" => self.dkms_path = value.into_path(key)?,
+            "dnf_path" => self.dnf_path = value.into_path(key)?,
+            "dpkg_query_path" => self.dpkg_query_path = value.into_path(key)?,
+            "email" => {
+                let address = value.into_string(key)?;
+                if !is_email_address(&address) {
+                    return Err(format!(
+                        "{} must be an address like admin@example.com, not {:?}",
+                        key, address
+                    ));
+                }
+                self.email = Some(address);
+            },
+            "exclude_patterns" => self.exclude_patterns = value.into_string_vec(key)?,
+            "force_update" => self.force_update = value.into_bool(key)?,
+            "full_env" => self.full_env = value.into_bool(key)?,
+            "gpg_path" => self.gpg_path = value.into_path(key)?,
+            "hold_new_packages" => self.hold_new_packages = value.into_bool(key)?,
+            "hold_patterns" => self.hold_patterns = value.into_string_vec(key)?,
+            "ignore_packages" => self.ignore_packages = value.into_string_vec(key)?,
+            "ignore_virtualization" => self.ignore_virtualization = value.into_bool(key)?,
+            "install_kernel_headers" => self.install_kernel_headers = value.into_bool(key)?,
+            "kernel_check" => self.kernel_check = value.into_bool(key)?,
+            "kill_users" => self.kill_users = value.into_bool(key)?,
+            "lock_file_path" => self.lock_file_path = value.into_path(key)?,
+            "min_free_bytes" => self.min_free_bytes = value.into_u64(key)?,
+            "mokutil_path" => self.mokutil_path = value.into_path(key)?,
+            "network_check_hosts" => self.network_check_hosts = value.into_string_vec(key)?,
+            "dpkg_lock_timeout" => {
+                self.dpkg_lock_timeout = Duration::from_secs(value.into_u64(key)?)
+            },
+            "network_timeout" => self.network_timeout = Duration::from_secs(value.into_u64(key)?),
+            "no_dist_upgrade" => self.no_dist_upgrade = value.into_bool(key)?,
+            "no_module_reload" => self.no_module_reload = value.into_bool(key)?,
+            "no_reboot" => self.no_reboot = value.into_bool(key)?,
+            "notify_send" => self.notify_send = value.into_bool(key)?,
+            "notify_send_path" => self.notify_send_path = value.into_path(key)?,
+            "nvidia_smi_path" => self.nvidia_smi_path = value.into_path(key)?,
+            "secure_boot_check" => self.secure_boot_check = value.into_bool(key)?,
+            "sendmail_path" => self.sendmail_path = value.into_path(key)?,
+            "post_upgrade_hook" => self.post_upgrade_hook = Some(value.into_path(key)?),
+            "pre_upgrade_hook" => self.pre_upgrade_hook = Some(value.into_path(key)?),
+            "skip_update" => self.skip_update = value.into_bool(key)?,
+            "proxy" => self.proxy = Some(value.into_string(key)?),
+            "https_proxy" => self.https_proxy = Some(value.into_string(key)?),
+            "ppa" => {
+                let ppa = value.into_string(key)?;
+                if parse_ppa(&ppa).is_none() {
+                    return Err(format!("{} must be of the form ppa:<user>/<name>", key));
+                }
+                self.ppa = Some(ppa);
+            },
+            "preserve_env" => {
+                let names = value.into_string_vec(key)?;
+                if let Some(name) = names.iter().find(|name| !is_env_var_name(name)) {
+                    return Err(format!("{} entries must be variable names, not {:?}", key, name));
+                }
+                self.preserve_env = names;
+            },
+            "sources_list" => self.sources_list = Some(value.into_path(key)?),
+            "package_versions_file" => self.package_versions_file = Some(value.into_path(key)?),
+            "auto_mark_policy" => {
+                let name = value.into_string(key)?;
+                self.auto_mark_policy = name.parse().map_err(|_| {
+                    format!("{} must be one of: {}", key, AutoMarkPolicy::NAMES.join(", "))
+                })?;
+            },
+            "driver_variant" => {
+                let name = value.into_string(key)?;
+                self.driver_variant = Some(name.parse().map_err(|_| {
+                    format!("{} must be one of: {}", key, NvidiaVariant::NAMES.join(", "))
+                })?);
+            },
+            "package_manager" => {
+                let name = value.into_string(key)?;
+                self.package_manager = Some(name.parse().map_err(|_| {
+                    format!("{} must be one of: {}", key, PackageManagerKind::NAMES.join(", "))
+                })?);
+            },
+            "package_patterns" => self.package_patterns = value.into_string_vec(key)?,
+            "reboot_delay" => self.reboot_delay = value.into_u64(key)?,
+            "reboot_on_verify_failure" => self.reboot_on_verify_failure = value.into_bool(key)?,
+            "reboot_path" => self.reboot_path = value.into_path(key)?,
+            "require_ac_power" => self.require_ac_power = value.into_bool(key)?,
+            "restore_settings" => self.restore_settings = value.into_bool(key)?,
+            "retry_attempts" => {
+                self.retry_attempts = u32::try_from(value.into_u64(key)?)
+                    .ok()
+                    .filter(|&attempts| attempts > 0)
+                    .ok_or_else(|| format!("{} must be between 1 and {}", key, u32::MAX))?;
+            },
+            "retry_delay" => self.retry_delay = Duration::from_secs(value.into_u64(key)?),
+            "rmmod_path" => self.rmmod_path = value.into_path(key)?,
+            "rpm_path" => self.rpm_path = value.into_path(key)?,
+            "zypper_path" => self.zypper_path = value.into_path(key)?,
+            "pacman_path" => self.pacman_path = value.into_path(key)?,
+            "modprobe_path" => self.modprobe_path = value.into_path(key)?,
+            "modprobe_options" => self.modprobe_options = value.into_string_vec(key)?,
+            "state_file_path" => self.state_file_path = value.into_path(key)?,
+            "shutdown_path" => self.shutdown_path = value.into_path(key)?,
+            "systemctl_path" => self.systemctl_path = value.into_path(key)?,
+            "systemd_inhibit_path" => self.systemd_inhibit_path = value.into_path(key)?,
+            "ubuntu_drivers_path" => self.ubuntu_drivers_path = value.into_path(key)?,
+            "use_ubuntu_drivers" => self.use_ubuntu_drivers = value.into_bool(key)?,
+            "timeout" => {
+                let secs = value.into_u64(key)?;
+                self.timeout = (secs > 0).then(|| Duration::from_secs(secs));
+            },
+            "verify_after_reload" => self.verify_after_reload = value.into_bool(key)?,
+            "nvidia_kmod_name" => self.nvidia_kmod_name = value.into_string(key)?,
+            "dependent_kmod_names" => self.dependent_kmod_names = value.into_string_vec(key)?,
+            _ => return Err(format!("unknown key {:?}", key)),
+        }
+        Ok(())
+    }
+}
+
+/// A syntax or validation error in a config file
+#[derive(Debug)]
+pub struct ConfigError {
+    /// The 1-based line number the error was encountered on
+    pub line: usize,
+    /// A human-readable description of the problem
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+impl Error for ConfigError {}
+
+/// A parsed TOML value
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// Human-readable name of the value's type for use in error messages
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+        }
+    }
+
+    fn into_string(self, key: &str) -> Result<String, String> {
+        match self {
+            Value::String(s) => Ok(s),
+            other => Err(format!("{} must be a string, not {}", key, other.type_name())),
+        }
+    }
+
+    fn into_bool(self, key: &str) -> Result<bool, String> {
+        match self {
+            Value::Boolean(b) => Ok(b),
+            other => Err(format!("{} must be a boolean, not {}", key, other.type_name())),
+        }
+    }
+
+    fn into_path(self, key: &str) -> Result<PathBuf, String> {
+        self.into_string(key).map(PathBuf::from)
+    }
+
+    fn into_string_vec(self, key: &str) -> Result<Vec<String>, String> {
+        match self {
+            Value::Array(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    Value::String(s) => Ok(s),
+                    other => {
+                        Err(format!("{} must only contain strings, not {}", key, other.type_name()))
+                    },
+                })
+                .collect(),
+            other => Err(format!("{} must be an array of strings, not {}", key, other.type_name())),
+        }
+    }
+
+    fn into_u64(self, key: &str) -> Result<u64, String> {
+        match self {
+            Value::Integer(i) => {
+                u64::try_from(i).map_err(|_| format!("{} must not be negative", key))
+            },
+            other => Err(format!("{} must be an integer, not {}", key, other.type_name())),
+        }
+    }
+}
+
+/// Parse a TOML document into `(line, key, value)` triples
+///
+/// Tables, dotted keys, floats, dates, and multi-line strings are rejected as unsupported.
+fn parse_toml(text: &str) -> Result<Vec<(usize, String, Value)>, ConfigError> {
+    let mut parser = Parser { chars: text.chars().peekable(), line: 1 };
+    let mut results = Vec::new();
+    loop {
+        parser.skip_blank_lines();
+        let line = parser.line;
+        match parser.peek() {
+            None => break,
+            Some('[') => return Err(parser.error("tables are not supported")),
+            Some(_) => {},
+        }
+        let key = parser.parse_key()?;
+        parser.skip_spaces();
+        if parser.peek() != Some('=') {
+            return Err(parser.error(format!("expected '=' after key {:?}", key)));
+        }
+        parser.bump();
+        parser.skip_spaces();
+        let value = parser.parse_value()?;
+        parser.expect_line_end()?;
+        results.push((line, key, value));
+    }
+    Ok(results)
+}
+
+/// Cursor over the characters of a TOML document which tracks the current line number
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let next = self.chars.next();
+        if next == Some('\n') {
+            self.line += 1;
+        }
+        next
+    }
+
+    fn error(&self, message: impl Into<String>) -> ConfigError {
+        ConfigError { line: self.line, message: message.into() }
+    }
+
+    /// Skip spaces and tabs, but not newlines
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+    }
+
+    /// Skip a `#` comment up to (but not including) the newline that ends it
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.bump();
+            }
+        }
+    }
+
+    /// Skip any combination of whitespace, comments, and newlines
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n' | '\r') => {
+                    self.bump();
+                },
+                _ => break,
+            }
+        }
+    }
+
+    /// Require that nothing but whitespace and an optional comment remains on the current line
+    fn expect_line_end(&mut self) -> Result<(), ConfigError> {
+        self.skip_spaces();
+        self.skip_comment();
+        if self.peek() == Some('\r') {
+            self.bump();
+        }
+        match self.bump() {
+            None | Some('\n') => Ok(()),
+            Some(c) => Err(self.error(format!("unexpected {:?} after value", c))),
+        }
+    }
+
+    fn parse_key(&mut self) -> Result<String, ConfigError> {
+        let mut key = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                key.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        match self.peek() {
+            _ if key.is_empty() => Err(self.error("expected a key")),
+            Some('.') => Err(self.error("dotted keys are not supported")),
+            _ => Ok(key),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, ConfigError> {
+        match self.peek() {
+            Some('"') => self.parse_basic_string().map(Value::String),
+            Some('\'') => self.parse_literal_string().map(Value::String),
+            Some('[') => self.parse_array(),
+            _ => self.parse_bare_value(),
+        }
+    }
+
+    fn parse_basic_string(&mut self) -> Result<String, ConfigError> {
+        self.bump(); // Opening quote
+        let mut result = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some('"') => return Ok(result),
+                Some('\\') => result.push(match self.bump() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some(c) => return Err(self.error(format!("unsupported escape \\{}", c))),
+                    None => return Err(self.error("unterminated string")),
+                }),
+                Some(c) => result.push(c),
+            }
+        }
+    }
+
+    fn parse_literal_string(&mut self) -> Result<String, ConfigError> {
+        self.bump(); // Opening quote
+        let mut result = String::new();
+        loop {
+            match self.bump() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some('\'') => return Ok(result),
+                Some(c) => result.push(c),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value, ConfigError> {
+        self.bump(); // Opening bracket
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank_lines();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.parse_value()?);
+            self.skip_blank_lines();
+            match self.bump() {
+                Some(',') => {},
+                Some(']') => return Ok(Value::Array(items)),
+                _ => return Err(self.error("expected ',' or ']' in array")),
+            }
+        }
+    }
+
+    /// Parse a boolean or integer
+    fn parse_bare_value(&mut self) -> Result<Value, ConfigError> {
+        let mut token = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || matches!(c, ',' | ']' | '#') {
+                break;
+            }
+            token.push(c);
+            self.bump();
+        }
+        match token.as_str() {
+            "" => Err(self.error("expected a value")),
+            "true" => Ok(Value::Boolean(true)),
+            "false" => Ok(Value::Boolean(false)),
+            _ => token
+                .replace('_', "")
+                .parse()
+                .map(Value::Integer)
+                .map_err(|_| self.error(format!("unsupported value {:?}", token))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apt_options_must_be_dash_o_assignments() {
+        let config =
+            Config::from_toml_str("apt_options = [\"-o\", \"Dpkg::Options::=--force-confold\"]")
+                .unwrap();
+        assert_eq!(config.apt_extra_options, ["Dpkg::Options::=--force-confold"]);
+        for bad in [r#"["-y", "--allow-unauthenticated"]"#, r#"["-o", "--force-yes"]"#] {
+            assert!(Config::from_toml_str(&format!("apt_options = {}", bad)).is_err());
+        }
+
+        let parse = |value: &str| Args::parse(["--apt-options".to_owned(), value.to_owned()]);
+        let args = parse("-o Dpkg::Options::=--force-confold -oAcquire::Retries=3").unwrap();
+        assert_eq!(
+            args.apt_extra_options,
+            ["Dpkg::Options::=--force-confold", "Acquire::Retries=3"]
+        );
+        assert!(parse("-y --allow-unauthenticated").is_err());
+    }
+
+    /// The value of the only key in `text`
+    fn parse_value(text: &str) -> Value {
+        let mut entries = parse_toml(text).unwrap();
+        assert_eq!(entries.len(), 1, "{:?}", entries);
+        entries.remove(0).2
+    }
+
+    #[test]
+    fn toml_strings_handle_escapes_and_literals() {
+        assert_eq!(
+            parse_value(r#"key = "tab\there \"quoted\" back\\slash\n""#),
+            Value::String("tab\there \"quoted\" back\\slash\n".to_owned())
+        );
+        assert_eq!(parse_value(r"key = 'C:\no\escapes'"), Value::String(r"C:\no\escapes".into()));
+        assert!(parse_toml(r#"key = "bad \x escape""#).is_err());
+        assert!(parse_toml("key = \"unterminated\nother = 1").is_err());
+    }
+
+    #[test]
+    fn toml_arrays_span_lines_and_allow_trailing_commas() {
+        let strings = |items: &[&str]| {
+            Value::Array(items.iter().map(|item| Value::String((*item).to_owned())).collect())
+        };
+        assert_eq!(parse_value("key = []"), Value::Array(Vec::new()));
+        assert_eq!(parse_value(r#"key = ["a", 'b']"#), strings(&["a", "b"]));
+        assert_eq!(
+            parse_value("key = [\n    \"a\",  # first\n    \"b\",\n]"),
+            strings(&["a", "b"])
+        );
+        assert!(parse_toml(r#"key = ["a" "b"]"#).is_err());
+    }
+
+    #[test]
+    fn toml_bare_values_are_booleans_or_integers() {
+        assert_eq!(parse_value("key = true"), Value::Boolean(true));
+        assert_eq!(parse_value("key = false"), Value::Boolean(false));
+        assert_eq!(parse_value("key = 172_800"), Value::Integer(172_800));
+        assert_eq!(parse_value("key = -1"), Value::Integer(-1));
+        assert!(parse_toml("key = yes").is_err());
+        assert!(parse_toml("key = 1.5").is_err());
+    }
+
+    #[test]
+    fn toml_comments_and_blank_lines_are_skipped() {
+        let entries =
+            parse_toml("# heading\n\n  a = 1  # trailing\r\n\t# indented\nb = \"#\"\n").unwrap();
+        assert_eq!(
+            entries,
+            [
+                (3, "a".to_owned(), Value::Integer(1)),
+                (5, "b".to_owned(), Value::String("#".to_owned())),
+            ]
+        );
+    }
+
+    #[test]
+    fn toml_malformed_lines_report_their_line_number() {
+        for (text, line) in [
+            ("assume_yes = true\nno_equals_sign\n", 2),
+            ("assume_yes = true extra\n", 1),
+            ("\n[table]\n", 2),
+            ("a.b = 1\n", 1),
+            ("key =\n", 1),
+        ] {
+            let error = Config::from_toml_str(text).unwrap_err();
+            assert_eq!(error.line, line, "{:?}: {}", text, error);
+        }
+    }
+
+    #[test]
+    fn config_rejects_unknown_and_duplicate_keys() {
+        let error = Config::from_toml_str("assume_yes = true\nasume_yes = true\n").unwrap_err();
+        assert_eq!((error.line, error.message.as_str()), (2, "unknown key \"asume_yes\""));
+
+        let error = Config::from_toml_str("assume_yes = true\nassume_yes = false\n").unwrap_err();
+        assert_eq!((error.line, error.message.as_str()), (2, "duplicate key \"assume_yes\""));
+    }
+
+    #[test]
+    fn config_values_must_have_the_right_type() {
+        let config =
+            Config::from_toml_str("assume_yes = true\napt_update_interval = 60\n").unwrap();
+        assert!(config.assume_yes);
+        assert_eq!(config.apt_update_interval, 60);
+        assert!(Config::from_toml_str("assume_yes = \"true\"").is_err());
+        assert!(Config::from_toml_str("apt_update_interval = -1").is_err());
+    }
+
+    #[test]
+    fn email_must_be_a_single_plain_address() {
+        let config = Config::from_toml_str("email = \"admin+gpu@mail.example.com\"").unwrap();
+        assert_eq!(config.email.as_deref(), Some("admin+gpu@mail.example.com"));
+
+        for address in [
+            "admin@example.com\nBcc: attacker@example.net",
+            "admin@example.com\r\nBcc: attacker@example.net",
+            "admin@example.com, attacker@example.net",
+            "Admin <admin@example.com>",
+            "admin",
+            "admin@",
+            "@example.com",
+            "admin@@example.com",
+            "admin..x@example.com",
+            "admin@example..com",
+            "admin@-example.com",
+        ] {
+            let toml = format!("email = {:?}", address);
+            assert!(Config::from_toml_str(&toml).is_err(), "{:?}", address);
+            let argv = ["--email".to_owned(), address.to_owned()];
+            assert!(Args::parse(argv.into_iter()).is_err(), "{:?}", address);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Free space checks, so a large upgrade doesn't run out of room halfway through unpacking
+
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_ulong};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::error::UpdateNvidiaError;
+
+/// Leading fields of `struct statvfs`
+///
+/// `c_ulong` matches glibc's `fsblkcnt_t` on 64-bit targets and on 32-bit ones without
+/// `_FILE_OFFSET_BITS=64`. Only the fields up to `f_bavail` are read and the padding leaves room
+/// for everything after them.
+#[repr(C)]
+struct StatVfs {
+    _f_bsize: c_ulong,
+    f_frsize: c_ulong,
+    _f_blocks: c_ulong,
+    _f_bfree: c_ulong,
+    f_bavail: c_ulong,
+    _rest: [c_ulong; 16],
+}
+
+/// Return the number of bytes available to unprivileged users on the filesystem containing `path`
+pub fn available_bytes(path: &Path) -> std::io::Result<u64> {
+    extern "C" {
+        fn statvfs(path: *const c_char, buf: *mut StatVfs) -> c_int;
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut buf = StatVfs {
+        _f_bsize: 0,
+        f_frsize: 0,
+        _f_blocks: 0,
+        _f_bfree: 0,
+        f_bavail: 0,
+        _rest: [0; 16],
+    };
+    // SAFETY: c_path is a valid NUL-terminated string and buf is a writable buffer at least as
+    // large as `struct statvfs`, both of which outlive the call.
+    if unsafe { statvfs(c_path.as_ptr(), &mut buf) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // Only a no-op on 64-bit targets, where c_ulong is u64
+    #[allow(clippy::useless_conversion)]
+    Ok(u64::from(buf.f_bavail).saturating_mul(u64::from(buf.f_frsize)))
+}
+
+/// Fail with [`UpdateNvidiaError::InsufficientDiskSpace`] if the filesystem containing `path` has
+/// less than `min_bytes` available
+///
+/// If `path` doesn't exist yet, its nearest existing ancestor is checked instead, since that's
+/// the filesystem it will be created on.
+pub fn check_disk_space(path: &Path, min_bytes: u64) -> Result<(), UpdateNvidiaError> {
+    let existing = path.ancestors().find(|dir| dir.exists()).unwrap_or(Path::new("/"));
+    let available = available_bytes(existing)?;
+    if available < min_bytes {
+        return Err(UpdateNvidiaError::InsufficientDiskSpace {
+            path: path.to_owned(),
+            available,
+            required: min_bytes,
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,180 @@
+//! Support for `nvidia-dkms`-style installs, where the kernel module is built locally and may not
+//! exist yet for the running kernel right after a driver upgrade
+
+use std::collections::BTreeMap;
+use std::process::Command;
+
+use crate::config::Config;
+use crate::error::UpdateNvidiaError;
+use crate::log::log;
+use crate::packages::{driver_variant, upstream_version, PackageManager, PackageManagerKind};
+use crate::process::{check_call, check_output};
+
+/// Where to read the running kernel's release (as printed by `uname -r`) from
+const OSRELEASE_PATH: &str = "/proc/sys/kernel/osrelease";
+
+/// How far along DKMS is with a particular module version for the running kernel
+///
+/// (Ordered so that the most advanced state reported for a module wins.)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DkmsStatus {
+    /// DKMS doesn't know about this module version at all
+    Absent,
+    /// The source is registered but hasn't been built
+    Added,
+    /// Built for the running kernel, but not installed into `/lib/modules`
+    Built,
+    /// Ready to be loaded with `modprobe`
+    Installed,
+}
+
+/// Ask `dkms status` how far along `version` of the module is for the running kernel
+///
+/// The first of `module_names` DKMS knows about is the one reported on, or the first of them if
+/// it knows none.
+pub fn check_dkms_status<'a>(
+    config: &Config,
+    module_names: &'a [String],
+    version: &str,
+) -> Result<(&'a str, DkmsStatus), UpdateNvidiaError> {
+    let stdout = check_output!(
+        Command::new(&config.dkms_path).arg("status"),
+        UpdateNvidiaError::DkmsFailed
+    )?;
+    let kernel = running_kernel().ok();
+    Ok(module_names
+        .iter()
+        .map(|name| (name.as_str(), parse_dkms_status(&stdout, name, version, kernel.as_deref())))
+        .find(|(_, status)| *status != DkmsStatus::Absent)
+        .unwrap_or((module_names.first().map_or("", String::as_str), DkmsStatus::Absent)))
+}
+
+/// The running kernel's release, as printed by `uname -r` (eg. `6.5.0-14-generic`)
+pub fn running_kernel() -> std::io::Result<String> {
+    Ok(std::fs::read_to_string(OSRELEASE_PATH)?.trim().to_owned())
+}
+
+/// The package providing the headers DKMS needs to build modules for `kernel`
+fn kernel_headers_package(
+    package_manager: &dyn PackageManager,
+    kernel: &str,
+) -> Result<String, UpdateNvidiaError> {
+    match package_manager.kind() {
+        PackageManagerKind::Apt => Ok(format!("linux-headers-{}", kernel)),
+        PackageManagerKind::Dnf => Ok(format!("kernel-devel-{}", kernel)),
+        // The devel and headers packages aren't named after the kernel release
+        PackageManagerKind::Zypper | PackageManagerKind::Pacman => {
+            Err(UpdateNvidiaError::Unsupported("kernel_check is only supported with apt and dnf"))
+        },
+    }
+}
+
+/// Check whether the headers for the running kernel are installed (`kernel_check`)
+pub fn check_kernel_headers_installed(
+    package_manager: &dyn PackageManager,
+) -> Result<bool, UpdateNvidiaError> {
+    let package = kernel_headers_package(package_manager, &running_kernel()?)?;
+    Ok(!package_manager.get_packages_matching(&package)?.is_empty())
+}
+
+/// Warn if the running kernel's headers are missing (installing them if
+/// `install_kernel_headers` is set), since DKMS can't build the new module without them
+///
+/// Failures are only logged, since not every driver install uses DKMS.
+pub fn check_kernel_headers(config: &Config, package_manager: &dyn PackageManager) {
+    let result = check_kernel_headers_installed(package_manager).and_then(|installed| {
+        if installed {
+            return Ok(());
+        }
+        let package = kernel_headers_package(package_manager, &running_kernel()?)?;
+        if !config.install_kernel_headers {
+            log!(
+                "WARNING: {} is not installed, so DKMS won't be able to build the upgraded \
+                 module for the running kernel. (Set install_kernel_headers = true to install it \
+                 automatically.)",
+                package
+            );
+            return Ok(());
+        }
+        log!("{} is not installed. Installing it so DKMS can build the module...", package);
+        package_manager.install_packages(&[package])
+    });
+    if let Err(e) = result {
+        log!("ERROR: Could not check for the running kernel's headers: {}", e);
+    }
+}
+
+/// Find the status of `module_name/version` for `kernel` (or any kernel, if `None`) in the output
+/// of `dkms status`
+///
+/// Both the current `nvidia/535.104.05, 6.5.0-14-generic, x86_64: installed` format and the
+/// older `nvidia, 535.104.05, ...` one are understood. `added` lines have no kernel field.
+fn parse_dkms_status(
+    stdout: &str,
+    module_name: &str,
+    version: &str,
+    kernel: Option<&str>,
+) -> DkmsStatus {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let (fields, status) = line.rsplit_once(':')?;
+            let mut fields = fields.split(&[',', '/'][..]).map(str::trim);
+            if (fields.next(), fields.next()) != (Some(module_name), Some(version)) {
+                return None;
+            }
+            let line_kernel = fields.next();
+            if line_kernel.is_some() && kernel.is_some() && line_kernel != kernel {
+                return None;
+            }
+            // Ignore any trailing notes like "(original_module exists)"
+            match status.split_whitespace().next()? {
+                "added" => Some(DkmsStatus::Added),
+                "built" => Some(DkmsStatus::Built),
+                "installed" => Some(DkmsStatus::Installed),
+                _ => None,
+            }
+        })
+        .max()
+        .unwrap_or(DkmsStatus::Absent)
+}
+
+/// If the upgraded packages include a DKMS-built driver, make sure its module is installed for the
+/// running kernel, running `dkms install` if it isn't
+///
+/// Does nothing on systems without a `*dkms*` package among the eligible ones or without `dkms`.
+pub fn ensure_installed(
+    config: &Config,
+    new_versions: &BTreeMap<String, String>,
+) -> Result<(), UpdateNvidiaError> {
+    let Some(package_version) =
+        new_versions.iter().find(|(name, _)| name.contains("dkms")).map(|(_, version)| version)
+    else {
+        return Ok(());
+    };
+    if !config.dkms_path.exists() {
+        log!("ERROR: A DKMS package is installed but {} is missing.", config.dkms_path.display());
+        return Ok(());
+    }
+
+    let version = upstream_version(package_version);
+    // Which name the open module is registered under depends on who packaged it
+    let module_names =
+        driver_variant(config, new_versions).dkms_module_names(&config.nvidia_kmod_name);
+    let (module, status) = check_dkms_status(config, &module_names, version)?;
+    match status {
+        DkmsStatus::Installed => {
+            log!("DKMS module {}/{} is installed for the running kernel.", module, version);
+        },
+        status => {
+            log!("DKMS module {}/{} is {:?}. Building and installing...", module, version, status);
+            check_call!(
+                Command::new(&config.dkms_path)
+                    .arg("install")
+                    .arg(format!("{}/{}", module, version)),
+                UpdateNvidiaError::DkmsFailed
+            )?;
+        },
+    }
+    Ok(())
+}
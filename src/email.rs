@@ -0,0 +1,125 @@
+//! Emailed upgrade reports for administrators of headless machines (`--email`)
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::atomic::Ordering;
+
+use crate::config::Config;
+use crate::error::{CalledProcessError, UpdateNvidiaError};
+use crate::log::log;
+use crate::output::{format_version_diff, Action, RunSummary};
+use crate::process::{clean_environment, format_command, DRY_RUN, VERBOSE};
+
+/// Where the kernel exposes the name to identify this machine by in the report
+const HOSTNAME_PATH: &str = "/proc/sys/kernel/hostname";
+
+/// How a run ended, as far as the report is concerned
+pub enum Report<'a> {
+    /// The packages were upgraded and the upgrade was made to take effect
+    Completed(&'a RunSummary),
+    /// The run was cut short by an error
+    Failed(&'a UpdateNvidiaError),
+}
+
+/// Whether `address` is a plain `local-part@domain` address, safe to put in a `To:` header
+///
+/// `sendmail -t` takes its recipients from the headers, so anything more (a display name, a
+/// second address, or a line break starting a `Bcc:` header) could send the report elsewhere.
+pub fn is_email_address(address: &str) -> bool {
+    let Some((local, domain)) = address.split_once('@') else {
+        return false;
+    };
+    let is_atext = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c);
+    let is_label = |label: &str| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    local.split('.').all(|atom| !atom.is_empty() && atom.chars().all(is_atext))
+        && domain.split('.').all(is_label)
+}
+
+/// Pipe a report on the run to `sendmail -t`, addressed to `to`
+///
+/// `sendmail` only queues the message, so this doesn't wait on the network.
+pub fn send_report(config: &Config, to: &str, report: &Report) -> Result<(), UpdateNvidiaError> {
+    let hostname = std::fs::read_to_string(HOSTNAME_PATH)
+        .map(|name| name.trim().to_owned())
+        .unwrap_or_else(|_| "localhost".to_owned());
+    let message = format_message(to, &hostname, report);
+
+    let mut cmd = Command::new(&config.sendmail_path);
+    cmd.arg("-t");
+    if DRY_RUN.load(Ordering::Relaxed) {
+        log!("[DRY RUN] would email a report to {} via: {}", to, format_command(&cmd));
+        return Ok(());
+    }
+    let verbose = VERBOSE.load(Ordering::Relaxed);
+    if verbose {
+        log!("+ {}", format_command(&cmd));
+    }
+
+    clean_environment(&mut cmd);
+    let mut child = cmd.stdin(Stdio::piped()).spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // Dropping stdin afterward closes it, which tells sendmail the message is complete
+        stdin.write_all(message.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if verbose {
+        log!("+ {}", status);
+    }
+    if !status.success() {
+        // sendmail's stderr wasn't captured, but went straight to ours
+        return Err(UpdateNvidiaError::SendmailFailed(CalledProcessError::new(&cmd, status, None)));
+    }
+    Ok(())
+}
+
+/// Render the report as an RFC 2822 message, with the headers `sendmail -t` reads recipients from
+fn format_message(to: &str, hostname: &str, report: &Report) -> String {
+    let (subject, body) = match report {
+        Report::Completed(summary) => (
+            format!("NVIDIA driver upgraded on {}", hostname),
+            format!(
+                "update_nvidia upgraded the NVIDIA driver on {}.\n\nPackage changes:\n{}\n\n\
+                 Action taken: {}\nElapsed time: {}s\n",
+                hostname,
+                indent(&format_version_diff(&summary.old_versions, &summary.new_versions)),
+                describe_action(summary.action),
+                summary.elapsed.as_secs()
+            ),
+        ),
+        Report::Failed(e) => (
+            format!("NVIDIA driver upgrade FAILED on {}", hostname),
+            format!(
+                "update_nvidia could not upgrade the NVIDIA driver on {}:\n\n{}\n\nSee its log \
+                 for details.\n",
+                hostname,
+                indent(&e.to_string())
+            ),
+        ),
+    };
+    format!(
+        "To: {}\nFrom: update_nvidia <root@{}>\nSubject: {}\nMIME-Version: 1.0\n\
+         Content-Type: text/plain; charset=utf-8\nContent-Transfer-Encoding: 8bit\n\n{}",
+        to, hostname, subject, body
+    )
+}
+
+/// What was done to make the upgrade take effect, in words
+fn describe_action(action: Action) -> &'static str {
+    match action {
+        Action::None => {
+            "none (the kernel module still needs to be reloaded or the system rebooted)"
+        },
+        Action::ModuleReload => "reloaded the kernel module",
+        Action::Reboot => "rebooted the system",
+    }
+}
+
+/// Indent every line of `text` by four spaces
+fn indent(text: &str) -> String {
+    text.lines().map(|line| format!("    {}", line)).collect::<Vec<_>>().join("\n")
+}
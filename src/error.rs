@@ -0,0 +1,425 @@
+//! Error types which allow callers to distinguish between the different ways a run can fail
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output};
+
+use crate::config::ConfigError;
+use crate::kmod::ModuleUnloadError;
+use crate::process::redact_credentials;
+use crate::procfs::DisplayServer;
+
+/// Workaround for `ExitStatusError` being unstable, with enough detail to say which command
+/// failed and why
+#[derive(Debug)]
+pub struct CalledProcessError {
+    /// The program which was run (eg. `/usr/bin/apt-get`)
+    pub command: String,
+    /// The arguments it was given, with any proxy passwords masked
+    pub args: Vec<String>,
+    /// The subprocess's exit code (or `None` if killed by a POSIX signal)
+    pub code: Option<i32>,
+    /// Everything it wrote to stderr, if that was captured
+    pub stderr: Option<String>,
+}
+
+impl CalledProcessError {
+    /// Describe `cmd` having exited with `status` after writing `stderr` (if it was captured)
+    pub fn new(cmd: &Command, status: ExitStatus, stderr: Option<&str>) -> Self {
+        Self {
+            command: cmd.get_program().to_string_lossy().into_owned(),
+            args: cmd.get_args().map(|arg| redact_credentials(&arg.to_string_lossy())).collect(),
+            code: status.code(),
+            stderr: stderr.map(str::to_owned),
+        }
+    }
+
+    /// Describe `cmd` having produced `output`
+    pub fn from_output(cmd: &Command, output: &Output) -> Self {
+        Self::new(cmd, output.status, Some(&String::from_utf8_lossy(&output.stderr)))
+    }
+}
+
+impl std::fmt::Display for CalledProcessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let program = Path::new(&self.command)
+            .file_name()
+            .map_or_else(|| self.command.clone(), |name| name.to_string_lossy().into_owned());
+        write!(f, "{}", program)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        match self.code {
+            Some(code) => write!(f, " returned exit code {}", code)?,
+            None => write!(f, " was killed by a signal")?,
+        }
+        // Every line was already logged as it was written, so the last one (usually the
+        // conclusion, like apt-get's "E: Unable to fetch some archives") is enough here
+        let last_line = self
+            .stderr
+            .as_deref()
+            .and_then(|stderr| stderr.lines().map(str::trim).rfind(|line| !line.is_empty()));
+        if let Some(line) = last_line {
+            write!(f, ": {}", line)?;
+        }
+        Ok(())
+    }
+}
+impl Error for CalledProcessError {}
+
+/// Every way in which a run of this tool can fail
+#[derive(Debug)]
+pub enum UpdateNvidiaError {
+    /// `apt-get` returned a non-success exit code
+    AptGetFailed(CalledProcessError),
+    /// `apt-cache` returned a non-success exit code
+    AptCacheFailed(CalledProcessError),
+    /// `apt-mark` returned a non-success exit code
+    AptMarkFailed(CalledProcessError),
+    /// `add-apt-repository` returned a non-success exit code
+    AddAptRepositoryFailed(CalledProcessError),
+    /// `dpkg-query` returned a non-success exit code
+    PackageQueryFailed(CalledProcessError),
+    /// `rmmod` returned a non-success exit code (eg. because the module is in use)
+    KernelModuleUnloadFailed(ModuleUnloadError),
+    /// `modprobe` returned a non-success exit code
+    KernelModuleLoadFailed(CalledProcessError),
+    /// The module which got loaded isn't the upgraded one (eg. because a stale copy was cached)
+    LoadedVersionMismatch {
+        /// The version in `/proc/driver/nvidia/version`
+        loaded: String,
+        /// The upstream version of the upgraded `nvidia-driver-*` package
+        expected: String,
+    },
+    /// `nvidia-smi -L` failed or listed no GPUs after the module was reloaded
+    DriverVerificationFailed(String),
+    /// `reboot` returned a non-success exit code
+    RebootFailed(CalledProcessError),
+    /// `dkms` returned a non-success exit code (eg. because the module failed to build)
+    DkmsFailed(CalledProcessError),
+    /// `notify-send` returned a non-success exit code
+    NotifySendFailed(CalledProcessError),
+    /// `ubuntu-drivers autoinstall` returned a non-success exit code
+    UbuntuDriversFailed(CalledProcessError),
+    /// `dnf` returned a non-success exit code
+    DnfFailed(CalledProcessError),
+    /// `rpm` returned a non-success exit code
+    RpmFailed(CalledProcessError),
+    /// `zypper` returned a non-success exit code
+    ZypperFailed(CalledProcessError),
+    /// `pacman` returned a non-success exit code
+    PacmanFailed(CalledProcessError),
+    /// `sendmail` returned a non-success exit code
+    SendmailFailed(CalledProcessError),
+    /// `mokutil` returned a non-success exit code
+    MokutilFailed(CalledProcessError),
+    /// `systemctl` returned a non-success exit code
+    SystemctlFailed(CalledProcessError),
+    /// `gpg` returned a non-success exit code
+    GpgFailed(CalledProcessError),
+    /// A `pre_upgrade_hook` or `post_upgrade_hook` script returned a non-success exit code
+    HookFailed(CalledProcessError),
+    /// The config file exists but could not be read
+    ConfigUnreadable(PathBuf, std::io::Error),
+    /// The config file could be read but contained invalid or unrecognized settings
+    ConfigInvalid(PathBuf, ConfigError),
+    /// An I/O error, such as failure to spawn a subprocess
+    IoError(std::io::Error),
+    /// Another instance holds the lock file (with the holder's PID, if it could be read)
+    LockHeld(Option<u32>),
+    /// Another package manager still held the dpkg lock after `dpkg_lock_timeout`
+    DpkgLockHeld {
+        /// The process holding it, if `/proc/locks` said
+        pid: Option<u32>,
+        /// How long was spent waiting for it
+        waited: std::time::Duration,
+    },
+    /// The module couldn't be reloaded and `--no-reboot` forbade falling back to a reboot
+    RebootRequired {
+        /// The command the user should run to finish the upgrade
+        reboot_path: PathBuf,
+    },
+    /// Reloading the module would interrupt this display server and `--allow-display-interrupt`
+    /// wasn't given
+    DisplayServerRunning(DisplayServer),
+    /// `--assume-yes` wasn't given and there's no terminal to ask for confirmation on
+    ConfirmationRequired,
+    /// The user answered "no" when asked to confirm the upgrade
+    UpgradeDeclined,
+    /// The upgrade would install these NVIDIA packages and `accept_new_packages` is off
+    NewPackagesRefused(Vec<String>),
+    /// `--interactive` was given but there's no terminal to prompt on
+    InteractiveWithoutTerminal,
+    /// The user pressed Ctrl-C (or closed stdin) at an `--interactive` prompt for this command
+    PromptAborted(String),
+    /// The upgrade would install a driver newer than `--max-version` allows
+    MaxVersionExceeded {
+        /// The version the package manager would upgrade to
+        candidate: String,
+        /// The limit given via `--max-version`
+        max: String,
+    },
+    /// Secure Boot is enabled, DKMS has no key to sign the rebuilt module with, and
+    /// `--secure-boot-check` was given
+    SecureBootKeyMissing,
+    /// These packages still weren't held after retrying `--hold-new-packages` verification
+    HoldsMissing(Vec<String>),
+    /// The effective UID isn't 0, so none of the package or module commands would be allowed
+    NotRunningAsRoot,
+    /// `--rollback` couldn't find a recorded run to roll back
+    NoRollbackTarget(String),
+    /// None of the `--apt-key` key's user IDs are at the host of an APT source, so it may not be
+    /// the key for the repository it was meant for
+    AptKeyMismatch {
+        /// The key file that was given
+        path: PathBuf,
+        /// The email domains of the key's user IDs
+        domains: Vec<String>,
+    },
+    /// The requested feature isn't available with the detected package manager
+    Unsupported(&'static str),
+    /// A command was killed for running longer than `--timeout`
+    Timeout {
+        /// The command line, as it would be logged
+        command: String,
+        /// The timeout it exceeded
+        after: std::time::Duration,
+    },
+    /// There isn't enough free space to safely download and unpack the upgrade
+    InsufficientDiskSpace {
+        /// The directory packages would be downloaded into
+        path: PathBuf,
+        /// Bytes currently available on its filesystem
+        available: u64,
+        /// Bytes `min_free_bytes` calls for
+        required: u64,
+    },
+    /// These required commands don't exist or aren't executable (eg. because of a typo in a
+    /// `*_path` setting)
+    MissingDependency {
+        /// Every missing command, as configured
+        paths: Vec<PathBuf>,
+    },
+    /// A subprocess produced output which could not be interpreted
+    ParseError(String),
+    /// The system clock reported a time which makes staleness checks impossible
+    ClockError(std::time::SystemTimeError),
+}
+
+impl std::fmt::Display for UpdateNvidiaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // The error names the command itself
+            Self::AptGetFailed(e)
+            | Self::AptCacheFailed(e)
+            | Self::AptMarkFailed(e)
+            | Self::AddAptRepositoryFailed(e)
+            | Self::PackageQueryFailed(e)
+            | Self::KernelModuleLoadFailed(e)
+            | Self::RebootFailed(e)
+            | Self::DkmsFailed(e)
+            | Self::NotifySendFailed(e)
+            | Self::UbuntuDriversFailed(e)
+            | Self::DnfFailed(e)
+            | Self::RpmFailed(e)
+            | Self::ZypperFailed(e)
+            | Self::PacmanFailed(e)
+            | Self::SendmailFailed(e)
+            | Self::MokutilFailed(e)
+            | Self::SystemctlFailed(e)
+            | Self::GpgFailed(e) => write!(f, "{}", e),
+            Self::KernelModuleUnloadFailed(e) => write!(f, "rmmod failed: {}", e),
+            Self::HookFailed(e) => write!(f, "Hook failed: {}", e),
+            Self::ConfigUnreadable(path, e) => {
+                write!(f, "Could not read {}: {}", path.display(), e)
+            },
+            Self::ConfigInvalid(path, e) => write!(f, "{}: {}", path.display(), e),
+            Self::IoError(e) => write!(f, "I/O error: {}", e),
+            Self::LockHeld(Some(pid)) => {
+                write!(f, "Another instance of update_nvidia is already running (PID {})", pid)
+            },
+            Self::LockHeld(None) => {
+                write!(f, "Another instance of update_nvidia is already running")
+            },
+            Self::DpkgLockHeld { pid, waited } => {
+                write!(f, "Another package manager")?;
+                if let Some(pid) = pid {
+                    write!(f, " (PID {})", pid)?;
+                }
+                write!(
+                    f,
+                    " is still using the package database after waiting {}s for it \
+                     (dpkg_lock_timeout)",
+                    waited.as_secs()
+                )
+            },
+            Self::RebootRequired { .. } => {
+                write!(f, "A reboot is required to finish the upgrade but --no-reboot was given")
+            },
+            Self::DisplayServerRunning(server) => write!(
+                f,
+                "The updated nvidia packages were installed, but {} is using the GPU, so the \
+                 kernel module was left alone rather than ending the graphical session or \
+                 rebooting. Reboot when convenient to finish the upgrade, or pass \
+                 --allow-display-interrupt to reload it anyway.",
+                server
+            ),
+            Self::ConfirmationRequired => write!(
+                f,
+                "Refusing to upgrade without confirmation. Pass --assume-yes (or set assume_yes = \
+                 true in the config file) to upgrade when not running in a terminal."
+            ),
+            Self::UpgradeDeclined => write!(f, "Upgrade cancelled. Nothing was changed."),
+            Self::NewPackagesRefused(names) => write!(
+                f,
+                "The upgrade would install new NVIDIA packages ({}) but accept_new_packages is \
+                 off. Pass --accept-new-packages to allow them. Nothing was changed.",
+                names.join(" ")
+            ),
+            Self::InteractiveWithoutTerminal => write!(
+                f,
+                "--interactive needs a terminal to prompt on, but stdin isn't one. Leave it out \
+                 when running from a service or script."
+            ),
+            Self::PromptAborted(command) => write!(f, "Aborted before running: {}", command),
+            Self::MaxVersionExceeded { candidate, max } => write!(
+                f,
+                "The available driver ({}) is newer than --max-version {}. Nothing was changed.",
+                candidate, max
+            ),
+            Self::SecureBootKeyMissing => write!(
+                f,
+                "Secure Boot is enabled but DKMS has no key to sign the rebuilt module with, so \
+                 the kernel would refuse to load it. Nothing was changed. Set up a Machine Owner \
+                 Key (and enroll it with mokutil --import) first."
+            ),
+            Self::AptKeyMismatch { path, domains } if domains.is_empty() => write!(
+                f,
+                "The key in {} has no email addresses to match against the APT sources' hosts, \
+                 so it wasn't installed.",
+                path.display()
+            ),
+            Self::AptKeyMismatch { path, domains } => write!(
+                f,
+                "The key in {} is for {}, which isn't the host of any APT source, so it wasn't \
+                 installed. Add the repository's source first.",
+                path.display(),
+                domains.join(", ")
+            ),
+            Self::Unsupported(what) => write!(f, "{}", what),
+            Self::NoRollbackTarget(why) => write!(f, "Nothing to roll back to: {}", why),
+            Self::NotRunningAsRoot => {
+                write!(f, "This tool must be run as root. Try: sudo update_nvidia")
+            },
+            Self::LoadedVersionMismatch { loaded, expected } => write!(
+                f,
+                "The reloaded kernel module is version {} rather than the upgraded {}",
+                loaded, expected
+            ),
+            Self::DriverVerificationFailed(why) => {
+                write!(f, "The reloaded driver isn't working: {}", why)
+            },
+            Self::HoldsMissing(names) => write!(
+                f,
+                "These packages could not be held and may be upgraded outside of update_nvidia: \
+                 {}",
+                names.join(" ")
+            ),
+            Self::Timeout { command, after } => write!(
+                f,
+                "{} was killed after running for more than {}s (--timeout)",
+                command,
+                after.as_secs()
+            ),
+            Self::InsufficientDiskSpace { path, available, required } => write!(
+                f,
+                "Only {} MiB free on the filesystem containing {} ({} MiB required). Free up some \
+                 space or lower min_free_bytes.",
+                available / (1024 * 1024),
+                path.display(),
+                required / (1024 * 1024)
+            ),
+            Self::MissingDependency { paths } => {
+                let paths: Vec<_> = paths.iter().map(|path| path.display().to_string()).collect();
+                write!(
+                    f,
+                    "These required commands are missing or not executable: {}. Install them or \
+                     correct the corresponding *_path settings.",
+                    paths.join(", ")
+                )
+            },
+            Self::ParseError(msg) => write!(f, "Could not parse command output: {}", msg),
+            Self::ClockError(e) => write!(f, "System clock error: {}", e),
+        }
+    }
+}
+
+impl Error for UpdateNvidiaError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::AptGetFailed(e)
+            | Self::AptCacheFailed(e)
+            | Self::AptMarkFailed(e)
+            | Self::AddAptRepositoryFailed(e)
+            | Self::PackageQueryFailed(e)
+            | Self::KernelModuleLoadFailed(e)
+            | Self::RebootFailed(e)
+            | Self::DkmsFailed(e)
+            | Self::NotifySendFailed(e)
+            | Self::UbuntuDriversFailed(e)
+            | Self::DnfFailed(e)
+            | Self::RpmFailed(e)
+            | Self::ZypperFailed(e)
+            | Self::PacmanFailed(e)
+            | Self::SendmailFailed(e)
+            | Self::MokutilFailed(e)
+            | Self::SystemctlFailed(e)
+            | Self::GpgFailed(e)
+            | Self::HookFailed(e) => Some(e),
+            Self::ConfigUnreadable(_, e) | Self::IoError(e) => Some(e),
+            Self::ConfigInvalid(_, e) => Some(e),
+            Self::KernelModuleUnloadFailed(e) => Some(e),
+            Self::ClockError(e) => Some(e),
+            Self::LockHeld(_)
+            | Self::DpkgLockHeld { .. }
+            | Self::RebootRequired { .. }
+            | Self::DisplayServerRunning(_)
+            | Self::ConfirmationRequired
+            | Self::UpgradeDeclined
+            | Self::NewPackagesRefused(_)
+            | Self::InteractiveWithoutTerminal
+            | Self::PromptAborted(_)
+            | Self::MaxVersionExceeded { .. }
+            | Self::SecureBootKeyMissing
+            | Self::AptKeyMismatch { .. }
+            | Self::Unsupported(_)
+            | Self::NoRollbackTarget(_)
+            | Self::NotRunningAsRoot
+            | Self::LoadedVersionMismatch { .. }
+            | Self::DriverVerificationFailed(_)
+            | Self::HoldsMissing(_)
+            | Self::Timeout { .. }
+            | Self::InsufficientDiskSpace { .. }
+            | Self::MissingDependency { .. }
+            | Self::ParseError(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for UpdateNvidiaError {
+    fn from(e: std::io::Error) -> Self {
+        Self::IoError(e)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for UpdateNvidiaError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        Self::ParseError(e.to_string())
+    }
+}
+
+impl From<std::time::SystemTimeError> for UpdateNvidiaError {
+    fn from(e: std::time::SystemTimeError) -> Self {
+        Self::ClockError(e)
+    }
+}
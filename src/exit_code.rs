@@ -0,0 +1,53 @@
+//! Every exit code this tool can emit, so scripts can rely on them
+//!
+//! The codes for `--check-only` overlap with the general ones, since that mode answers a yes/no
+//! question via its exit code instead.
+
+/// The run completed (including when there was nothing to upgrade or it was skipped on battery)
+pub const SUCCESS: i32 = 0;
+
+/// The upgrade failed or was refused, or packages were left unheld afterward
+pub const UPGRADE_ERROR: i32 = 1;
+
+/// The command line was invalid or incomplete
+pub const USAGE_ERROR: i32 = 2;
+
+/// Another instance (or package manager) is already running (`EX_TEMPFAIL` from `sysexits.h`)
+pub const LOCK_HELD: i32 = 75;
+
+/// The packages were upgraded, but the new kernel module won't be loaded until a reboot
+pub const REBOOT_REQUIRED: i32 = 100;
+
+/// The packages were upgraded, but the kernel module was left alone because a display server
+/// is using it (see `--allow-display-interrupt`)
+pub const DISPLAY_IN_USE: i32 = 101;
+
+/// A required command couldn't be found (as with the shell's "command not found")
+pub const DEPENDENCY_MISSING: i32 = 127;
+
+/// `--check-only`: An upgrade is available
+pub const CHECK_UPGRADE_AVAILABLE: i32 = 0;
+
+/// `--check-only`: There's nothing to upgrade
+pub const CHECK_NO_UPGRADE: i32 = 1;
+
+/// `--check-only`: The check itself failed
+pub const CHECK_FAILED: i32 = 2;
+
+/// Each general exit code and its meaning, for `--help`
+pub const DESCRIPTIONS: &[(i32, &str)] = &[
+    (SUCCESS, "Success, or nothing needed upgrading"),
+    (UPGRADE_ERROR, "The upgrade failed or was refused, or packages were left unheld"),
+    (USAGE_ERROR, "Invalid command line or missing option"),
+    (LOCK_HELD, "Another instance (or package manager) is already running"),
+    (REBOOT_REQUIRED, "Upgraded, but a reboot is needed to load the new module"),
+    (DISPLAY_IN_USE, "Upgraded, but the module was left alone for a running display server"),
+    (DEPENDENCY_MISSING, "A required command could not be found"),
+];
+
+/// Each `--check-only` exit code and its meaning, for `--help`
+pub const CHECK_DESCRIPTIONS: &[(i32, &str)] = &[
+    (CHECK_UPGRADE_AVAILABLE, "An NVIDIA upgrade is available"),
+    (CHECK_NO_UPGRADE, "There is nothing to upgrade"),
+    (CHECK_FAILED, "The check failed"),
+];
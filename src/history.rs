@@ -0,0 +1,90 @@
+//! An append-only record of the package versions before and after each run, for auditing and
+//! rollback
+//!
+//! Each run is one line of tab-separated fields: an RFC 3339 timestamp, the action taken (as in
+//! `--json` output), then the old and new versions as space-separated `name=version` pairs.
+//! Debian and RPM package names and versions can't contain tabs, spaces, or `=`.
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::log::format_rfc3339;
+use crate::output::Action;
+
+/// One run's line from the versions file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// When the run finished, as an RFC 3339 UTC timestamp
+    pub timestamp: String,
+    /// What was done to make the upgrade take effect (eg. `module_reload`)
+    pub action: String,
+    /// Eligible packages and their versions before the upgrade
+    pub old_versions: BTreeMap<String, String>,
+    /// Eligible packages and their versions after the upgrade
+    pub new_versions: BTreeMap<String, String>,
+}
+
+/// Append an entry for the current run to the versions file at `path`, creating it if needed
+pub fn write_versions_report(
+    path: &Path,
+    old: &BTreeMap<String, String>,
+    new: &BTreeMap<String, String>,
+    action: Action,
+) -> io::Result<()> {
+    let line = format!(
+        "{}\t{}\t{}\t{}\n",
+        format_rfc3339(SystemTime::now()),
+        action.as_str(),
+        format_versions(old),
+        format_versions(new)
+    );
+    // A single write of the whole line, so concurrent appends can't interleave mid-entry
+    OpenOptions::new().create(true).append(true).open(path)?.write_all(line.as_bytes())
+}
+
+/// Load every entry from the versions file at `path`, in the order they were written
+pub fn read_history(path: &Path) -> io::Result<Vec<HistoryEntry>> {
+    fs::read_to_string(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| {
+            parse_entry(line).ok_or_else(|| {
+                let message = format!("{}: malformed entry on line {}", path.display(), idx + 1);
+                io::Error::new(io::ErrorKind::InvalidData, message)
+            })
+        })
+        .collect()
+}
+
+/// Render versions as space-separated `name=version` pairs
+fn format_versions(versions: &BTreeMap<String, String>) -> String {
+    let pairs: Vec<String> =
+        versions.iter().map(|(name, version)| format!("{}={}", name, version)).collect();
+    pairs.join(" ")
+}
+
+/// The inverse of [`format_versions`]
+fn parse_versions(field: &str) -> Option<BTreeMap<String, String>> {
+    field
+        .split_whitespace()
+        .map(|pair| pair.split_once('=').map(|(name, version)| (name.into(), version.into())))
+        .collect()
+}
+
+/// Parse one line written by [`write_versions_report`]
+fn parse_entry(line: &str) -> Option<HistoryEntry> {
+    let mut fields = line.split('\t');
+    let (timestamp, action) = (fields.next()?, fields.next()?);
+    let (old_versions, new_versions) =
+        (parse_versions(fields.next()?)?, parse_versions(fields.next()?)?);
+    Some(HistoryEntry {
+        timestamp: timestamp.to_owned(),
+        action: action.to_owned(),
+        old_versions,
+        new_versions,
+    })
+}
@@ -0,0 +1,26 @@
+//! User-supplied scripts run around the upgrade (eg. to stop GPU workloads or notify monitoring)
+
+use std::path::Path;
+use std::process::Command;
+
+use crate::error::{CalledProcessError, UpdateNvidiaError};
+use crate::log::log;
+use crate::process::run_status;
+
+/// Run the hook script at `path` with `env` added to its environment
+///
+/// Goes through [`run_status`], so hooks are only reported under `--dry-run`.
+pub fn run_hook(path: &Path, env: &[(&str, &str)]) -> Result<(), UpdateNvidiaError> {
+    log!("Running hook {}...", path.display());
+    let mut cmd = Command::new(path);
+    cmd.envs(env.iter().copied());
+    let (status, stderr) = run_status(&mut cmd)?;
+    if !status.success() {
+        return Err(UpdateNvidiaError::HookFailed(CalledProcessError::new(
+            &cmd,
+            status,
+            Some(&stderr),
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,91 @@
+//! Keeping logind from suspending, shutting down, or idling the system mid-upgrade
+
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::Ordering;
+
+use crate::log::log;
+use crate::process::{clean_environment, format_command, DRY_RUN};
+
+/// Command `systemd-inhibit` runs to hold the lock, which exits once its stdin is closed
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const HOLD_COMMAND_PATH: &str = "/bin/cat";
+
+/// An inhibitor lock, held for as long as this value (and the `systemd-inhibit` child) lives
+///
+/// The lock is tied to a `systemd-inhibit` process whose stdin is a pipe from this one, so it's
+/// released when this is dropped or [`release`](Self::release)d, and also if this process dies.
+pub struct SystemdInhibitLock {
+    /// The running `systemd-inhibit`, or `None` if the lock couldn't be taken or was released
+    child: Option<Child>,
+}
+
+impl SystemdInhibitLock {
+    /// Block shutdown, sleep, and idle actions using the `systemd-inhibit` at `inhibit_path`
+    ///
+    /// The upgrade is more important than the lock, so failure only produces a warning.
+    pub fn acquire(inhibit_path: &Path) -> Self {
+        let mut cmd = Command::new(inhibit_path);
+        cmd.args([
+            "--what=shutdown:sleep:idle",
+            "--who=update_nvidia",
+            "--why=NVIDIA driver upgrade in progress",
+            "--mode=block",
+            HOLD_COMMAND_PATH,
+        ]);
+        if DRY_RUN.load(Ordering::Relaxed) {
+            log!("[DRY RUN] would run: {}", format_command(&cmd));
+            return Self { child: None };
+        }
+
+        clean_environment(&mut cmd);
+        match cmd.stdin(Stdio::piped()).stdout(Stdio::null()).spawn() {
+            Ok(child) => Self { child: Some(child) },
+            Err(e) => {
+                let reason = match e.kind() {
+                    ErrorKind::NotFound => format!("{} not found", inhibit_path.display()),
+                    _ => e.to_string(),
+                };
+                log!(
+                    "WARNING: Could not block shutdown and sleep during the upgrade. ({})",
+                    reason
+                );
+                Self { child: None }
+            },
+        }
+    }
+
+    /// A lock which was never taken, for tests of code which only ever releases one
+    #[cfg(test)]
+    pub fn none() -> Self {
+        Self { child: None }
+    }
+
+    /// Release the lock early (eg. so it doesn't block this tool's own reboot)
+    ///
+    /// If `systemd-inhibit` turns out to have failed (eg. because logind isn't running), that's
+    /// reported now, since there was no way to tell without waiting for it when it was started.
+    pub fn release(&mut self) {
+        let Some(mut child) = self.child.take() else {
+            return;
+        };
+        drop(child.stdin.take());
+        match child.wait() {
+            Ok(status) if status.success() => {},
+            Ok(status) => log!(
+                "WARNING: systemd-inhibit exited with {}, so shutdown and sleep may not have been \
+                 blocked during the upgrade.",
+                status
+            ),
+            Err(e) => log!("WARNING: Could not wait for systemd-inhibit to exit. ({})", e),
+        }
+    }
+}
+
+impl Drop for SystemdInhibitLock {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
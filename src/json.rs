@@ -0,0 +1,241 @@
+//! Minimal JSON support for machine-readable output and the state file
+//!
+//! Hand-written rather than pulling in `serde_json` to keep this tool free of non-std
+//! dependencies.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+
+/// A JSON value which serializes itself via its `Display` implementation
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Bool(bool),
+    Integer(i64),
+    String(String),
+    /// An object, with keys kept in insertion order so output is predictable
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Build an object from a list of `(key, value)` pairs
+    pub fn object<'a>(fields: impl IntoIterator<Item = (&'a str, Json)>) -> Self {
+        Json::Object(fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+    }
+}
+
+impl Json {
+    /// Parse a document containing only the kinds of values [`Json`] can represent
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser { text, pos: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos < text.len() {
+            return Err(format!("trailing characters at offset {}", parser.pos));
+        }
+        Ok(value)
+    }
+
+    /// Look up `key` if this is an object
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// The value, if this is a boolean
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// The value, if this is a string
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The inverse of `From<&BTreeMap<String, String>>`, if this is an object of strings
+    pub fn to_string_map(&self) -> Option<BTreeMap<String, String>> {
+        match self {
+            Json::Object(fields) => {
+                fields.iter().map(|(k, v)| Some((k.clone(), v.as_str()?.to_owned()))).collect()
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Recursive-descent parser state for [`Json::parse`]
+struct Parser<'a> {
+    text: &'a str,
+    /// Byte offset of the next unconsumed character
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    /// Consume `expected` (after any whitespace) or fail
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += c.len_utf8();
+                Ok(())
+            },
+            _ => Err(format!("expected {:?} at offset {}", expected, self.pos)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        let rest = &self.text[self.pos..];
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('"') => self.string().map(Json::String),
+            Some('t') if rest.starts_with("true") => {
+                self.pos += "true".len();
+                Ok(Json::Bool(true))
+            },
+            Some('f') if rest.starts_with("false") => {
+                self.pos += "false".len();
+                Ok(Json::Bool(false))
+            },
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let len = rest
+                    .char_indices()
+                    .find(|&(idx, c)| !(c.is_ascii_digit() || (idx == 0 && c == '-')))
+                    .map_or(rest.len(), |(idx, _)| idx);
+                let number = rest[..len]
+                    .parse()
+                    .map_err(|_| format!("invalid integer at offset {}", self.pos))?;
+                self.pos += len;
+                Ok(Json::Integer(number))
+            },
+            _ => Err(format!("unsupported or missing value at offset {}", self.pos)),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                },
+                _ => return Err(format!("expected ',' or '}}' at offset {}", self.pos)),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut result = String::new();
+        let mut chars = self.text[self.pos..].char_indices();
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += idx + 1;
+                    return Ok(result);
+                },
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => result.push('"'),
+                    Some('\\') => result.push('\\'),
+                    Some('/') => result.push('/'),
+                    Some('n') => result.push('\n'),
+                    Some('r') => result.push('\r'),
+                    Some('t') => result.push('\t'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                        let code = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                        result.push(code.ok_or("unsupported \\u escape in string")?);
+                    },
+                    _ => return Err(format!("invalid escape in string at offset {}", self.pos)),
+                },
+                c => result.push(c),
+            }
+        }
+        Err("unterminated string".into())
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Json::Bool(value)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_owned())
+    }
+}
+
+impl From<&BTreeMap<String, String>> for Json {
+    fn from(map: &BTreeMap<String, String>) -> Self {
+        Json::Object(map.iter().map(|(k, v)| (k.clone(), Json::String(v.clone()))).collect())
+    }
+}
+
+/// Write `value` as a quoted, escaped JSON string
+fn write_str(f: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in value.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if u32::from(c) < 0x20 => write!(f, "\\u{:04x}", u32::from(c))?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Integer(i) => write!(f, "{}", i),
+            Json::String(s) => write_str(f, s),
+            Json::Object(fields) => {
+                f.write_char('{')?;
+                for (idx, (key, value)) in fields.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_char('}')
+            },
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Unloading and loading kernel modules, with enough detail about failures to decide whether to
+//! reboot
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::Ordering;
+
+use crate::config::Config;
+use crate::error::{CalledProcessError, UpdateNvidiaError};
+use crate::log::log;
+use crate::process::{check_call, format_command, run_output, DRY_RUN};
+use crate::procfs::{list_loaded_modules, LoadedModule};
+use crate::prompt::confirm_command;
+
+/// Default modules which depend on the main `nvidia` module and keep it in use while loaded
+pub const DEPENDENT_KMOD_NAMES: &[&str] = &["nvidia_drm", "nvidia_modeset", "nvidia_uvm"];
+
+/// The `modprobe` config file the driver's module parameters conventionally go in
+const MODPROBE_CONF_PATH: &str = "/etc/modprobe.d/nvidia.conf";
+
+/// Why `rmmod` couldn't unload a module
+#[derive(Debug)]
+pub enum ModuleUnloadError {
+    /// A process or another module is using it, so a reboot may be the only way to replace it
+    InUse(String),
+    /// Anything else (eg. permission denied), including `rmmod`'s error message
+    Other(CalledProcessError),
+}
+
+impl std::fmt::Display for ModuleUnloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InUse(message) => write!(f, "Module is in use ({})", message),
+            Self::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ModuleUnloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InUse(_) => None,
+            Self::Other(e) => Some(e),
+        }
+    }
+}
+
+/// Unload the kernel module `name` using the `rmmod` at `rmmod_path`
+///
+/// `rmmod`'s error messages are echoed as usual, but also inspected so a module which is merely
+/// in use can be told apart from other failures. A module that turns out not to be loaded
+/// counts as successfully unloaded.
+pub fn unload_module(rmmod_path: &Path, name: &str) -> Result<(), UpdateNvidiaError> {
+    let mut cmd = Command::new(rmmod_path);
+    cmd.arg(name);
+    if DRY_RUN.load(Ordering::Relaxed) {
+        log!("[DRY RUN] would run: {}", format_command(&cmd));
+        return Ok(());
+    }
+
+    confirm_command(&cmd)?;
+    let output = run_output(&mut cmd)?;
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+    for line in stderr.lines() {
+        log!("{}", line);
+    }
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let error = if stderr.contains("is in use") {
+        ModuleUnloadError::InUse(stderr)
+    } else if stderr.contains("is not currently loaded") {
+        log!("The {} module was already unloaded.", name);
+        return Ok(());
+    } else {
+        ModuleUnloadError::Other(CalledProcessError::new(&cmd, output.status, Some(&stderr)))
+    };
+    Err(UpdateNvidiaError::KernelModuleUnloadFailed(error))
+}
+
+/// Unload whichever of `dependent_kmod_names` are loaded, then `nvidia_kmod_name` itself
+///
+/// Each dependent module is pushed onto `unloaded` once it's gone, so the caller can load them
+/// again (in reverse order) after the main module, even if a later step fails. If
+/// `/proc/modules` can't be read, every dependent module is tried in the configured order and
+/// left to `rmmod` to skip.
+pub fn unload_nvidia_modules(
+    config: &Config,
+    unloaded: &mut Vec<String>,
+) -> Result<(), UpdateNvidiaError> {
+    let deps = match list_loaded_modules() {
+        Ok(modules) => unload_order(&modules, &config.dependent_kmod_names),
+        Err(e) => {
+            log!("ERROR: Could not read /proc/modules. ({})", e);
+            config.dependent_kmod_names.clone()
+        },
+    };
+    for name in deps {
+        unload_module(&config.rmmod_path, &name)?;
+        unloaded.push(name);
+    }
+    unload_module(&config.rmmod_path, &config.nvidia_kmod_name)
+}
+
+/// Load `nvidia_kmod_name` with `modprobe`, passing along any `modprobe_options`
+///
+/// `modprobe` also applies the `options` lines in its own config files, so this warns if
+/// [`MODPROBE_CONF_PATH`] sets parameters for the module too, since it's easy to lose track of
+/// which one a value came from.
+pub fn load_nvidia_module(config: &Config) -> Result<(), UpdateNvidiaError> {
+    let module = &config.nvidia_kmod_name;
+    if !config.modprobe_options.is_empty() {
+        match configured_module_options(module) {
+            Ok(configured) if !configured.is_empty() => {
+                let param_name = |option: &String| option.split('=').next().map(str::to_owned);
+                let overlapping: Vec<String> = config
+                    .modprobe_options
+                    .iter()
+                    .filter_map(param_name)
+                    .filter(|name| configured.iter().filter_map(param_name).any(|n| n == *name))
+                    .collect();
+                if overlapping.is_empty() {
+                    log!(
+                        "WARNING: Options for {} are set in both modprobe_options and {}. \
+                         modprobe will combine them.",
+                        module,
+                        MODPROBE_CONF_PATH
+                    );
+                } else {
+                    log!(
+                        "WARNING: {} set in both modprobe_options and {}. The modprobe_options \
+                         values will take precedence.",
+                        overlapping.join(", "),
+                        MODPROBE_CONF_PATH
+                    );
+                }
+            },
+            Ok(_) => {},
+            Err(e) => log!("ERROR: Could not read {}. ({})", MODPROBE_CONF_PATH, e),
+        }
+    }
+
+    let mut cmd = Command::new(&config.modprobe_path);
+    cmd.arg(module).args(&config.modprobe_options);
+    confirm_command(&cmd)?;
+    check_call!(cmd, UpdateNvidiaError::KernelModuleLoadFailed)?;
+    Ok(())
+}
+
+/// Read the parameters which `options` lines in [`MODPROBE_CONF_PATH`] set for `module`
+///
+/// As with `modprobe`, `-` and `_` are interchangeable in module names. A missing file just means
+/// there aren't any.
+fn configured_module_options(module: &str) -> io::Result<Vec<String>> {
+    let conf = match std::fs::read_to_string(MODPROBE_CONF_PATH) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        result => result?,
+    };
+    let normalize = |name: &str| name.replace('-', "_");
+    let module = normalize(module);
+    Ok(conf
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("options"), Some(name)) if normalize(name) == module => Some(words),
+                _ => None,
+            }
+        })
+        .flatten()
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Sort the loaded members of `candidates` so each one comes before any module it depends on
+///
+/// `modules` is as returned by [`list_loaded_modules`]. Anything left over because of a
+/// dependency cycle keeps its original order at the end rather than being dropped.
+fn unload_order(modules: &[LoadedModule], candidates: &[String]) -> Vec<String> {
+    let used_by: BTreeMap<&str, &[String]> =
+        modules.iter().map(|module| (module.name.as_str(), module.used_by.as_slice())).collect();
+    let mut remaining: Vec<&String> =
+        candidates.iter().filter(|name| used_by.contains_key(name.as_str())).collect();
+    let mut order = Vec::new();
+    while !remaining.is_empty() {
+        // A module is safe to unload once none of the remaining candidates are using it
+        let next = remaining
+            .iter()
+            .position(|name| !used_by[name.as_str()].iter().any(|user| remaining.contains(&user)));
+        match next {
+            Some(idx) => order.push(remaining.remove(idx).clone()),
+            None => order.extend(remaining.drain(..).cloned()),
+        }
+    }
+    order
+}
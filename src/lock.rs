@@ -0,0 +1,115 @@
+//! Protection against concurrent runs racing through the unhold/upgrade/re-hold cycle, and
+//! against `apt-get` failing because some other package manager (eg. `unattended-upgrades`)
+//! is busy
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::error::UpdateNvidiaError;
+use crate::log::log;
+use crate::procfs::lock_holders;
+
+/// Default path to the lock file used to prevent concurrent runs
+pub const LOCK_FILE_PATH: &str = "/var/run/update_nvidia.lock";
+
+/// The lock dpkg and every APT frontend take while they work on the package database
+const DPKG_FRONTEND_LOCK_PATH: &str = "/var/lib/dpkg/lock-frontend";
+
+/// How often [`wait_for_dpkg_lock`] checks whether the lock has been released
+const DPKG_LOCK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An RAII-based exclusive `flock(2)` on a lock file, released on drop
+pub struct LockFile {
+    /// The open lock file. (The lock lives as long as the file handle does.)
+    file: File,
+}
+
+impl LockFile {
+    /// Create (if necessary) and exclusively lock `path` without blocking
+    ///
+    /// On success, the current PID is written into the file so that a competing instance can
+    /// report who holds the lock.
+    pub fn acquire(path: &Path) -> Result<Self, UpdateNvidiaError> {
+        let mut file =
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        match file.try_lock() {
+            Ok(()) => {},
+            Err(TryLockError::WouldBlock) => {
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)?;
+                return Err(UpdateNvidiaError::LockHeld(contents.trim().parse().ok()));
+            },
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self { file })
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        // The lock file is deliberately not deleted, since removing a lock file that another
+        // process may have just opened is a classic source of races.
+        if let Err(e) = self.file.unlock() {
+            crate::log::log!("ERROR: Failed to release lock file: {}", e);
+        }
+    }
+}
+
+/// Wait up to `timeout` for [`DPKG_FRONTEND_LOCK_PATH`] to be free, so `apt-get` doesn't fail
+/// straight away with "Could not get lock" if something else is installing packages
+///
+/// If the lock can't be checked at all, `apt-get` is left to report the problem itself.
+pub fn wait_for_dpkg_lock(timeout: Duration) -> Result<(), UpdateNvidiaError> {
+    let path = Path::new(DPKG_FRONTEND_LOCK_PATH);
+    let started = Instant::now();
+    let mut waiting = false;
+    loop {
+        let holder = match dpkg_lock_holder(path) {
+            Ok(None) => return Ok(()),
+            Ok(Some(holder)) => holder,
+            Err(e) => {
+                log!("WARNING: Could not check whether {} is locked. ({})", path.display(), e);
+                return Ok(());
+            },
+        };
+        if started.elapsed() >= timeout {
+            return Err(UpdateNvidiaError::DpkgLockHeld { pid: holder, waited: timeout });
+        }
+        if !waiting {
+            match holder {
+                Some(pid) => log!("Waiting for dpkg lock... (held by PID {})", pid),
+                None => log!("Waiting for dpkg lock..."),
+            }
+            waiting = true;
+        }
+        std::thread::sleep(DPKG_LOCK_POLL_INTERVAL);
+    }
+}
+
+/// Check whether something holds `path` locked, returning `Some` with the holder's PID (if
+/// known) if so
+///
+/// dpkg and APT use `fcntl(2)` locks, which `flock(2)` can't see, so `/proc/locks` is checked
+/// too. A missing lock file means dpkg isn't installed, let alone running.
+fn dpkg_lock_holder(path: &Path) -> io::Result<Option<Option<u32>>> {
+    let file = match OpenOptions::new().write(true).open(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        result => result?,
+    };
+    match file.try_lock() {
+        // Released straight away, so it isn't mistaken for someone else's in `/proc/locks`
+        Ok(()) => file.unlock()?,
+        Err(TryLockError::WouldBlock) => return Ok(Some(None)),
+        Err(TryLockError::Error(e)) => return Err(e),
+    }
+    let metadata = file.metadata()?;
+    let holders = lock_holders(metadata.dev(), metadata.ino())?;
+    Ok(holders.into_iter().find(|&pid| pid != Some(std::process::id())))
+}
@@ -0,0 +1,289 @@
+//! Status messages which go to stderr (or syslog) and, optionally, a timestamped log file
+//! (`--log-file`)
+
+use std::ffi::{c_char, c_int, CString};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+use std::time::SystemTime;
+
+use crate::json::Json;
+
+/// The process-wide logger used by [`log!`]
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    file: None,
+    format: LogFormat::Text,
+    pretend: false,
+    last_message: String::new(),
+});
+
+/// What every message is prefixed with in `--pretend-version` mode
+const PRETEND_PREFIX: &str = "[PRETEND MODE] ";
+
+/// How messages are written by [`log!`] (`--output-format`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Plain lines on stderr
+    Text,
+    /// One JSON object per message on stderr
+    Json,
+    /// `syslog(3)`, which ends up in the journal on systemd-based systems
+    Syslog,
+}
+
+impl LogFormat {
+    /// The values accepted by `FromStr`
+    pub const NAMES: &'static [&'static str] = &["text", "json", "syslog"];
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            "syslog" => Ok(LogFormat::Syslog),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Severity of a message, as indicated by an `ERROR: ` or `WARNING: ` prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Level {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Level {
+    /// Split the severity prefix (if any) off of a message
+    fn parse(message: &str) -> (Self, &str) {
+        if let Some(rest) = message.strip_prefix("ERROR: ") {
+            (Level::Error, rest)
+        } else if let Some(rest) = message.strip_prefix("WARNING: ") {
+            (Level::Warning, rest)
+        } else {
+            (Level::Info, message)
+        }
+    }
+
+    /// The level's name in `--output-format json` output
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warning => "warning",
+            Level::Error => "error",
+        }
+    }
+
+    /// The matching `LOG_*` priority from `syslog.h`
+    fn syslog_priority(self) -> c_int {
+        match self {
+            Level::Info => 6,
+            Level::Warning => 4,
+            Level::Error => 3,
+        }
+    }
+}
+
+/// Destination for status messages
+pub struct Logger {
+    /// Log file that messages are teed into, if `--log-file` was given and could be opened
+    file: Option<BufWriter<File>>,
+    /// How messages which aren't going into the log file are written
+    format: LogFormat,
+    /// Whether to prefix every message with [`PRETEND_PREFIX`], so faked versions can't be
+    /// mistaken for real ones
+    pretend: bool,
+    /// The most recent message passed to [`log!`], for the `SIGUSR1` status dump
+    last_message: String,
+}
+
+impl Logger {
+    /// Print a message and append it, with a timestamp, to the log file if any
+    fn log(&mut self, message: &str) {
+        self.emit(message);
+        self.write_to_file(message);
+        message.clone_into(&mut self.last_message);
+    }
+
+    /// Print a message to stderr or syslog, as selected by `--output-format`
+    fn emit(&self, message: &str) {
+        // After the severity prefix, so it's still recognized
+        let prefix = self.prefix();
+        match self.format {
+            LogFormat::Text => eprintln!("{}{}", prefix, message),
+            LogFormat::Json => {
+                let (level, msg) = Level::parse(message);
+                let entry = Json::object([
+                    ("level", level.as_str().into()),
+                    ("ts", format_rfc3339(SystemTime::now()).as_str().into()),
+                    ("msg", format!("{}{}", prefix, msg).as_str().into()),
+                ]);
+                eprintln!("{}", entry);
+            },
+            LogFormat::Syslog => {
+                let (level, msg) = Level::parse(message);
+                send_to_syslog(level, &format!("{}{}", prefix, msg));
+            },
+        }
+    }
+
+    /// [`PRETEND_PREFIX`] in `--pretend-version` mode, otherwise nothing
+    fn prefix(&self) -> &'static str {
+        if self.pretend {
+            PRETEND_PREFIX
+        } else {
+            ""
+        }
+    }
+
+    /// Append a timestamped line to the log file (if any) without printing it to stderr
+    fn write_to_file(&mut self, message: &str) {
+        let prefix = self.prefix();
+        if let Some(file) = &mut self.file {
+            let timestamp = format_rfc3339(SystemTime::now());
+            // Flush every line so nothing is lost if the run ends in a reboot
+            let result = message
+                .lines()
+                .try_for_each(|line| writeln!(file, "{} {}{}", timestamp, prefix, line))
+                .and_then(|()| file.flush());
+            if let Err(e) = result {
+                self.emit(&format!("ERROR: Could not write to log file. Disabling it. ({})", e));
+                self.file = None;
+            }
+        }
+    }
+}
+
+/// Run a closure with exclusive access to the process-wide logger
+fn with_logger<T>(f: impl FnOnce(&mut Logger) -> T) -> T {
+    // A panic mid-message can't leave the logger in an invalid state, so ignore poisoning
+    f(&mut LOGGER.lock().unwrap_or_else(PoisonError::into_inner))
+}
+
+/// Start teeing messages into `path` (opened in append mode) and write a start-of-run sentinel
+///
+/// If the file can't be opened, the error is reported on stderr and logging continues without it,
+/// since a missing log shouldn't prevent the upgrade from happening.
+pub fn open_log_file(path: &Path) {
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => with_logger(|logger| {
+            logger.file = Some(BufWriter::new(file));
+            logger.write_to_file(&format!(
+                "===== update_nvidia {} started (PID {}) =====",
+                env!("CARGO_PKG_VERSION"),
+                std::process::id()
+            ));
+        }),
+        Err(e) => with_logger(|logger| {
+            logger.emit(&format!("ERROR: Could not open log file {}: {}", path.display(), e));
+        }),
+    }
+}
+
+/// Write an end-of-run sentinel (if a log file is open) and close the log file
+///
+/// If the run failed, `error` is also recorded in the log file. (It isn't printed to stderr
+/// because returning it from `main` will do that.)
+pub fn close_log_file(error: Option<&dyn std::fmt::Display>) {
+    with_logger(|logger| {
+        if let Some(error) = error {
+            logger.write_to_file(&format!("ERROR: {}", error));
+        }
+        let outcome = if error.is_none() { "succeeded" } else { "failed" };
+        logger.write_to_file(&format!("===== update_nvidia run {} =====", outcome));
+        logger.file = None;
+    });
+}
+
+/// Select how messages are written, opening the connection to syslog if needed
+pub fn set_format(format: LogFormat) {
+    if format == LogFormat::Syslog {
+        extern "C" {
+            fn openlog(ident: *const c_char, option: c_int, facility: c_int);
+        }
+        /// `LOG_PID` from `syslog.h`: Include the PID in each message
+        const LOG_PID: c_int = 0x01;
+        /// `LOG_DAEMON` from `syslog.h`: The facility for system daemons
+        const LOG_DAEMON: c_int = 3 << 3;
+
+        // SAFETY: `openlog` keeps the `ident` pointer rather than copying it, which is fine
+        // because it points to a NUL-terminated `'static` literal.
+        unsafe { openlog(c"update_nvidia".as_ptr(), LOG_PID, LOG_DAEMON) };
+    }
+    with_logger(|logger| logger.format = format);
+}
+
+/// Prefix every message with `[PRETEND MODE]` (for `--pretend-version`)
+pub fn set_pretend_mode(pretend: bool) {
+    with_logger(|logger| logger.pretend = pretend);
+}
+
+/// Send a message to syslog at the given severity
+fn send_to_syslog(level: Level, message: &str) {
+    extern "C" {
+        fn syslog(priority: c_int, format: *const c_char, ...);
+    }
+    let message = CString::new(message.replace('\0', " ")).expect("NULs were just replaced");
+    // SAFETY: The format string is a NUL-terminated literal consuming exactly one `%s`
+    // argument, which is a NUL-terminated string that outlives the call.
+    unsafe { syslog(level.syslog_priority(), c"%s".as_ptr(), message.as_ptr()) };
+}
+
+/// Implementation detail of [`log!`]
+pub fn log_message(message: &str) {
+    with_logger(|logger| logger.log(message));
+}
+
+/// Print a message in the selected format without teeing it into the log file
+///
+/// For errors which [`close_log_file`] has already recorded there.
+pub fn print_message(message: &str) {
+    with_logger(|logger| logger.emit(message));
+}
+
+/// The most recent message passed to [`log!`], or an empty string if there hasn't been one
+pub fn last_message() -> String {
+    with_logger(|logger| logger.last_message.clone())
+}
+
+/// Format a timestamp as an RFC 3339 UTC date-time (eg. `2024-01-01T00:00:00Z`)
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let secs = time.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Convert days since the epoch to a proleptic Gregorian date.
+    // Source: http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Drop-in replacement for `eprintln!` which also tees into the `--log-file` if one is open
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::log::log_message(&format!($($arg)*))
+    };
+}
+pub(crate) use log;
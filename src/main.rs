@@ -6,244 +6,1762 @@
 //!
 //! - `apt-get`: [`APT_GET_PATH`]
 //! - `apt-mark`: [`APT_MARK_PATH`]
+//! - `apt-cache`: [`APT_CACHE_PATH`] (only for `--max-version`)
+//! - `add-apt-repository`: [`ADD_APT_REPOSITORY_PATH`] (only for `--ppa`)
+//! - `gpg`: [`GPG_PATH`] (only for `--apt-key`)
+//! - `ubuntu-drivers`: [`UBUNTU_DRIVERS_PATH`] (optional, for `--ubuntu-drivers`)
 //! - `dpkg-query`: [`DPKG_QUERY_PATH`]
+//! - `dkms`: [`DKMS_PATH`] (only if the driver is built by DKMS)
 //! - `modprobe`: [`MODPROBE_PATH`] (or `reboot` at [`REBOOT_PATH`])
 //! - `rmmod`: [`RMMOD_PATH`] (or `reboot` at [`REBOOT_PATH`])
+//! - `shutdown`: [`SHUTDOWN_PATH`] (instead of `reboot` if a reboot delay is configured)
+//! - `notify-send`: [`NOTIFY_SEND_PATH`] (only if `notify_send` is enabled)
+//! - `mokutil`: [`MOKUTIL_PATH`] (only if Secure Boot's state can't be read from `efivarfs`)
+//! - `sendmail`: [`SENDMAIL_PATH`] (only for `--email`)
+//! - `nvidia-smi`: [`NVIDIA_SMI_PATH`] (optional, for `report`)
+//! - `systemctl`: [`SYSTEMCTL_PATH`] (only for `--schedule` and `--unschedule`)
+//! - `systemd-inhibit`: [`SYSTEMD_INHIBIT_PATH`] (optional, to block shutdown mid-upgrade)
+//!
+//! On Fedora/RHEL systems, `dnf` at [`DNF_PATH`] (with the `versionlock` plugin) and `rpm` at
+//! [`RPM_PATH`] replace `apt-get`, `apt-mark`, and `dpkg-query`. On openSUSE/SLES systems,
+//! `zypper` at [`ZYPPER_PATH`] and `rpm` replace them. On Arch Linux, `pacman` at
+//! [`PACMAN_PATH`] replaces all of them.
 
-use std::collections::BTreeMap; // So user-visible output is sorted
+use std::cell::RefCell;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BTreeMap, BTreeSet}; // So user-visible output is sorted
 use std::error::Error;
+use std::io::IsTerminal;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+mod apt_key;
+mod cli;
+mod cmd;
+mod config;
+mod disk;
+mod dkms;
+mod email;
+mod error;
+mod exit_code;
+mod history;
+mod hooks;
+mod inhibit;
+mod json;
+mod kmod;
+mod lock;
+mod log;
+#[cfg(test)]
+mod mock;
+mod network;
+mod notify;
+mod output;
+mod packages;
+mod power;
+mod ppa;
+mod pretend;
+mod process;
+mod procfs;
+mod progress;
+mod prompt;
+mod schedule;
+mod secure_boot;
+mod settings;
+mod signal;
+mod state;
+mod systemd;
+mod timing;
+mod virt;
 
-/// Path to use for invoking the `apt-get` Command
+use config::{Config, DEFAULT_CONFIG_PATH};
+use error::{CalledProcessError, UpdateNvidiaError};
+use inhibit::SystemdInhibitLock;
+use json::Json;
+use kmod::ModuleUnloadError;
+use lock::LockFile;
+use log::log;
+use output::{format_version_diff, log_event, Action, OutputSink, RunSummary};
+use packages::{
+    dpkg_version_cmp, exceeds_max_version, is_eligible, upstream_version, version_maps,
+    AutoMarkPolicy, PackageManager, PackageManagerKind,
+};
+use process::{check_call, retry_with_backoff, DRY_RUN, VERBOSE};
+use procfs::{detect_display_server_using_nvidia, find_nvidia_users, is_module_loaded, NvidiaUser};
+use prompt::{confirm_command, INTERACTIVE};
+use settings::SettingsBackup;
+use state::State;
+use timing::Phase;
+
+/// Default path to use for invoking the `apt-get` Command
 ///
 /// (Hard-coded to an absolute path for security-reasons)
 const APT_GET_PATH: &str = "/usr/bin/apt-get";
 
-/// Path to use for invoking the `apt-mark` Command
+/// Default path to use for invoking the `apt-cache` Command
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const APT_CACHE_PATH: &str = "/usr/bin/apt-cache";
+
+/// Default path to use for invoking the `apt-mark` Command
 ///
 /// (Hard-coded to an absolute path for security-reasons)
 const APT_MARK_PATH: &str = "/usr/bin/apt-mark";
 
-/// Path to the file that should have its `mtime` used as a sign of when `apt-get update` last ran
+/// Default path to use for invoking the `add-apt-repository` Command (`--ppa`)
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const ADD_APT_REPOSITORY_PATH: &str = "/usr/bin/add-apt-repository";
+
+/// Default path to use for invoking the `gpg` Command to inspect and dearmor keys (`--apt-key`)
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const GPG_PATH: &str = "/usr/bin/gpg";
+
+/// Default path to the file whose `mtime` is used as a sign of when `apt-get update` last ran
 const APT_UPDATE_MTIME_PATH: &str = "/var/cache/apt/pkgcache.bin";
 
-/// Threshold beyond which we should consider the package cache stale and run `apt-get update`
+/// Default threshold beyond which the package cache should be considered stale and updated
 const APT_UPDATE_INTERVAL: u64 = 3600u64.saturating_mul(48); // 48 hours
 
-/// Path to use for invoking the `dpkg-query` Command
+/// Default path to use for invoking the `dpkg-query` Command
 ///
 /// (Hard-coded to an absolute path for security-reasons)
 const DPKG_QUERY_PATH: &str = "/usr/bin/dpkg-query";
 
-/// Path to use for invoking the `reboot` Command
+/// Default path to use for invoking the `dkms` Command on systems using `nvidia-dkms`
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const DKMS_PATH: &str = "/usr/sbin/dkms";
+
+/// Default path to use for invoking the `notify-send` Command for desktop notifications
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const NOTIFY_SEND_PATH: &str = "/usr/bin/notify-send";
+
+/// Default path to use for invoking the `mokutil` Command to check whether Secure Boot is enabled
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const MOKUTIL_PATH: &str = "/usr/bin/mokutil";
+
+/// Default path to use for invoking the `nvidia-smi` Command for the `report` subcommand
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const NVIDIA_SMI_PATH: &str = "/usr/bin/nvidia-smi";
+
+/// Default path to use for invoking the `sendmail` Command to email reports (`--email`)
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const SENDMAIL_PATH: &str = "/usr/sbin/sendmail";
+
+/// Default path to use for invoking the `systemctl` Command to install units (`--schedule`)
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const SYSTEMCTL_PATH: &str = "/usr/bin/systemctl";
+
+/// Default path to use for invoking the `systemd-inhibit` Command during the upgrade
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const SYSTEMD_INHIBIT_PATH: &str = "/usr/bin/systemd-inhibit";
+
+/// Default path to use for invoking the `ubuntu-drivers` Command (`use_ubuntu_drivers`)
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const UBUNTU_DRIVERS_PATH: &str = "/usr/bin/ubuntu-drivers";
+
+/// Default path to use for invoking the `dnf` Command
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const DNF_PATH: &str = "/usr/bin/dnf";
+
+/// Default path to use for invoking the `zypper` Command
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const ZYPPER_PATH: &str = "/usr/bin/zypper";
+
+/// Default path to use for invoking the `pacman` Command
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const PACMAN_PATH: &str = "/usr/bin/pacman";
+
+/// Default path to use for invoking the `rpm` Command
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const RPM_PATH: &str = "/usr/bin/rpm";
+
+/// Default path to use for invoking the `reboot` Command
 ///
 /// (Hard-coded to an absolute path for security-reasons)
 const REBOOT_PATH: &str = "/sbin/reboot";
 
-/// Path to use for invoking the `rmmod` Command
+/// Default path to use for invoking the `shutdown` Command when a reboot delay is requested
+///
+/// (Hard-coded to an absolute path for security-reasons)
+const SHUTDOWN_PATH: &str = "/sbin/shutdown";
+
+/// Default path to use for invoking the `rmmod` Command
 ///
 /// (Hard-coded to an absolute path for security-reasons)
 const RMMOD_PATH: &str = "/sbin/rmmod";
 
-/// Path to use for invoking the `modprobe` Command
+/// Default path to use for invoking the `modprobe` Command
 ///
 /// (Hard-coded to an absolute path for security-reasons)
 const MODPROBE_PATH: &str = "/sbin/modprobe";
 
-/// Single definition of the kernel module name to load and unload
+/// Default single definition of the kernel module name to load and unload
 const NVIDIA_KMOD_NAME: &str = "nvidia";
 
-/// Workaround for `ExitStatusError` being unstable
-#[derive(Debug)]
-struct CalledProcessError {
-    /// The subprocess's exit code (or `None` if killed by a POSIX signal)
-    pub code: Option<i32>,
-}
+/// Message `shutdown` broadcasts to logged-in users when a reboot delay is configured
+const REBOOT_MESSAGE: &str = "NVIDIA driver update requires reboot";
 
-impl std::fmt::Display for CalledProcessError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Process returned non-success exit code {:?}", self.code)
-    }
-}
-impl Error for CalledProcessError {}
+/// File written in place of rebooting with `--simulate-reboot`, so a test can tell it happened
+const REBOOT_SIMULATED_MARKER: &str = "/tmp/update_nvidia_reboot_simulated";
 
-/// Helper to deduplicate the boilerplate of handling errors with `Command`
-///
-/// Named after the Python `subprocess` function it mimics.
-#[rustfmt::skip] // rustfmt bug causes inside of closure to migrate right on every save
-macro_rules! check_call {
-    ($cmd:expr) => {
-        (|| {
-            let status = $cmd.status()?;
-            if !status.success() {
-                // TODO: Nicer output
-                return Err(CalledProcessError { code: status.code() }.into());
-            }
-            Ok::<std::process::ExitStatus, Box<dyn Error>>(status)
-        })()
-    };
-}
+/// How long to wait for processes to exit after `--kill-users` sends them `SIGTERM`
+const KILL_USERS_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Packages `cuda_compat` holds and upgrades along with the driver (eg. `cuda-compat-12-2`)
+const CUDA_COMPAT_PATTERN: &str = "cuda-compat-*";
 
-/// An RAII-based mechanism for temporarily `apt-mark unhold`-ing packages
-struct UnholdGuard {
-    /// Names of packages to re-`apt-mark hold` on drop
+/// An RAII-based mechanism for temporarily un-holding packages
+struct UnholdGuard<'a> {
+    /// Backend to use for re-holding
+    package_manager: &'a dyn PackageManager,
+    /// Names of packages to re-hold on drop
     names: Vec<String>,
+    /// Names from `names` which weren't held before, so they're left un-held on drop
+    previously_unheld: Vec<String>,
 }
 
-impl UnholdGuard {
-    /// Construct a new guard and immediately un-hold the given packages
-    pub fn new(names: Vec<String>) -> Result<Self, Box<dyn Error>> {
-        eprintln!("Un-holding: {}", names.join(" "));
-        check_call!(Command::new(APT_MARK_PATH).arg("unhold").arg("-qq").args(&names))?;
-        Ok(Self { names })
+impl<'a> UnholdGuard<'a> {
+    /// Construct a new guard and immediately un-hold whichever of the given packages are held
+    ///
+    /// Packages which weren't held are assumed to have been left that way on purpose, so they
+    /// aren't held on drop either (unless [`UnholdGuard::hold_all`] says otherwise). Packages
+    /// added later with [`UnholdGuard::extend`] (eg. ones the upgrade newly installed) are.
+    pub fn new(
+        package_manager: &'a dyn PackageManager,
+        names: Vec<String>,
+    ) -> Result<Self, UpdateNvidiaError> {
+        let held = package_manager.get_held_packages(&names)?;
+        let previously_unheld: Vec<String> =
+            names.iter().filter(|name| !held.contains(name)).cloned().collect();
+        if held.is_empty() {
+            log!("None of the packages are held, so there's nothing to un-hold.");
+        } else {
+            log!("Un-holding: {}", held.join(" "));
+            package_manager.unhold_packages(&held)?;
+        }
+        if !previously_unheld.is_empty() {
+            log!("Leaving these un-held, as they were: {}", previously_unheld.join(" "));
+        }
+        Ok(Self { package_manager, names, previously_unheld })
     }
+
     /// Add more entries to the list of things to hold when the guard drops
     pub fn extend(&mut self, names: impl IntoIterator<Item = String>) {
         self.names.extend(names);
     }
+
+    /// Hold the packages which weren't held before too, for `--mark-only`
+    pub fn hold_all(&mut self) {
+        if !self.previously_unheld.is_empty() {
+            log!("Holding for the first time: {}", self.previously_unheld.join(" "));
+            self.previously_unheld.clear();
+        }
+    }
 }
 
-impl Drop for UnholdGuard {
+impl Drop for UnholdGuard<'_> {
+    /// Re-hold the packages, recording any failure for `main` rather than panicking
+    ///
+    /// This may run during unwinding, where a second panic would abort the process.
     fn drop(&mut self) {
-        eprintln!("Re-holding: {}", self.names.join(" "));
-        if !Command::new(APT_MARK_PATH)
-            .arg("hold")
-            .arg("-qq")
-            .args(&self.names)
-            .status()
-            .expect("run apt-mark again to re-hold packages")
-            .success()
-        {
-            panic!("Failed to re-mark packages as held: {}", self.names.join(" "));
+        let names: BTreeSet<&String> =
+            self.names.iter().filter(|name| !self.previously_unheld.contains(name)).collect();
+        if names.is_empty() {
+            return;
+        }
+        let names: Vec<String> = names.into_iter().cloned().collect();
+        if let Err(e) = cleanup_hold_packages(self.package_manager, &names) {
+            log!("ERROR: Failed to re-mark packages as held: {} ({})", names.join(" "), e);
+            UNHELD_PACKAGES.with(|unheld| unheld.borrow_mut().extend(names));
+            REHOLD_FAILED.store(true, Ordering::Relaxed);
         }
     }
 }
 
-/// Retrieve a map from installed packages with `nvidia` in the name to their version strings
-fn get_nvidia_packages() -> Result<BTreeMap<String, String>, Box<dyn Error>> {
-    // Use the fastest of the choices I found. No need to gratuitously extend boot times
-    let cmd_result = Command::new(DPKG_QUERY_PATH).arg("--list").arg("*nvidia*").output()?;
+thread_local! {
+    /// Packages an [`UnholdGuard`] failed to re-hold, so `main` can tell the user what to fix
+    static UNHELD_PACKAGES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Set when an [`UnholdGuard`] fails to re-hold its packages, so `main` can exit non-zero even
+/// if the rest of the run succeeded
+static REHOLD_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Whether `args` asks for something which changes the system, and so has to run as root
+///
+/// `status`, `report`, `--show-history`, `--simulate`, `--check-only`, and `--list-available`
+/// (without `--force-update`) only look, as does anything under `--dry-run` or
+/// `--pretend-version`.
+fn changes_system(args: &cli::Args) -> bool {
+    let read_only = args.subcommand.is_some()
+        || args.show_history
+        || args.simulate
+        || args.check_only
+        || (args.list_available && !args.force_update);
+    !(read_only || args.dry_run || !args.pretend_versions.0.is_empty())
+}
 
-    if !cmd_result.status.success() {
-        return Err(CalledProcessError { code: cmd_result.status.code() }.into());
+/// Fail early with a clear message, rather than on the first `apt-mark` or `apt-get` call
+///
+/// This also keeps packages from being un-held by a process which couldn't hold them again.
+/// If `/proc/self/status` can't be read, the commands are left to report it themselves.
+fn check_running_as_root() -> Result<(), UpdateNvidiaError> {
+    match procfs::effective_uid() {
+        Ok(0) => Ok(()),
+        Ok(_) => Err(UpdateNvidiaError::NotRunningAsRoot),
+        Err(e) => {
+            log!("WARNING: Could not determine whether running as root. ({})", e);
+            Ok(())
+        },
     }
+}
 
-    let mut results = BTreeMap::new();
-    for line in String::from_utf8(cmd_result.stdout)?.split('\n') {
-        let mut fields = line.split_whitespace();
-        if !matches!(fields.next(), Some("ii" | "hi")) {
-            continue;
-        }
-        if let (Some(pkgname), Some(pkgver)) = (fields.next(), fields.next()) {
-            results.insert(pkgname.to_owned(), pkgver.to_owned());
+/// Put the given packages back on hold
+fn cleanup_hold_packages(
+    package_manager: &dyn PackageManager,
+    names: &[String],
+) -> Result<(), UpdateNvidiaError> {
+    log!("Re-holding: {}", names.join(" "));
+    package_manager.hold_packages(names)
+}
+
+/// The eligible packages and their versions from before and after an upgrade
+struct UpgradeOutcome {
+    old_versions: BTreeMap<String, String>,
+    new_versions: BTreeMap<String, String>,
+    /// Copies of the GPU settings files from before the upgrade, if any were made
+    settings_backup: Option<SettingsBackup>,
+    /// Packages which weren't held before the run, and so were left un-held after it
+    left_unheld: Vec<String>,
+}
+
+impl UpgradeOutcome {
+    /// Whether something was updated and a kernel module reload may be necessary
+    ///
+    /// Versions are compared the way `dpkg` does, so a merely cosmetic change in the version
+    /// string (eg. an explicit `0:` epoch) isn't mistaken for an upgrade. Downgrades still count,
+    /// since the module on disk no longer matches the one that's loaded.
+    fn upgraded(&self) -> bool {
+        let mut upgraded = false;
+        for (name, new_version) in &self.new_versions {
+            let Some(old_version) = self.old_versions.get(name) else {
+                // Newly installed (eg. pulled in by a metapackage switching driver series)
+                upgraded = true;
+                continue;
+            };
+            match dpkg_version_cmp(new_version, old_version) {
+                CmpOrdering::Greater => upgraded = true,
+                CmpOrdering::Less => {
+                    log!(
+                        "WARNING: {} was downgraded from {} to {}",
+                        name,
+                        old_version,
+                        new_version
+                    );
+                    upgraded = true;
+                },
+                CmpOrdering::Equal => {},
+            }
         }
+        upgraded
     }
-    Ok(results)
 }
 
-/// Run `apt-get update` if the package index is stale
-fn update_package_index() -> Result<(), Box<dyn Error>> {
-    // Retrieve the mtime of APT_UPDATE_MTIME_PATH.
-    // If we can't for some reason, report the failure and assume maximum staleness.
-    let stat = std::fs::metadata(APT_UPDATE_MTIME_PATH);
-    if let Err(e) = &stat {
-        eprintln!("ERROR: Could not stat {}. ({:?})", APT_UPDATE_MTIME_PATH, e);
+/// Have `ubuntu-drivers autoinstall` install the driver recommended for the hardware
+/// (`use_ubuntu_drivers`), so it takes part in the hold/unhold cycle from this run on
+///
+/// `versions` is re-read afterward, and the packages it newly installed are returned. It's only
+/// a convenience, so a missing `ubuntu-drivers` (eg. on Debian) is just a warning. `run` has
+/// already refused it for anything but apt.
+fn install_recommended_driver(
+    config: &Config,
+    package_manager: &dyn PackageManager,
+    versions: &mut BTreeMap<String, String>,
+) -> Result<Vec<String>, UpdateNvidiaError> {
+    let path = &config.ubuntu_drivers_path;
+    if !path.exists() {
+        log!("WARNING: {} not found. Continuing without it.", path.display());
+        return Ok(Vec::new());
     }
-    let last_update = stat.and_then(|stat| stat.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+    log!("Installing the recommended driver with ubuntu-drivers autoinstall...");
+    let mut cmd = Command::new(path);
+    cmd.arg("autoinstall");
+    confirm_command(&cmd)?;
+    check_call!(cmd, UpdateNvidiaError::UbuntuDriversFailed)?;
 
-    if SystemTime::now().duration_since(last_update)?.as_secs() > APT_UPDATE_INTERVAL {
-        eprintln!("Package index is stale. Updating...");
-        check_call!(Command::new(APT_GET_PATH).arg("update"))?;
-    } else {
-        eprintln!("Package index is sufficiently fresh.");
-    }
-    Ok(())
+    let before = std::mem::replace(versions, package_manager.get_nvidia_packages()?);
+    Ok(versions.keys().filter(|name| !before.contains_key(*name)).cloned().collect())
 }
 
 /// Un-pin nVidia packages, update them, and re-pin them
 ///
 /// If `mark_only` is `true`, then don't actually update anything and just refresh the package pins
-///
-/// The return value indicates whether something was updated and a kernel module reload may be
-/// necessary.
-fn do_upgrade(mark_only: bool) -> Result<bool, Box<dyn Error>> {
+fn do_upgrade(
+    config: &Config,
+    package_manager: &dyn PackageManager,
+    mark_only: bool,
+    max_version: Option<&str>,
+) -> Result<UpgradeOutcome, UpdateNvidiaError> {
     if !mark_only {
+        // Bail out before touching any packages, rather than letting apt-get fail halfway through
+        disk::check_disk_space(package_manager.download_dir(), config.min_free_bytes)?;
+
         // Update the package index to ensure we don't wind up upgrading to something that's
         // already stale too
-        update_package_index()?;
+        timing::time(Phase::IndexUpdate, || refresh_index(config, package_manager))?;
+    }
+
+    log!("Getting list of eligible packages");
+    let mut old_versions = package_manager.get_nvidia_packages()?;
+    // Installing a first driver is what `ubuntu-drivers autoinstall` is mostly for
+    let mut driver_installed = None;
+    if old_versions.is_empty() && !mark_only && config.use_ubuntu_drivers {
+        confirm_changes(config, "No NVIDIA packages are installed yet. This will run:", || {
+            Ok(vec![format!("{} autoinstall", config.ubuntu_drivers_path.display())])
+        })?;
+        driver_installed =
+            Some(install_recommended_driver(config, package_manager, &mut old_versions)?);
+    }
+    if old_versions.is_empty() {
+        // Don't run a bare `apt-mark unhold` or let `dist-upgrade` upgrade unrelated packages
+        log!("No NVIDIA packages found; nothing to do.");
+        return Ok(UpgradeOutcome {
+            old_versions: BTreeMap::new(),
+            new_versions: BTreeMap::new(),
+            settings_backup: None,
+            left_unheld: Vec::new(),
+        });
+    }
+    if DRY_RUN.load(Ordering::Relaxed) {
+        log!("[DRY RUN] The following packages would be eligible for upgrade:");
+        for (name, version) in &old_versions {
+            log!("[DRY RUN]     {} {}", name, version);
+        }
+        if !mark_only {
+            log_simulated_changes(config, package_manager);
+        }
     }
 
-    eprintln!("Getting list of eligible packages");
-    let old_versions = get_nvidia_packages()?;
+    if let (false, Some(max)) = (mark_only, max_version) {
+        check_max_version(package_manager, &old_versions, max)?;
+    }
+    if !mark_only {
+        check_new_packages(config, package_manager)?;
+        confirm_upgrade(config, package_manager)?;
+        if config.kernel_check {
+            dkms::check_kernel_headers(config, package_manager);
+        }
+        secure_boot::check_secure_boot(config, &old_versions)?;
+    }
 
-    let mut unhold_guard = UnholdGuard::new(old_versions.keys().cloned().collect())?;
+    if let (false, Some(hook)) = (mark_only, &config.pre_upgrade_hook) {
+        hooks::run_hook(
+            hook,
+            &[
+                ("UPDATE_NVIDIA_ACTION", "pre_upgrade"),
+                ("UPDATE_NVIDIA_OLD_VERSIONS", &Json::from(&old_versions).to_string()),
+            ],
+        )?;
+    }
+
+    // Only now that the upgrade has been confirmed and checked against --max-version
+    let installed_names = match driver_installed {
+        Some(names) => names,
+        None if !mark_only && config.use_ubuntu_drivers => {
+            install_recommended_driver(config, package_manager, &mut old_versions)?
+        },
+        None => Vec::new(),
+    };
+    // Held alongside the NVIDIA packages, but never a reason to reload the module
+    let extra_names = get_extra_hold_packages(config, package_manager, &old_versions)?;
+    let mut unhold_guard = timing::time(Phase::Unhold, || {
+        UnholdGuard::new(
+            package_manager,
+            old_versions
+                .keys()
+                .filter(|name| !installed_names.contains(name))
+                .chain(&extra_names)
+                .cloned()
+                .collect(),
+        )
+    })?;
+    // Whatever ubuntu-drivers installed is new, so it's held from now on like anything else
+    unhold_guard.extend(installed_names);
     if mark_only {
+        // Refreshing the holds is the whole point, so the un-held packages get held too
+        unhold_guard.hold_all();
         // Just go straight to dropping the guard
-        return Ok(false);
+        return Ok(UpgradeOutcome {
+            new_versions: old_versions.clone(),
+            old_versions,
+            settings_backup: None,
+            left_unheld: Vec::new(),
+        });
     }
 
-    // Not the best solution, but quick and generally works
-    eprintln!("Applying plending package upgrades...");
-    check_call!(Command::new(APT_GET_PATH).arg("dist-upgrade").arg("-y"))?;
+    // A missing backup is no reason to keep running a mismatched driver, so keep going
+    let settings_backup = settings::backup_nvidia_settings(&config.backup_settings_dir)
+        .unwrap_or_else(|e| {
+            log!("ERROR: Could not back up GPU settings. Continuing without a backup. ({})", e);
+            None
+        });
+
+    // The upgrade can take a while, so don't let systemd's start timeout cut it short
+    systemd::notify("READY=1\nSTATUS=Upgrading packages...");
+    log!("Applying plending package upgrades...");
+    timing::time(Phase::Upgrade, || upgrade_with_recovery(config, package_manager))?;
 
     // Update the list of packages to re-hold and report whether a kernel module reload is needed
-    eprintln!("Getting updated list of eligible packages");
-    let new_versions = get_nvidia_packages()?;
+    log!("Getting updated list of eligible packages");
+    let new_versions = package_manager.get_nvidia_packages()?;
     unhold_guard.extend(new_versions.keys().cloned());
-    Ok(old_versions != new_versions)
+    match get_extra_hold_packages(config, package_manager, &new_versions) {
+        Ok(names) => unhold_guard.extend(names),
+        // The ones from before the upgrade will still be re-held
+        Err(e) => log!("ERROR: Could not list the packages matching hold_patterns: {}", e),
+    }
+
+    // Only once the upgrade has succeeded, since a failed one may need the packages to recover
+    if config.clean_apt_cache {
+        log!("Deleting the downloaded packages...");
+        if let Err(e) = package_manager.clean_download_cache() {
+            log!("ERROR: Could not delete the downloaded packages: {}", e);
+        }
+    }
+
+    // The upgrade already happened, so this isn't worth failing the run over
+    let names: Vec<String> = new_versions.keys().cloned().collect();
+    if let Err(e) = apply_auto_mark_policy(config, package_manager, &names) {
+        log!("ERROR: Could not update the automatically installed marks: {}", e);
+    }
+    let left_unheld = unhold_guard.previously_unheld.clone();
+    Ok(UpgradeOutcome { old_versions, new_versions, settings_backup, left_unheld })
+}
+
+/// Apply the pending upgrades and, if that fails, try repairing any broken packages with
+/// [`PackageManager::fix_broken_packages`] and upgrading once more
+///
+/// If the repair or the second attempt fails too, the original error is returned, since that's
+/// the one which says what actually went wrong.
+fn upgrade_with_recovery(
+    config: &Config,
+    package_manager: &dyn PackageManager,
+) -> Result<(), UpdateNvidiaError> {
+    let Err(e) = retry_with_backoff(config.retry_attempts, config.retry_delay, || {
+        package_manager.upgrade_packages()
+    }) else {
+        return Ok(());
+    };
+
+    log!("ERROR: The upgrade failed ({}). Trying to repair any broken packages...", e);
+    match package_manager.fix_broken_packages() {
+        Ok(true) => log!("Repaired the broken packages. Retrying the upgrade..."),
+        Ok(false) => {
+            log!("Nothing needed repairing, so the upgrade won't be retried.");
+            return Err(e);
+        },
+        Err(fix_error) => {
+            log!("ERROR: Could not repair the broken packages: {}", fix_error);
+            return Err(e);
+        },
+    }
+    package_manager.upgrade_packages().map_err(|retry_error| {
+        log!("ERROR: The retried upgrade failed too: {}", retry_error);
+        e
+    })
+}
+
+/// Names of the installed packages matching `hold_patterns` (or [`CUDA_COMPAT_PATTERN`] with
+/// `cuda_compat`) which aren't already in `versions`
+fn get_extra_hold_packages(
+    config: &Config,
+    package_manager: &dyn PackageManager,
+    versions: &BTreeMap<String, String>,
+) -> Result<Vec<String>, UpdateNvidiaError> {
+    let mut names = BTreeSet::new();
+    let cuda_compat = config.cuda_compat.then_some(CUDA_COMPAT_PATTERN);
+    for pattern in config.hold_patterns.iter().map(String::as_str).chain(cuda_compat) {
+        names.extend(package_manager.get_packages_matching(pattern)?.into_keys());
+    }
+    Ok(names.into_iter().filter(|name| !versions.contains_key(name)).collect())
+}
+
+/// Mark `names` as manually or automatically installed, as `auto_mark_policy` calls for
+///
+/// Only packages whose mark doesn't already match are changed.
+fn apply_auto_mark_policy(
+    config: &Config,
+    package_manager: &dyn PackageManager,
+    names: &[String],
+) -> Result<(), UpdateNvidiaError> {
+    let auto = match config.auto_mark_policy {
+        AutoMarkPolicy::DoNothing => return Ok(()),
+        AutoMarkPolicy::MarkManual => false,
+        AutoMarkPolicy::MarkAuto => true,
+    };
+    let marked_auto = package_manager.get_auto_installed_nvidia_packages()?;
+    let to_mark: Vec<String> =
+        names.iter().filter(|name| marked_auto.contains(*name) != auto).cloned().collect();
+    if to_mark.is_empty() {
+        return Ok(());
+    }
+    let how = if auto { "automatically" } else { "manually" };
+    log!("Marking as {} installed: {}", how, to_mark.join(" "));
+    package_manager.mark_auto_installed(&to_mark, auto)
+}
+
+/// For `--dry-run`, report which eligible packages the package manager says an upgrade would
+/// change
+fn log_simulated_changes(config: &Config, package_manager: &dyn PackageManager) {
+    match package_manager.simulate_upgrade() {
+        Ok(changes) => {
+            let (old, new) =
+                version_maps(changes.iter().filter(|change| is_eligible(config, &change.name)));
+            if new.is_empty() {
+                log!("[DRY RUN] The package manager reports no NVIDIA package changes.");
+                return;
+            }
+            log!("[DRY RUN] The upgrade would make these changes:");
+            for line in format_version_diff(&old, &new).lines() {
+                log!("[DRY RUN]     {}", line);
+            }
+        },
+        Err(e) => log!("ERROR: Could not simulate the upgrade: {}", e),
+    }
+}
+
+/// Update the package index (if stale), unless the network doesn't come up in time
+///
+/// An index that's a little out of date is better than no upgrade, so a network that's still
+/// down after `network_timeout` only produces a warning.
+fn refresh_index(
+    config: &Config,
+    package_manager: &dyn PackageManager,
+) -> Result<(), UpdateNvidiaError> {
+    if config.skip_update {
+        log!("Skipping the package index update (--skip-update).");
+        return Ok(());
+    }
+    systemd::status("Updating the package index...");
+    if let Err(e) = network::wait_for_network(&config.network_check_hosts, config.network_timeout) {
+        log!("WARNING: Skipping the package index update because the network is down. ({})", e);
+        return Ok(());
+    }
+    retry_with_backoff(config.retry_attempts, config.retry_delay, || package_manager.update_index())
+}
+
+/// How many times `--hold-new-packages` retries holding packages that didn't stick
+const HOLD_VERIFY_ATTEMPTS: u32 = 3;
+
+/// Confirm that every package in `names` is now held, re-holding any that aren't
+///
+/// A package the upgrade newly installed has never been held before, so this catches cases where
+/// `apt-mark hold` reported success but didn't take effect.
+fn verify_holds(
+    package_manager: &dyn PackageManager,
+    names: &[String],
+) -> Result<(), UpdateNvidiaError> {
+    let find_missing = || -> Result<Vec<String>, UpdateNvidiaError> {
+        let held = package_manager.get_held_nvidia_packages()?;
+        Ok(names.iter().filter(|name| !held.contains(*name)).cloned().collect())
+    };
+    let mut missing = find_missing()?;
+    for attempt in 1..=HOLD_VERIFY_ATTEMPTS {
+        if missing.is_empty() {
+            return Ok(());
+        }
+        log!(
+            "ERROR: Not held after the upgrade: {}. Holding again (attempt {} of {})...",
+            missing.join(" "),
+            attempt,
+            HOLD_VERIFY_ATTEMPTS
+        );
+        if let Err(e) = package_manager.hold_packages(&missing) {
+            log!("ERROR: {}", e);
+        }
+        missing = find_missing()?;
+    }
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(UpdateNvidiaError::HoldsMissing(missing))
+    }
+}
+
+/// Fail if the upgrade would take any installed `nvidia-driver-*` package past `max`
+///
+/// `apt-get` has no command-line equivalent of a version pin, so this is checked up front
+/// (before anything is unheld) instead.
+fn check_max_version(
+    package_manager: &dyn PackageManager,
+    old_versions: &BTreeMap<String, String>,
+    max: &str,
+) -> Result<(), UpdateNvidiaError> {
+    let drivers: Vec<String> =
+        old_versions.keys().filter(|name| name.starts_with("nvidia-driver-")).cloned().collect();
+    match package_manager.candidate_version(&drivers)? {
+        Some(candidate) if exceeds_max_version(&candidate, max) => {
+            Err(UpdateNvidiaError::MaxVersionExceeded { candidate, max: max.to_owned() })
+        },
+        Some(candidate) => {
+            log!("Candidate driver version {} is within --max-version {}.", candidate, max);
+            Ok(())
+        },
+        None => {
+            log!("No installed nvidia-driver-* package has a candidate version to check.");
+            Ok(())
+        },
+    }
+}
+
+/// With `accept_new_packages = false`, refuse an upgrade which would install NVIDIA packages
+/// that aren't installed yet
+///
+/// Removing them afterward would also remove whatever depends on them (often the
+/// `nvidia-driver-*` package itself), so this asks the package manager before anything changes.
+fn check_new_packages(
+    config: &Config,
+    package_manager: &dyn PackageManager,
+) -> Result<(), UpdateNvidiaError> {
+    if config.accept_new_packages {
+        return Ok(());
+    }
+    let added: Vec<String> = package_manager
+        .simulate_upgrade()?
+        .into_iter()
+        .filter(|change| change.old_version.is_none() && is_eligible(config, &change.name))
+        .map(|change| change.name)
+        .collect();
+    if added.is_empty() {
+        Ok(())
+    } else {
+        Err(UpdateNvidiaError::NewPackagesRefused(added))
+    }
+}
+
+/// Unless `--assume-yes` was given, show what the upgrade would change and ask before proceeding
+///
+/// The upgrade isn't limited to NVIDIA packages, so this guards against a misconfigured system
+/// upgrading far more than intended. Without a terminal to ask on, it fails rather than hanging.
+fn confirm_upgrade(
+    config: &Config,
+    package_manager: &dyn PackageManager,
+) -> Result<(), UpdateNvidiaError> {
+    confirm_changes(config, "The upgrade will make the following changes:", || {
+        let mut lines: Vec<String> = package_manager
+            .simulate_upgrade()?
+            .into_iter()
+            .map(|change| {
+                let old_version = change.old_version.as_deref().unwrap_or("not installed");
+                format!("{} {} -> {}", change.name, old_version, change.new_version)
+            })
+            .collect();
+        if lines.is_empty() {
+            lines.push("(none reported, but held packages will still be re-held)".to_owned());
+        }
+        Ok(lines)
+    })
+}
+
+/// Unless `--assume-yes` was given, print `heading` and the lines `describe` returns, then ask
+/// whether to proceed
+///
+/// `describe` is only called once it's clear the user will actually be asked.
+fn confirm_changes(
+    config: &Config,
+    heading: &str,
+    describe: impl FnOnce() -> Result<Vec<String>, UpdateNvidiaError>,
+) -> Result<(), UpdateNvidiaError> {
+    if config.assume_yes || DRY_RUN.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(UpdateNvidiaError::ConfirmationRequired);
+    }
+
+    let lines = describe()?;
+    eprintln!("{}", heading);
+    for line in &lines {
+        eprintln!("    {}", line);
+    }
+    eprint!("Proceed? [y/N] ");
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(UpdateNvidiaError::UpgradeDeclined),
+    }
+}
+
+/// Ask the given processes to exit via `SIGTERM` and give them a few seconds to do so
+fn terminate_users(users: &[NvidiaUser]) {
+    log!("Asking processes using the GPU to exit (--kill-users)...");
+    for user in users {
+        if DRY_RUN.load(Ordering::Relaxed) {
+            log!("[DRY RUN] would send SIGTERM to PID {}", user.pid);
+        } else if let Err(e) = procfs::terminate(user.pid) {
+            log!("ERROR: Could not send SIGTERM to PID {}: {}", user.pid, e);
+        }
+    }
+
+    let deadline = Instant::now() + KILL_USERS_GRACE_PERIOD;
+    while !DRY_RUN.load(Ordering::Relaxed)
+        && Instant::now() < deadline
+        && users.iter().any(|user| procfs::is_running(user.pid))
+    {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Reboot the system, either immediately or after `config.reboot_delay` via `shutdown -r`
+fn reboot(config: &Config) -> Result<(), UpdateNvidiaError> {
+    if config.simulate_reboot {
+        std::fs::write(REBOOT_SIMULATED_MARKER, "")?;
+        log!("Would have rebooted");
+        return Ok(());
+    }
+    systemd::status("Rebooting to finish the upgrade...");
+    if config.reboot_delay == 0 {
+        log!("Triggering reboot...");
+        let mut cmd = Command::new(&config.reboot_path);
+        confirm_command(&cmd)?;
+        check_call!(cmd, UpdateNvidiaError::RebootFailed)?;
+    } else {
+        // shutdown(8) only supports whole minutes, so round up rather than rebooting early
+        let minutes = config.reboot_delay.div_ceil(60);
+        log!("Scheduling reboot in {} minute(s)...", minutes);
+        let mut cmd = Command::new(&config.shutdown_path);
+        cmd.arg("-r").arg(format!("+{}", minutes)).arg(REBOOT_MESSAGE);
+        confirm_command(&cmd)?;
+        check_call!(cmd, UpdateNvidiaError::RebootFailed)?;
+    }
+    Ok(())
 }
 
 /// Attempt to reload the nVidia kernel module. May trigger a reboot.
-fn reload_nvidia() -> Result<(), Box<dyn Error>> {
-    eprintln!("Attempting nvidia kernel module reload...");
-    match check_call!(Command::new(RMMOD_PATH).arg(NVIDIA_KMOD_NAME)) {
+///
+/// Loaded modules from `dependent_kmod_names` get unloaded first, since they'd keep the main
+/// module in use, and are loaded again after it.
+///
+/// If the module can't be unloaded because it's in use, the processes holding `/dev/nvidia*`
+/// open are reported and, with `kill_users`, asked to exit before one more attempt is made. Only
+/// that case falls back to a reboot. Other `rmmod` failures are returned as errors.
+///
+/// If an X server or Wayland compositor is running, nothing is done (unless
+/// `allow_display_interrupt` is set), since either a reload or a reboot would end the session.
+///
+/// If the module isn't loaded in the first place (eg. on a headless machine), there's nothing
+/// stale to replace, so neither it nor a reboot is attempted. If the driver is built by DKMS, the
+/// module for `new_versions` is built and installed first, with a reboot as the fallback should
+/// that fail.
+///
+/// The return value indicates which of these was done.
+fn reload_nvidia(
+    config: &Config,
+    new_versions: &BTreeMap<String, String>,
+    inhibit_lock: &mut SystemdInhibitLock,
+) -> Result<Action, UpdateNvidiaError> {
+    match is_module_loaded(&config.nvidia_kmod_name) {
+        Ok(true) => {},
+        Ok(false) => {
+            log!("The {} kernel module isn't loaded. No reload needed.", config.nvidia_kmod_name);
+            return Ok(Action::None);
+        },
+        // Err on the side of the old behaviour and let rmmod decide
+        Err(e) => log!("ERROR: Could not read /proc/modules. ({})", e),
+    }
+
+    if !config.allow_display_interrupt {
+        if let Some(server) = detect_display_server_using_nvidia() {
+            return Err(UpdateNvidiaError::DisplayServerRunning(server));
+        }
+    }
+
+    // Don't unload a working module if there's nothing to replace it with
+    if let Err(e) = dkms::ensure_installed(config, new_versions) {
+        return fall_back_to_reboot(config, &e, inhibit_lock);
+    }
+
+    // Only the dependent modules which were loaded need to be put back afterward
+    let mut unloaded_deps = Vec::new();
+    let mut unload = || kmod::unload_nvidia_modules(config, &mut unloaded_deps);
+
+    systemd::status("Reloading the kernel module...");
+    log!("Attempting nvidia kernel module reload...");
+    let mut unload_result = unload();
+    if let Err(e @ UpdateNvidiaError::KernelModuleUnloadFailed(ModuleUnloadError::InUse(_))) =
+        &unload_result
+    {
+        log!("Module unload failed ({}).", e);
+        let users = find_nvidia_users();
+        for user in &users {
+            log!("    In use by {}", user);
+        }
+        if config.kill_users && !users.is_empty() {
+            terminate_users(&users);
+            log!("Retrying nvidia kernel module unload...");
+            unload_result = unload();
+        }
+    }
+
+    match unload_result {
         Ok(_) => {
-            check_call!(Command::new(MODPROBE_PATH).arg(NVIDIA_KMOD_NAME))?;
+            kmod::load_nvidia_module(config)?;
+            for name in unloaded_deps.iter().rev() {
+                let mut cmd = Command::new(&config.modprobe_path);
+                cmd.arg(name);
+                confirm_command(&cmd)?;
+                check_call!(cmd, UpdateNvidiaError::KernelModuleLoadFailed)?;
+            }
+            if let Some(e) = loaded_version_mismatch(new_versions) {
+                return fall_back_to_reboot(config, &e, inhibit_lock);
+            }
+            match verify_driver(config) {
+                Err(e) if config.reboot_on_verify_failure => {
+                    fall_back_to_reboot(config, &e, inhibit_lock)
+                },
+                Err(e) => Err(e),
+                Ok(()) => Ok(Action::ModuleReload),
+            }
         },
-        Err(_) => {
-            eprintln!("Module reload failed. Triggering reboot...");
-            check_call!(Command::new(REBOOT_PATH))?;
+        Err(e @ UpdateNvidiaError::KernelModuleUnloadFailed(ModuleUnloadError::InUse(_))) => {
+            fall_back_to_reboot(config, &e, inhibit_lock)
         },
+        // Rebooting won't help with something like a permissions problem
+        Err(e) => Err(e),
+    }
+}
+
+/// Check that the module which just got loaded is the one from the upgraded `nvidia-driver-*`
+/// package, rather than a stale copy, returning the error to reboot over if it isn't
+///
+/// Only a mismatch counts. If there's no such package or the loaded version can't be read,
+/// there's nothing to compare, so the reload is taken at its word.
+fn loaded_version_mismatch(new_versions: &BTreeMap<String, String>) -> Option<UpdateNvidiaError> {
+    if DRY_RUN.load(Ordering::Relaxed) {
+        return None;
+    }
+    let expected = new_versions
+        .iter()
+        .find(|(name, _)| name.starts_with("nvidia-driver-"))
+        .map(|(_, version)| upstream_version(version))?;
+    match procfs::get_loaded_nvidia_version() {
+        Ok(Some(loaded)) if loaded != expected => {
+            Some(UpdateNvidiaError::LoadedVersionMismatch { loaded, expected: expected.to_owned() })
+        },
+        Ok(Some(loaded)) => {
+            log!("The loaded kernel module is version {}.", loaded);
+            None
+        },
+        Ok(None) => None,
+        Err(e) => {
+            log!("WARNING: Could not check the loaded kernel module's version. ({})", e);
+            None
+        },
+    }
+}
+
+/// Check that the reloaded driver can see at least one GPU (`verify_after_reload`), since
+/// `modprobe` can succeed even if the driver then fails to initialize
+///
+/// Does nothing unless `verify_after_reload` is set, or under `--dry-run`, where nothing was
+/// reloaded.
+fn verify_driver(config: &Config) -> Result<(), UpdateNvidiaError> {
+    if !config.verify_after_reload || DRY_RUN.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let mut cmd = Command::new(&config.nvidia_smi_path);
+    cmd.arg("-L");
+    let output = process::run_output(&mut cmd)?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let gpus = stdout.lines().filter(|line| line.starts_with("GPU ")).count();
+    if output.status.success() && gpus > 0 {
+        log!("nvidia-smi sees {} GPU(s) with the reloaded driver.", gpus);
+        return Ok(());
+    }
+
+    for line in stdout.lines().chain(stderr.lines()).filter(|line| !line.trim().is_empty()) {
+        log!("    {}", line);
+    }
+    Err(UpdateNvidiaError::DriverVerificationFailed(if output.status.success() {
+        "nvidia-smi -L listed no GPUs".to_owned()
+    } else {
+        CalledProcessError::from_output(&cmd, &output).to_string()
+    }))
+}
+
+/// Tell whoever handles the module for `--packages-only` whether it needs reloading
+///
+/// That's the case if anything was upgraded while the module is loaded, since the old one will
+/// keep running until it's replaced.
+fn report_pending_reload(config: &Config, upgraded: bool) {
+    let name = &config.nvidia_kmod_name;
+    if !upgraded {
+        log!("Nothing was upgraded. No reload needed.");
+        return;
+    }
+    match is_module_loaded(name) {
+        Ok(true) => log!(
+            "The {} kernel module is loaded, so it needs to be reloaded (or the system rebooted) \
+             to finish the upgrade. Skipping that because of --packages-only.",
+            name
+        ),
+        Ok(false) => log!("The {} kernel module isn't loaded. No reload needed.", name),
+        Err(e) => log!(
+            "ERROR: Could not read /proc/modules, so the {} kernel module may need to be \
+             reloaded to finish the upgrade. ({})",
+            name,
+            e
+        ),
+    }
+}
+
+/// With `--no-module-reload`, leave the upgraded module for the next reboot, returning whether
+/// one is needed (ie. whether the old module is loaded)
+///
+/// If `/proc/modules` can't be read, the module is assumed to be loaded, so the reboot isn't
+/// skipped by mistake.
+fn defer_reload_to_reboot(config: &Config) -> bool {
+    let name = &config.nvidia_kmod_name;
+    let loaded = is_module_loaded(name).unwrap_or_else(|e| {
+        log!("ERROR: Could not read /proc/modules. Assuming {} is loaded. ({})", name, e);
+        true
+    });
+    if loaded {
+        log!(
+            "Leaving the {} kernel module loaded because of --no-module-reload. Recording that a \
+             reboot is required in {}.",
+            name,
+            config.state_file_path.display()
+        );
+    } else {
+        log!("The {} kernel module isn't loaded. No reboot needed.", name);
+    }
+    loaded
+}
+
+/// Whether the state file says an earlier `--no-module-reload` run is still waiting for a reboot
+fn reboot_pending(config: &Config) -> bool {
+    State::read(&config.state_file_path).is_ok_and(|state| state.reboot_pending())
+}
+
+/// Reboot because the module couldn't be reloaded or, with `no_reboot`, report that one is needed
+///
+/// `inhibit_lock` is released first, since it would otherwise block the reboot.
+fn fall_back_to_reboot(
+    config: &Config,
+    reason: &UpdateNvidiaError,
+    inhibit_lock: &mut SystemdInhibitLock,
+) -> Result<Action, UpdateNvidiaError> {
+    if config.no_reboot {
+        log!("Module reload failed ({}). Not rebooting because of --no-reboot.", reason);
+        Err(UpdateNvidiaError::RebootRequired { reboot_path: config.reboot_path.clone() })
+    } else {
+        log!("Module reload failed ({}).", reason);
+        inhibit_lock.release();
+        reboot(config)?;
+        Ok(Action::Reboot)
     }
-    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let mut mark_only = false;
-
-    // Basic CLI argument parser that doesn't rely on external crates
-    let mut args = std::env::args();
-    let cmd = args.next().expect("get argv[0] from std::env::args");
-    #[allow(clippy::wildcard_in_or_patterns)] // Make "Help or unrecognized" intent clear
-    for arg in args {
-        match arg.as_ref() {
-            "--mark-only" => {
-                mark_only = true;
+    let start_time = Instant::now();
+
+    let mut argv = std::env::args();
+    let cmd = argv.next().expect("get argv[0] from std::env::args");
+    let args = match cli::Args::parse(argv) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("ERROR: {}\n\nRun {} --help for usage information.", e, cmd);
+            std::process::exit(exit_code::USAGE_ERROR);
+        },
+    };
+    if args.help {
+        cli::print_help(&cmd);
+        return Ok(());
+    }
+    if let Some(shell) = args.generate_completions {
+        print!("{}", shell.completion_script());
+        return Ok(());
+    }
+    if changes_system(&args) {
+        if let Err(e) = check_running_as_root() {
+            eprintln!("ERROR: {}", e);
+            std::process::exit(exit_code::UPGRADE_ERROR);
+        }
+    }
+    // Faked versions are only good for seeing what would happen, never for acting on
+    let pretending = !args.pretend_versions.0.is_empty();
+    DRY_RUN.store(args.dry_run || pretending, Ordering::Relaxed);
+    log::set_pretend_mode(pretending);
+    VERBOSE.store(args.verbose, Ordering::Relaxed);
+    INTERACTIVE.store(args.interactive, Ordering::Relaxed);
+    if let Some(format) = args.output_format {
+        log::set_format(format);
+    }
+    systemd::SYSTEMD_NOTIFY.store(args.systemd_notify, Ordering::Relaxed);
+    if let Some(path) = &args.log_file {
+        log::open_log_file(path);
+    }
+
+    let result = match args.check_interval {
+        Some(hours) => run_periodically(&args, Duration::from_secs(hours.saturating_mul(3600))),
+        None => run(&args, start_time),
+    };
+    // In case nothing needed upgrading, so a `Type=notify` unit doesn't see the exit as a failure
+    systemd::notify("READY=1");
+    log_unheld_packages();
+    log::close_log_file(result.as_ref().err().map(|e| e as &dyn std::fmt::Display));
+    match &result {
+        Err(e) if args.check_only => {
+            log::print_message(&format!("ERROR: {}", e));
+            std::process::exit(exit_code::CHECK_FAILED);
+        },
+        Err(e @ (UpdateNvidiaError::LockHeld(_) | UpdateNvidiaError::DpkgLockHeld { .. })) => {
+            log::print_message(&format!("ERROR: {}", e));
+            std::process::exit(exit_code::LOCK_HELD);
+        },
+        Err(
+            e @ (UpdateNvidiaError::InsufficientDiskSpace { .. }
+            | UpdateNvidiaError::Timeout { .. }
+            | UpdateNvidiaError::ConfirmationRequired
+            | UpdateNvidiaError::UpgradeDeclined
+            | UpdateNvidiaError::NewPackagesRefused(_)
+            | UpdateNvidiaError::InteractiveWithoutTerminal
+            | UpdateNvidiaError::PromptAborted(_)
+            | UpdateNvidiaError::MaxVersionExceeded { .. }
+            | UpdateNvidiaError::SecureBootKeyMissing
+            | UpdateNvidiaError::AptKeyMismatch { .. }
+            | UpdateNvidiaError::HoldsMissing(_)
+            | UpdateNvidiaError::NoRollbackTarget(_)),
+        ) => {
+            log::print_message(&format!("ERROR: {}", e));
+            std::process::exit(exit_code::UPGRADE_ERROR);
+        },
+        Err(UpdateNvidiaError::RebootRequired { reboot_path }) => {
+            log::print_message(&format!(
+                "ERROR: The updated nvidia packages were installed, but the kernel module could \
+                 not be reloaded and --no-reboot was given.\n\nUntil the system is restarted, \
+                 GPU acceleration may be broken. To finish the upgrade, run:\n\n    sudo {}",
+                reboot_path.display()
+            ));
+            std::process::exit(exit_code::REBOOT_REQUIRED);
+        },
+        Err(e @ UpdateNvidiaError::MissingDependency { .. }) => {
+            log::print_message(&format!("ERROR: {}", e));
+            std::process::exit(exit_code::DEPENDENCY_MISSING);
+        },
+        Err(UpdateNvidiaError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            log::print_message(&format!("ERROR: A required command could not be found: {}", e));
+            std::process::exit(exit_code::DEPENDENCY_MISSING);
+        },
+        // Make sure systemd reports the unit as failed so someone notices the missing holds
+        Ok(_) if REHOLD_FAILED.load(Ordering::Relaxed) => {
+            std::process::exit(exit_code::UPGRADE_ERROR)
+        },
+        Ok(code) if *code != exit_code::SUCCESS => std::process::exit(*code),
+        _ => {},
+    }
+    result?;
+    Ok(())
+}
+
+/// Update the state file for monitoring systems and append to the versions file (if enabled),
+/// logging rather than returning any failure since the upgrade itself has already succeeded
+fn record_state(config: &Config, summary: &RunSummary) {
+    let path = &config.state_file_path;
+    if DRY_RUN.load(Ordering::Relaxed) {
+        log!("[DRY RUN] would record the results in {}", path.display());
+        return;
+    }
+    let state = State::from_summary(
+        summary,
+        log::format_rfc3339(SystemTime::now()),
+        procfs::boot_id().ok(),
+    );
+    if let Err(e) = state.write(path) {
+        log!("ERROR: Could not write state file {}: {}", path.display(), e);
+    }
+
+    if let Some(path) = &config.package_versions_file {
+        let (old, new) = (&summary.old_versions, &summary.new_versions);
+        if let Err(e) = history::write_versions_report(path, old, new, summary.action) {
+            log!("ERROR: Could not append to versions file {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Email `report` to the `--email` address (if there is one), logging rather than returning any
+/// failure, since the report shouldn't change the outcome of the run
+fn send_email_report(config: &Config, report: &email::Report) {
+    let Some(to) = &config.email else {
+        return;
+    };
+    if let Err(e) = email::send_report(config, to, report) {
+        log!("ERROR: Could not email the report to {}: {}", to, e);
+    }
+}
+
+/// Make sure every command this run will need exists and is executable
+///
+/// Without this, a typo in a `*_path` setting would only show up as a bare "No such file or
+/// directory" partway through the run, possibly with the packages already unheld. Every missing
+/// command is reported at once so they can all be fixed in one go.
+fn check_dependencies(
+    config: &Config,
+    args: &cli::Args,
+    kind: PackageManagerKind,
+) -> Result<(), UpdateNvidiaError> {
+    let mut required: Vec<&Path> = match kind {
+        PackageManagerKind::Apt => {
+            vec![&config.apt_get_path, &config.apt_mark_path, &config.dpkg_query_path]
+        },
+        PackageManagerKind::Dnf => vec![&config.dnf_path, &config.rpm_path],
+        PackageManagerKind::Zypper => vec![&config.zypper_path, &config.rpm_path],
+        PackageManagerKind::Pacman => vec![&config.pacman_path],
+    };
+    if (args.max_version.is_some() || args.list_available) && kind == PackageManagerKind::Apt {
+        required.push(&config.apt_cache_path);
+    }
+    if !(args.simulate || args.check_only || args.migrate_hold || args.list_available) {
+        if config.notify_send {
+            required.push(&config.notify_send_path);
+        }
+        if config.email.is_some() {
+            required.push(&config.sendmail_path);
+        }
+        if config.ppa.is_some() && !args.mark_only {
+            required.push(&config.add_apt_repository_path);
+        }
+        if config.apt_key.is_some() && !args.mark_only {
+            required.push(&config.gpg_path);
+        }
+        let reloading = config.ignore_virtualization || virt::reload_blocker().is_none();
+        if reloading && !(args.mark_only || args.packages_only || config.no_module_reload) {
+            required.extend([config.rmmod_path.as_path(), &config.modprobe_path]);
+            if config.verify_after_reload {
+                required.push(&config.nvidia_smi_path);
+            }
+            if !config.no_reboot {
+                required.push(if config.reboot_delay == 0 {
+                    &config.reboot_path
+                } else {
+                    &config.shutdown_path
+                });
+            }
+        }
+    }
+
+    let is_executable = |path: &Path| {
+        path.metadata().is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    };
+    let missing: Vec<PathBuf> =
+        required.into_iter().filter(|path| !is_executable(path)).map(Path::to_owned).collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(UpdateNvidiaError::MissingDependency { paths: missing })
+    }
+}
+
+/// Report any packages whose re-hold failed (and which are still unreported)
+fn log_unheld_packages() {
+    let mut unheld = UNHELD_PACKAGES.with(|unheld| unheld.take());
+    if unheld.is_empty() {
+        return;
+    }
+    unheld.sort_unstable();
+    unheld.dedup();
+    log!(
+        "ERROR: These packages are no longer held and may be upgraded outside of update_nvidia: \
+         {}",
+        unheld.join(" ")
+    );
+}
+
+/// Call [`run`] every `interval` until `SIGTERM` or `SIGINT` arrives (`--check-interval`)
+///
+/// A failed run is only logged, so a flaky mirror doesn't stop the checks for good. This is
+/// meant for systems without cron or systemd timers rather than as a full daemon.
+fn run_periodically(args: &cli::Args, interval: Duration) -> Result<i32, UpdateNvidiaError> {
+    signal::install_termination_handler()?;
+    loop {
+        match run(args, Instant::now()) {
+            Ok(code) if code != exit_code::SUCCESS => {
+                log!("WARNING: The run finished with exit code {}.", code)
             },
-            "-h" | "--help" | _ => {
-                println!("Usage: {} [-h|--help|--mark-only]\n", cmd);
-                println!("    -h | --help\t\tShow this message");
-                println!(
-                    "    --mark-only\t\tDon't actually update packages. Just re-hold packages."
+            Ok(_) => {},
+            Err(e) => log!("ERROR: {}", e),
+        }
+        log_unheld_packages();
+
+        if signal::terminate_requested() {
+            break;
+        }
+        log!("Next check in {} hour(s).", interval.as_secs() / 3600);
+        if !signal::sleep_unless_terminated(interval) {
+            break;
+        }
+    }
+    log!("Received a termination signal. Exiting.");
+    Ok(exit_code::SUCCESS)
+}
+
+/// Everything after argument parsing and logging setup, so `main` can log the outcome
+///
+/// Returns the code the process should exit with, if it succeeds.
+fn run(args: &cli::Args, start_time: Instant) -> Result<i32, UpdateNvidiaError> {
+    let sink = if args.json { OutputSink::Json } else { OutputSink::Human };
+
+    let mut config = match &args.config {
+        Some(path) => Config::load(path, true)?,
+        None => Config::load(DEFAULT_CONFIG_PATH.as_ref(), false)?,
+    };
+    config.apply_env_overrides();
+    config.apply_args(args);
+    process::set_timeout(config.timeout);
+    process::set_preserved_env(if config.full_env {
+        None
+    } else {
+        Some(config.preserve_env.clone())
+    });
+    if config.force_update && config.skip_update {
+        log!("ERROR: --force-update and --skip-update can't be used together");
+        return Ok(exit_code::USAGE_ERROR);
+    }
+    if args.interactive && !DRY_RUN.load(Ordering::Relaxed) {
+        // Fail before anything is changed, rather than at the first prompt
+        if !std::io::stdin().is_terminal() {
+            return Err(UpdateNvidiaError::InteractiveWithoutTerminal);
+        }
+        // Let Ctrl-C at a prompt return an error (so the holds go back on) instead of killing
+        // the process outright
+        signal::install_termination_handler()?;
+    }
+    if args.clean_cache && args.keep_apt_cache {
+        log!("ERROR: --clean-cache and --keep-apt-cache can't be used together");
+        return Ok(exit_code::USAGE_ERROR);
+    }
+    if args.force_reload && (args.mark_only || args.packages_only || config.no_module_reload) {
+        log!(
+            "ERROR: --force-reload can't be used with --mark-only, --packages-only, or \
+             --no-module-reload"
+        );
+        return Ok(exit_code::USAGE_ERROR);
+    }
+    if args.schedule && args.unschedule {
+        log!("ERROR: --schedule and --unschedule can't be used together");
+        return Ok(exit_code::USAGE_ERROR);
+    }
+    if args.schedule && !(args.on_boot || args.daily) {
+        log!("ERROR: --schedule requires --on-boot, --daily, or both");
+        return Ok(exit_code::USAGE_ERROR);
+    }
+    if !args.schedule && (args.on_boot || args.daily) {
+        log!("ERROR: --on-boot and --daily can only be used with --schedule");
+        return Ok(exit_code::USAGE_ERROR);
+    }
+    if args.schedule {
+        return schedule::install(&config, args.config.as_deref(), args.on_boot, args.daily)
+            .map(|()| 0);
+    }
+    if args.unschedule {
+        return schedule::uninstall(&config).map(|()| 0);
+    }
+
+    let package_manager = packages::detect(&config);
+    if args.subcommand == Some(cli::Subcommand::Status) {
+        return cmd::status::run(&config, &*package_manager).map(|()| 0);
+    }
+    if args.subcommand == Some(cli::Subcommand::Report) {
+        return cmd::report::run(&config, &*package_manager).map(|()| 0);
+    }
+    if args.show_history {
+        let Some(path) = &config.package_versions_file else {
+            log!("ERROR: --show-history requires --package-versions-file or package_versions_file");
+            return Ok(exit_code::USAGE_ERROR);
+        };
+        return cmd::history::run(path).map(|()| 0);
+    }
+    check_dependencies(&config, args, package_manager.kind())?;
+    if args.list_available {
+        if package_manager.kind() != PackageManagerKind::Apt {
+            return Err(UpdateNvidiaError::Unsupported(
+                "--list-available is only supported with apt",
+            ));
+        }
+        return cmd::list_available::run(&config, &*package_manager, args.force_update).map(|()| 0);
+    }
+    if args.simulate {
+        return cmd::simulate::run(&config, &*package_manager).map(|()| 0);
+    }
+    if args.migrate_hold {
+        return cmd::migrate_hold::run(&config, &*package_manager).map(|()| 0);
+    }
+    if args.check_only {
+        // If it's unknown, try the refresh anyway and let it report any failure itself
+        let is_root = procfs::effective_uid().map_or(true, |uid| uid == 0);
+        let available =
+            cmd::check::check_nvidia_upgrade_available(&config, &*package_manager, is_root)?;
+        return Ok(if available {
+            exit_code::CHECK_UPGRADE_AVAILABLE
+        } else {
+            exit_code::CHECK_NO_UPGRADE
+        });
+    }
+
+    if args.only_if_running {
+        match is_module_loaded(&config.nvidia_kmod_name) {
+            Ok(true) => {},
+            Ok(false) => {
+                log!(
+                    "The {} kernel module isn't loaded. Skipping the upgrade (--only-if-running).",
+                    config.nvidia_kmod_name
                 );
-                println!("\nRequired external dependencies:\n");
-                println!("    - {}", APT_GET_PATH);
-                println!("    - {}", DPKG_QUERY_PATH);
-                println!("    - {} (or {})", MODPROBE_PATH, REBOOT_PATH);
-                println!("    - {} (or {})", RMMOD_PATH, REBOOT_PATH);
-                println!("\nOptional external dependencies:\n");
-                println!(
-                    "    - {} (mtime is checked to judge package index staleness)",
-                    APT_UPDATE_MTIME_PATH
+                return Ok(exit_code::SUCCESS);
+            },
+            Err(e) => log!("ERROR: Could not read /proc/modules. Continuing. ({})", e),
+        }
+    }
+    let is_apt = package_manager.kind() == PackageManagerKind::Apt;
+    let key_added = match &config.apt_key {
+        Some(_) if !is_apt => {
+            return Err(UpdateNvidiaError::Unsupported("--apt-key is only supported with apt"));
+        },
+        Some(key) if !args.mark_only => {
+            apt_key::setup_apt_key(&config, key, &apt_key::keyring_path(key))?
+        },
+        _ => false,
+    };
+    if config.use_ubuntu_drivers && !is_apt {
+        return Err(UpdateNvidiaError::Unsupported("--ubuntu-drivers is only supported with apt"));
+    }
+    let ppa_added = match &config.ppa {
+        Some(_) if !is_apt => {
+            return Err(UpdateNvidiaError::Unsupported("--ppa is only supported with apt"));
+        },
+        Some(ppa) if !args.mark_only => ppa::setup_ppa(&config, ppa)?,
+        _ => false,
+    };
+    let package_manager = if key_added || ppa_added {
+        // Make sure the new source or key makes it into the index, however fresh it otherwise is
+        drop(package_manager);
+        config.force_update = true;
+        packages::detect(&config)
+    } else {
+        package_manager
+    };
+    let package_manager: Box<dyn PackageManager> = if !args.pretend_versions.0.is_empty() {
+        let (old, new) = args.pretend_versions.clone();
+        Box::new(pretend::PretendPackageManager::new(package_manager, old, new))
+    } else {
+        package_manager
+    };
+
+    let result = run_upgrade(&config, args, &*package_manager, sink, start_time);
+    if let Err(e) = &result {
+        send_email_report(&config, &email::Report::Failed(e));
+    }
+    result
+}
+
+/// Upgrade (or roll back) the packages and make the new driver take effect
+fn run_upgrade(
+    config: &Config,
+    args: &cli::Args,
+    package_manager: &dyn PackageManager,
+    sink: OutputSink,
+    start_time: Instant,
+) -> Result<i32, UpdateNvidiaError> {
+    if config.require_ac_power && !args.mark_only {
+        match power::check_ac_power() {
+            Ok(true) => {},
+            Ok(false) => {
+                log!(
+                    "WARNING: Running on battery power. Skipping the upgrade, since losing power \
+                     partway through could leave the package database broken. It will be \
+                     retried the next time this runs on AC power. (To upgrade anyway, set \
+                     require_ac_power = false.)"
                 );
-                return Ok(());
+                return Ok(exit_code::SUCCESS);
             },
+            Err(e) => log!("ERROR: Could not determine the power source. Continuing. ({})", e),
         }
     }
 
-    if do_upgrade(mark_only)? {
-        reload_nvidia()?;
+    // Held until the end of the run so two instances can't race through unhold/upgrade/re-hold
+    let _lock = if DRY_RUN.load(Ordering::Relaxed) {
+        None
+    } else {
+        Some(LockFile::acquire(&config.lock_file_path)?)
+    };
+    // Lets monitoring scripts ask how far along the run is with `kill -USR1`
+    let _status_dump = if DRY_RUN.load(Ordering::Relaxed) {
+        None
+    } else {
+        progress::StatusDump::start(start_time)
+            .map_err(|e| log!("WARNING: Could not set up the SIGUSR1 status dump: {}", e))
+            .ok()
+    };
+
+    // Covers everything from unholding the packages through reloading the module
+    let mut inhibit_lock = SystemdInhibitLock::acquire(&config.systemd_inhibit_path);
+    let mut outcome = if args.rollback {
+        let target = cmd::rollback::select_target(config, args.rollback_before.as_deref())?;
+        cmd::rollback::run(config, package_manager, &target)?
+    } else {
+        do_upgrade(config, package_manager, args.mark_only, args.max_version.as_deref())?
+    };
+    let upgraded = outcome.upgraded();
+    let left_unheld = std::mem::take(&mut outcome.left_unheld);
+    if upgraded {
+        log!("Package changes:");
+        for line in format_version_diff(&outcome.old_versions, &outcome.new_versions).lines() {
+            log!("    {}", line);
+        }
+    }
+    if upgraded && config.notify_send {
+        if let Err(e) = notify::notify_upgrade(config, &outcome.old_versions, &outcome.new_versions)
+        {
+            log!("ERROR: Could not send desktop notification: {}", e);
+        }
+    }
+    match &outcome.settings_backup {
+        Some(backup) if upgraded && config.restore_settings => backup.restore()?,
+        _ => {},
+    }
+    let mut reboot_required = false;
+    let mut display_in_use = false;
+    let action = if args.packages_only {
+        report_pending_reload(config, upgraded);
+        Action::None
+    } else if upgraded && config.no_module_reload {
+        reboot_required = defer_reload_to_reboot(config);
+        Action::None
+    } else if upgraded || args.force_reload {
+        if !upgraded {
+            log!("Forcing module reload as requested (--force-reload)");
+        }
+        match (!config.ignore_virtualization).then(virt::reload_blocker).flatten() {
+            Some(kind) => {
+                log!(
+                    "Running in {}, so there's no NVIDIA kernel module to reload. (Pass \
+                     --ignore-virtualization to reload it anyway.)",
+                    kind
+                );
+                Action::None
+            },
+            None => match timing::time(Phase::ModuleReload, || {
+                reload_nvidia(config, &outcome.new_versions, &mut inhibit_lock)
+            }) {
+                // The upgrade itself succeeded, so it still gets recorded and reported as one
+                Err(e @ UpdateNvidiaError::DisplayServerRunning(_)) => {
+                    log!("ERROR: {}", e);
+                    display_in_use = true;
+                    reboot_required = true;
+                    Action::None
+                },
+                result => result?,
+            },
+        }
+    } else {
+        Action::None
+    };
+    drop(inhibit_lock);
+    // A run with nothing to upgrade mustn't hide the reboot an earlier one is still waiting for
+    let reboot_required = reboot_required || (action == Action::None && reboot_pending(config));
+
+    // Only if an upgrade was actually attempted, to mirror the pre-upgrade hook
+    if let Some(hook) = &config.post_upgrade_hook {
+        if !args.mark_only && !outcome.old_versions.is_empty() {
+            let result = hooks::run_hook(
+                hook,
+                &[
+                    ("UPDATE_NVIDIA_ACTION", action.as_str()),
+                    ("UPDATE_NVIDIA_OLD_VERSIONS", &Json::from(&outcome.old_versions).to_string()),
+                    ("UPDATE_NVIDIA_NEW_VERSIONS", &Json::from(&outcome.new_versions).to_string()),
+                ],
+            );
+            if let Err(e) = result {
+                log!("ERROR: {}", e);
+            }
+        }
+    }
+
+    let summary = RunSummary {
+        upgraded,
+        old_versions: outcome.old_versions,
+        new_versions: outcome.new_versions,
+        action,
+        reboot_required,
+        elapsed: start_time.elapsed(),
+        timings: timing::take_timings(),
+    };
+    log!("Time taken: {}", timing::format_timings(&summary.timings, summary.elapsed));
+    if !args.mark_only {
+        record_state(config, &summary);
+    }
+    log_event(sink, &summary);
+    if upgraded {
+        send_email_report(config, &email::Report::Completed(&summary));
+    }
+
+    // Checked last, since the driver is already upgraded and reloaded either way
+    if config.hold_new_packages && !DRY_RUN.load(Ordering::Relaxed) {
+        let names: Vec<String> =
+            summary.new_versions.into_keys().filter(|name| !left_unheld.contains(name)).collect();
+        verify_holds(package_manager, &names)?;
+    }
+    Ok(if display_in_use {
+        exit_code::DISPLAY_IN_USE
+    } else if reboot_required {
+        exit_code::REBOOT_REQUIRED
+    } else {
+        exit_code::SUCCESS
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, CommandResult, ScriptedRunner};
+
+    #[test]
+    fn module_in_use_falls_back_to_a_simulated_reboot() {
+        let runner = ScriptedRunner::new()
+            .on("rmmod *", CommandResult::failed(1, "rmmod: ERROR: Module nvidia is in use\n"));
+        let calls = runner.calls();
+        let _guard = mock::install(runner);
+        let config = Config { simulate_reboot: true, ..Config::default() };
+        let _ = std::fs::remove_file(REBOOT_SIMULATED_MARKER);
+
+        let error = kmod::unload_nvidia_modules(&config, &mut Vec::new()).unwrap_err();
+        assert!(matches!(
+            error,
+            UpdateNvidiaError::KernelModuleUnloadFailed(ModuleUnloadError::InUse(_))
+        ));
+        let action = fall_back_to_reboot(&config, &error, &mut SystemdInhibitLock::none());
+        assert_eq!(action.unwrap(), Action::Reboot);
+        assert!(Path::new(REBOOT_SIMULATED_MARKER).exists());
+        assert!(calls.borrow().iter().all(|call| call.starts_with("rmmod ")));
+        std::fs::remove_file(REBOOT_SIMULATED_MARKER).unwrap();
+    }
+
+    #[test]
+    fn only_modes_that_change_the_system_need_root() {
+        let needs_root = |argv: &[&str]| {
+            changes_system(&cli::Args::parse(argv.iter().map(|arg| arg.to_string())).unwrap())
+        };
+        for argv in [
+            &["status"][..],
+            &["report"],
+            &["--check-only"],
+            &["--list-available"],
+            &["--show-history"],
+            &["--simulate"],
+            &["--dry-run"],
+            &["--mark-only", "--dry-run"],
+        ] {
+            assert!(!needs_root(argv), "{:?}", argv);
+        }
+        for argv in [
+            &[][..],
+            &["--mark-only"],
+            &["--migrate-hold"],
+            &["--list-available", "--force-update"],
+        ] {
+            assert!(needs_root(argv), "{:?}", argv);
+        }
+
+        // Even with a stale index, --check-only without root only looks rather than updating it
+        let runner = ScriptedRunner::new().on("apt-get *dist-upgrade -s*", CommandResult::ok(""));
+        let calls = runner.calls();
+        let _guard = mock::install(runner);
+        let config = Config {
+            package_manager: Some(PackageManagerKind::Apt),
+            // Never updated, so as stale as an index gets
+            apt_update_mtime_path: PathBuf::from("/nonexistent/update-success-stamp"),
+            ..Config::default()
+        };
+
+        let package_manager = packages::detect(&config);
+        assert!(
+            !cmd::check::check_nvidia_upgrade_available(&config, &*package_manager, false).unwrap()
+        );
+        assert!(calls.borrow().iter().all(|call| !call.contains(" update")), "{:?}", calls);
+    }
+
+    #[test]
+    fn new_packages_are_refused_before_the_upgrade() {
+        let runner = ScriptedRunner::new().on(
+            "apt-get *dist-upgrade -s*",
+            CommandResult::ok(
+                "Inst nvidia-driver-535 [535.86.10-1] (535.104.05-1 Ubuntu:22.04/jammy-updates)\n\
+                 Inst nvidia-firmware-535-535.104.05 (535.104.05-1 Ubuntu:22.04/jammy-updates)\n",
+            ),
+        );
+        let calls = runner.calls();
+        let _guard = mock::install(runner);
+        let config = Config {
+            package_manager: Some(PackageManagerKind::Apt),
+            accept_new_packages: false,
+            ..Config::default()
+        };
+
+        let error = check_new_packages(&config, &*packages::detect(&config)).unwrap_err();
+        assert!(matches!(
+            error,
+            UpdateNvidiaError::NewPackagesRefused(names)
+                if names == ["nvidia-firmware-535-535.104.05"]
+        ));
+        assert!(calls.borrow().iter().all(|call| call.contains(" -s")));
+
+        // Allowed by default, without even simulating the upgrade
+        let config = Config { package_manager: Some(PackageManagerKind::Apt), ..Config::default() };
+        check_new_packages(&config, &*packages::detect(&config)).unwrap();
+        assert_eq!(calls.borrow().len(), 1);
+    }
+
+    #[test]
+    fn unhold_guard_leaves_previously_unheld_packages_alone() {
+        let runner = ScriptedRunner::new()
+            .on("apt-mark showhold", CommandResult::ok("nvidia-driver-535\n"))
+            .on("apt-mark unhold *", CommandResult::ok(""))
+            .on("apt-mark hold *", CommandResult::ok(""));
+        let calls = runner.calls();
+        let _guard = mock::install(runner);
+        let config = Config { package_manager: Some(PackageManagerKind::Apt), ..Config::default() };
+        let package_manager = packages::detect(&config);
+        let names = vec!["libnvidia-gl-535".to_owned(), "nvidia-driver-535".to_owned()];
+
+        let mut unhold_guard = UnholdGuard::new(&*package_manager, names.clone()).unwrap();
+        // As after an upgrade which installed a new package
+        unhold_guard.extend(names.into_iter().chain(["nvidia-firmware-535".to_owned()]));
+        drop(unhold_guard);
+        let holds: Vec<String> = calls
+            .borrow()
+            .iter()
+            .filter(|call| call.starts_with("apt-mark hold "))
+            .cloned()
+            .collect();
+        assert_eq!(holds, ["apt-mark hold -qq nvidia-driver-535 nvidia-firmware-535"]);
     }
-    Ok(())
 }
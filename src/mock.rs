@@ -0,0 +1,130 @@
+//! Stand-ins for external commands, so whole code paths can be tested without root access or
+//! real NVIDIA packages
+//!
+//! While a [`MockCommandRunner`] is installed on the current thread, [`run_status`],
+//! [`run_apt`], and [`run_output`] (and so [`check_call!`] and [`check_output!`]) ask it what
+//! each command did instead of running it. `--dry-run` is still honoured first.
+//!
+//! [`run_status`]: crate::process::run_status
+//! [`run_apt`]: crate::process::run_apt
+//! [`run_output`]: crate::process::run_output
+//! [`check_call!`]: crate::process::check_call
+//! [`check_output!`]: crate::process::check_output
+
+use std::cell::RefCell;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Output};
+use std::rc::Rc;
+
+use crate::packages::glob_match;
+
+/// What a [`MockCommandRunner`] says a command did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandResult {
+    /// Everything it wrote to stdout
+    pub stdout: String,
+    /// Everything it wrote to stderr
+    pub stderr: String,
+    /// How it exited
+    pub exit_code: i32,
+}
+
+impl CommandResult {
+    /// A successful run which printed `stdout`
+    pub fn ok(stdout: &str) -> Self {
+        Self { stdout: stdout.to_owned(), ..Self::default() }
+    }
+
+    /// A failed run which exited with `exit_code` after printing `stderr`
+    pub fn failed(exit_code: i32, stderr: &str) -> Self {
+        Self { stderr: stderr.to_owned(), exit_code, ..Self::default() }
+    }
+}
+
+/// Something which can answer for external commands in place of running them
+pub trait MockCommandRunner {
+    /// Pretend to run `cmd` (the program as given to `Command::new`) with `args`
+    fn run(&self, cmd: &str, args: &[&str]) -> CommandResult;
+}
+
+thread_local! {
+    /// The runner answering for commands on this thread, if any
+    static MOCK_RUNNER: RefCell<Option<Box<dyn MockCommandRunner>>> = const { RefCell::new(None) };
+}
+
+/// Keeps a [`MockCommandRunner`] installed on the current thread until it's dropped
+#[must_use = "the runner is uninstalled as soon as the guard is dropped"]
+pub struct MockGuard(());
+
+impl Drop for MockGuard {
+    fn drop(&mut self) {
+        MOCK_RUNNER.with(|runner| runner.take());
+    }
+}
+
+/// Answer for every command run on this thread with `runner` until the guard is dropped
+pub fn install(runner: impl MockCommandRunner + 'static) -> MockGuard {
+    MOCK_RUNNER.with(|slot| *slot.borrow_mut() = Some(Box::new(runner)));
+    MockGuard(())
+}
+
+/// Ask the installed runner, if there is one, what running `cmd` did
+pub fn run(cmd: &Command) -> Option<Output> {
+    MOCK_RUNNER.with(|runner| {
+        let runner = runner.borrow();
+        let runner = runner.as_ref()?;
+        let program = cmd.get_program().to_string_lossy();
+        let args: Vec<String> = cmd.get_args().map(|arg| arg.to_string_lossy().into()).collect();
+        let result = runner.run(&program, &args.iter().map(String::as_str).collect::<Vec<_>>());
+        Some(Output {
+            status: ExitStatus::from_raw(result.exit_code << 8),
+            stdout: result.stdout.into_bytes(),
+            stderr: result.stderr.into_bytes(),
+        })
+    })
+}
+
+/// A [`MockCommandRunner`] which answers from a list of rules and records every call
+///
+/// Each rule is a glob (as in `package_patterns`) matched against the command line, with the
+/// program reduced to its file name (eg. `apt-mark showhold`). The first matching rule answers,
+/// and a command no rule matches fails the test.
+#[derive(Debug, Default)]
+pub struct ScriptedRunner {
+    /// The patterns and how commands matching them respond, in the order they're tried
+    rules: Vec<(String, CommandResult)>,
+    /// The command line of every call so far
+    calls: Rc<RefCell<Vec<String>>>,
+}
+
+impl ScriptedRunner {
+    /// Start with no rules, so any command fails the test
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Respond to commands matching `pattern` with `result`
+    pub fn on(mut self, pattern: &str, result: CommandResult) -> Self {
+        self.rules.push((pattern.to_owned(), result));
+        self
+    }
+
+    /// A handle to the log of calls, which stays usable once the runner is installed
+    pub fn calls(&self) -> Rc<RefCell<Vec<String>>> {
+        Rc::clone(&self.calls)
+    }
+}
+
+impl MockCommandRunner for ScriptedRunner {
+    fn run(&self, cmd: &str, args: &[&str]) -> CommandResult {
+        let program = Path::new(cmd).file_name().map_or(cmd.into(), |name| name.to_string_lossy());
+        let line = std::iter::once(&*program).chain(args.iter().copied()).collect::<Vec<_>>();
+        let line = line.join(" ");
+        self.calls.borrow_mut().push(line.clone());
+        match self.rules.iter().find(|(pattern, _)| glob_match(pattern, &line)) {
+            Some((_, result)) => result.clone(),
+            None => panic!("Unexpected command: {}", line),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Waiting for the network at boot, so `apt-get update` doesn't fail spuriously before it's up
+
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::log::log;
+
+/// Port to test connectivity on, since package mirrors increasingly only offer HTTPS
+const PROBE_PORT: u16 = 443;
+
+/// How long to wait for any single connection attempt
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to pause between rounds of connection attempts
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Default hosts to probe, matching the default Ubuntu mirrors
+pub const NETWORK_CHECK_HOSTS: &[&str] = &["archive.ubuntu.com", "security.ubuntu.com"];
+
+/// Repeatedly try to open a TCP connection to port 443 on any of `hosts` until one succeeds or
+/// `timeout` runs out
+///
+/// A failure to resolve a host name counts as a failed attempt, since DNS is usually the last
+/// thing to come up. An empty `hosts` list disables the check.
+pub fn wait_for_network(hosts: &[String], timeout: Duration) -> io::Result<()> {
+    if hosts.is_empty() {
+        return Ok(());
+    }
+    let deadline = Instant::now() + timeout;
+    let mut announced = false;
+    loop {
+        if hosts.iter().any(|host| can_connect(host)) {
+            return Ok(());
+        }
+        if Instant::now() + RETRY_INTERVAL >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "none of {} were reachable within {}s",
+                    hosts.join(", "),
+                    timeout.as_secs()
+                ),
+            ));
+        }
+        if !announced {
+            log!("Waiting up to {}s for the network to come up...", timeout.as_secs());
+            announced = true;
+        }
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// Check whether a TCP connection to [`PROBE_PORT`] on `host` can be established
+fn can_connect(host: &str) -> bool {
+    let Ok(addrs) = (host, PROBE_PORT).to_socket_addrs() else {
+        return false;
+    };
+    addrs.into_iter().any(|addr| TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).is_ok())
+}
@@ -0,0 +1,76 @@
+//! Desktop notifications for users who run this at login rather than only at boot
+
+use std::collections::BTreeMap;
+use std::env;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use crate::config::Config;
+use crate::error::{CalledProcessError, UpdateNvidiaError};
+use crate::log::log;
+use crate::output::format_version_diff;
+use crate::process::run_status;
+use crate::procfs::{find_desktop_session, SESSION_ENV_VARS};
+
+/// Summary line for the notification sent by [`notify_upgrade`]
+const NOTIFY_SUMMARY: &str = "NVIDIA driver updated";
+
+/// Tell the logged-in user, via `notify-send`, that the driver was upgraded
+///
+/// If this process doesn't have `DBUS_SESSION_BUS_ADDRESS` set (eg. because it was started by
+/// systemd), the variables are borrowed from a running desktop session and `notify-send` is run
+/// as that session's user so it's allowed to connect to the session bus.
+pub fn notify_upgrade(
+    config: &Config,
+    old_versions: &BTreeMap<String, String>,
+    new_versions: &BTreeMap<String, String>,
+) -> Result<(), UpdateNvidiaError> {
+    let mut cmd = Command::new(&config.notify_send_path);
+    cmd.args([
+        "--app-name=update_nvidia",
+        NOTIFY_SUMMARY,
+        &format_body(old_versions, new_versions),
+    ]);
+
+    match env::var_os("DBUS_SESSION_BUS_ADDRESS") {
+        // Set explicitly (along with `DISPLAY` and the rest), so they survive the environment
+        // being cleaned for subprocesses
+        Some(_) => {
+            for name in SESSION_ENV_VARS {
+                if let Some(value) = env::var_os(name) {
+                    cmd.env(name, value);
+                }
+            }
+        },
+        None => match find_desktop_session() {
+            Some(session) => {
+                cmd.envs(session.env).uid(session.uid).gid(session.gid);
+            },
+            None => {
+                log!("No desktop session found. Not sending a notification.");
+                return Ok(());
+            },
+        },
+    }
+
+    let (status, stderr) = run_status(&mut cmd)?;
+    if !status.success() {
+        return Err(UpdateNvidiaError::NotifySendFailed(CalledProcessError::new(
+            &cmd,
+            status,
+            Some(&stderr),
+        )));
+    }
+    Ok(())
+}
+
+/// List each package whose version changed, followed by a warning about the module reload
+fn format_body(
+    old_versions: &BTreeMap<String, String>,
+    new_versions: &BTreeMap<String, String>,
+) -> String {
+    format!(
+        "{}\nThe GPU may be briefly unavailable while the new kernel module loads.",
+        format_version_diff(old_versions, new_versions)
+    )
+}
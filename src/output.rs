@@ -0,0 +1,118 @@
+//! Reporting of end-of-run results for humans or machines
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use crate::json::Json;
+use crate::timing::Phase;
+
+/// What was done to make the upgraded driver take effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Nothing changed, so nothing needed to be done
+    None,
+    /// The kernel module was unloaded and reloaded
+    ModuleReload,
+    /// The module couldn't be unloaded, so a reboot was triggered
+    Reboot,
+}
+
+impl Action {
+    /// The identifier used for this action in machine-readable output
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::None => "none",
+            Action::ModuleReload => "module_reload",
+            Action::Reboot => "reboot",
+        }
+    }
+}
+
+/// Everything about a completed run that's worth reporting
+#[derive(Debug)]
+pub struct RunSummary {
+    /// Whether any eligible package was changed
+    pub upgraded: bool,
+    /// Eligible packages and their versions before the upgrade
+    pub old_versions: BTreeMap<String, String>,
+    /// Eligible packages and their versions after the upgrade
+    pub new_versions: BTreeMap<String, String>,
+    /// What was done to make the upgrade take effect
+    pub action: Action,
+    /// Whether a reboot is still needed to load the upgraded module (`--no-module-reload`)
+    pub reboot_required: bool,
+    /// Wall-clock time the run took
+    pub elapsed: Duration,
+    /// Wall-clock time each of the slow steps took, in the order they ran
+    pub timings: Vec<(Phase, Duration)>,
+}
+
+impl RunSummary {
+    /// Render the summary as a single JSON object
+    pub fn to_json(&self) -> Json {
+        Json::object([
+            ("upgraded", self.upgraded.into()),
+            ("old_versions", (&self.old_versions).into()),
+            ("new_versions", (&self.new_versions).into()),
+            ("action", self.action.as_str().into()),
+            ("reboot_required", self.reboot_required.into()),
+            (
+                "elapsed_seconds",
+                Json::Integer(i64::try_from(self.elapsed.as_secs()).unwrap_or(i64::MAX)),
+            ),
+            (
+                "timings_ms",
+                Json::object(
+                    self.timings
+                        .iter()
+                        .map(|&(phase, elapsed)| (phase.as_str(), elapsed))
+                        .chain([("total", self.elapsed)])
+                        .map(|(name, elapsed)| {
+                            let millis = i64::try_from(elapsed.as_millis()).unwrap_or(i64::MAX);
+                            (name, Json::Integer(millis))
+                        }),
+                ),
+            ),
+        ])
+    }
+}
+
+/// List each package whose version differs between `old` and `new`, one per line
+///
+/// Changed packages are shown as `name: old → new`, and the rest are marked as added or removed.
+pub fn format_version_diff(
+    old: &BTreeMap<String, String>,
+    new: &BTreeMap<String, String>,
+) -> String {
+    let names: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    let mut lines = Vec::new();
+    for name in names {
+        match (old.get(name), new.get(name)) {
+            (Some(old_version), Some(new_version)) if old_version == new_version => {},
+            (Some(old_version), Some(new_version)) => {
+                lines.push(format!("{}: {} → {}", name, old_version, new_version));
+            },
+            (None, Some(new_version)) => lines.push(format!("{}: {} (added)", name, new_version)),
+            (Some(old_version), None) => lines.push(format!("{}: {} (removed)", name, old_version)),
+            (None, None) => unreachable!("every name came from one of the maps"),
+        }
+    }
+    lines.join("\n")
+}
+
+/// Where end-of-run results should be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputSink {
+    /// Progress has already been reported on stderr as it happened. Print nothing more.
+    Human,
+    /// Print a single JSON object to stdout (`--json`)
+    Json,
+}
+
+/// Report the results of a completed run through the selected sink
+pub fn log_event(sink: OutputSink, summary: &RunSummary) {
+    match sink {
+        OutputSink::Human => {},
+        OutputSink::Json => println!("{}", summary.to_json()),
+    }
+}
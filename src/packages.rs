@@ -0,0 +1,2075 @@
+//! Discovery, holding, and upgrading of the packages which take part in the update cycle
+//!
+//! Everything distro-specific lives behind [`PackageManager`] so the rest of the tool works the
+//! same on APT-based systems, on Fedora/RHEL systems using `dnf`, on openSUSE/SLES systems
+//! using `zypper`, and on Arch Linux using `pacman`.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::atomic::Ordering as AtomicOrdering;
+use std::time::SystemTime;
+
+use crate::config::Config;
+use crate::error::{CalledProcessError, UpdateNvidiaError};
+use crate::lock::wait_for_dpkg_lock;
+use crate::log::log;
+use crate::process::{
+    check_call, check_output, run_apt, run_output, run_status, AptOutput, DRY_RUN,
+};
+use crate::prompt::confirm_command;
+
+/// The package management backends [`detect`] can choose between
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageManagerKind {
+    /// `apt-get`, `apt-mark`, and `dpkg-query` (Debian, Ubuntu, etc.)
+    Apt,
+    /// `dnf` with the `versionlock` plugin, plus `rpm` (Fedora, RHEL, etc.)
+    Dnf,
+    /// `zypper` and its package locks, plus `rpm` (openSUSE, SLES)
+    Zypper,
+    /// `pacman`, with `IgnorePkg` entries in place of holds (Arch Linux)
+    Pacman,
+}
+
+impl PackageManagerKind {
+    /// The values accepted by `FromStr`
+    pub const NAMES: &'static [&'static str] = &["apt", "dnf", "zypper", "pacman"];
+
+    /// The name used for this backend in the config file and on the command line
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PackageManagerKind::Apt => "apt",
+            PackageManagerKind::Dnf => "dnf",
+            PackageManagerKind::Zypper => "zypper",
+            PackageManagerKind::Pacman => "pacman",
+        }
+    }
+}
+
+impl FromStr for PackageManagerKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "apt" => Ok(PackageManagerKind::Apt),
+            "dnf" => Ok(PackageManagerKind::Dnf),
+            "zypper" => Ok(PackageManagerKind::Zypper),
+            "pacman" => Ok(PackageManagerKind::Pacman),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What to do with the upgraded packages' "automatically installed" marks (`auto_mark_policy`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AutoMarkPolicy {
+    /// Leave the marks however the upgrade left them
+    DoNothing,
+    /// `apt-mark manual`, so cleanup (eg. by `unattended-upgrades`) never auto-removes them
+    MarkManual,
+    /// `apt-mark auto`, so they're removed once nothing depends on them
+    MarkAuto,
+}
+
+impl AutoMarkPolicy {
+    /// The values accepted by `FromStr`
+    pub const NAMES: &'static [&'static str] = &["do_nothing", "mark_manual", "mark_auto"];
+}
+
+impl FromStr for AutoMarkPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "do_nothing" => Ok(AutoMarkPolicy::DoNothing),
+            "mark_manual" => Ok(AutoMarkPolicy::MarkManual),
+            "mark_auto" => Ok(AutoMarkPolicy::MarkAuto),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Which of NVIDIA's kernel modules the installed driver uses (`driver_variant`)
+///
+/// Both are loaded as `nvidia`, but the open-source one comes from differently named packages
+/// (eg. `nvidia-open-575` or `nvidia-dkms-575-open`) and DKMS module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvidiaVariant {
+    /// The closed-source module NVIDIA has always shipped
+    Proprietary,
+    /// NVIDIA's open-source kernel module (`nvidia-open`)
+    Open,
+}
+
+impl NvidiaVariant {
+    /// The values accepted by `FromStr`
+    pub const NAMES: &'static [&'static str] = &["prop", "open"];
+
+    /// The name used for this variant in the config file and on the command line
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NvidiaVariant::Proprietary => "prop",
+            NvidiaVariant::Open => "open",
+        }
+    }
+
+    /// Guess the variant from the installed packages, assuming the proprietary module unless one
+    /// of them is for the open one
+    pub fn detect<'a>(names: impl IntoIterator<Item = &'a String>) -> Self {
+        if names.into_iter().any(|name| is_open_module_package(name)) {
+            NvidiaVariant::Open
+        } else {
+            NvidiaVariant::Proprietary
+        }
+    }
+
+    /// Whether `name` belongs to this variant's driver, rather than only the other one's
+    ///
+    /// The userspace packages (eg. `libnvidia-gl-575`) are shared, so only the kernel module
+    /// packages differ.
+    pub fn includes(self, name: &str) -> bool {
+        let is_open = is_open_module_package(name);
+        match self {
+            NvidiaVariant::Proprietary => !is_open,
+            NvidiaVariant::Open => {
+                is_open
+                    || !["dkms", "kernel-source", "linux-modules"].iter().any(|s| name.contains(s))
+            },
+        }
+    }
+
+    /// The names DKMS may know this variant's module by, in the order to try them
+    pub fn dkms_module_names(self, kmod_name: &str) -> Vec<String> {
+        match self {
+            NvidiaVariant::Proprietary => vec![kmod_name.to_owned()],
+            NvidiaVariant::Open => vec![format!("{}-open", kmod_name), kmod_name.to_owned()],
+        }
+    }
+}
+
+impl FromStr for NvidiaVariant {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prop" => Ok(NvidiaVariant::Proprietary),
+            "open" => Ok(NvidiaVariant::Open),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Check whether `name` is a package for the open-source kernel module (eg. `nvidia-open-575`,
+/// `nvidia-dkms-575-open`, or `linux-modules-nvidia-575-open-generic`)
+fn is_open_module_package(name: &str) -> bool {
+    name.split('-').any(|part| part == "open")
+}
+
+/// The variant set with `driver_variant`, or else the one detected from `packages`
+pub fn driver_variant(config: &Config, packages: &BTreeMap<String, String>) -> NvidiaVariant {
+    config.driver_variant.unwrap_or_else(|| NvidiaVariant::detect(packages.keys()))
+}
+
+/// The operations the update cycle needs from the system's package manager
+pub trait PackageManager {
+    /// Which backend this is
+    fn kind(&self) -> PackageManagerKind;
+
+    /// Retrieve a map from installed packages matching `config.package_patterns` (and not
+    /// `config.exclude_patterns`) to their version strings
+    fn get_nvidia_packages(&self) -> Result<BTreeMap<String, String>, UpdateNvidiaError>;
+
+    /// Retrieve a map from installed packages matching `pattern` (and not
+    /// `config.exclude_patterns`) to their version strings
+    fn get_packages_matching(
+        &self,
+        pattern: &str,
+    ) -> Result<BTreeMap<String, String>, UpdateNvidiaError>;
+
+    /// Retrieve the names of the eligible packages which are currently held back from upgrades
+    fn get_held_nvidia_packages(&self) -> Result<BTreeSet<String>, UpdateNvidiaError>;
+
+    /// Retrieve the subset of `names` which is currently held back from upgrades, in order
+    fn get_held_packages(&self, names: &[String]) -> Result<Vec<String>, UpdateNvidiaError>;
+
+    /// Prevent the given packages from being upgraded by normal system updates
+    fn hold_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError>;
+
+    /// Allow the given packages to be upgraded again
+    fn unhold_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError>;
+
+    /// Retrieve the names of the eligible packages which are marked as automatically installed
+    fn get_auto_installed_nvidia_packages(&self) -> Result<BTreeSet<String>, UpdateNvidiaError>;
+
+    /// Mark the given packages as automatically installed (if `auto`) or manually installed
+    fn mark_auto_installed(&self, names: &[String], auto: bool) -> Result<(), UpdateNvidiaError>;
+
+    /// Refresh the package index if it's stale
+    fn update_index(&self) -> Result<(), UpdateNvidiaError>;
+
+    /// Apply every pending upgrade for packages which aren't held
+    fn upgrade_packages(&self) -> Result<(), UpdateNvidiaError>;
+
+    /// Try to repair packages left broken or half-configured by a failed upgrade, returning
+    /// whether anything was changed
+    fn fix_broken_packages(&self) -> Result<bool, UpdateNvidiaError>;
+
+    /// Install the newest available version of each package
+    fn install_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError>;
+
+    /// Install exactly the given version of each package, downgrading if necessary
+    fn install_versions(
+        &self,
+        versions: &BTreeMap<String, String>,
+    ) -> Result<(), UpdateNvidiaError>;
+
+    /// The directory downloaded packages are stored in while they're being installed
+    fn download_dir(&self) -> &'static Path;
+
+    /// Delete the downloaded packages from [`PackageManager::download_dir`]
+    fn clean_download_cache(&self) -> Result<(), UpdateNvidiaError>;
+
+    /// Ask the package manager what an upgrade (ignoring holds) would change without changing it
+    fn simulate_upgrade(&self) -> Result<Vec<PackageChange>, UpdateNvidiaError>;
+
+    /// The newest version an upgrade would install for any of `names`, if there is one
+    fn candidate_version(&self, names: &[String]) -> Result<Option<String>, UpdateNvidiaError>;
+}
+
+/// A single package an upgrade would install or replace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageChange {
+    /// Package name
+    pub name: String,
+    /// Currently installed version, or `None` if the package would be newly installed
+    pub old_version: Option<String>,
+    /// Version that would be installed
+    pub new_version: String,
+}
+
+/// Split `changes` into maps of the versions before and after, for [`format_version_diff`]
+///
+/// Packages which aren't installed yet only appear in the second map.
+///
+/// [`format_version_diff`]: crate::output::format_version_diff
+pub fn version_maps<'a>(
+    changes: impl IntoIterator<Item = &'a PackageChange>,
+) -> (BTreeMap<String, String>, BTreeMap<String, String>) {
+    let (mut old, mut new) = (BTreeMap::new(), BTreeMap::new());
+    for change in changes {
+        if let Some(old_version) = &change.old_version {
+            old.insert(change.name.clone(), old_version.clone());
+        }
+        new.insert(change.name.clone(), change.new_version.clone());
+    }
+    (old, new)
+}
+
+/// Construct the backend selected by `config.package_manager` or, if that's unset, the first one
+/// whose main binary exists (preferring APT)
+pub fn detect(config: &Config) -> Box<dyn PackageManager + '_> {
+    let kind = config.package_manager.unwrap_or_else(|| {
+        if config.apt_get_path.exists() {
+            PackageManagerKind::Apt
+        } else if config.dnf_path.exists() {
+            PackageManagerKind::Dnf
+        } else if config.zypper_path.exists() {
+            PackageManagerKind::Zypper
+        } else if config.pacman_path.exists() {
+            PackageManagerKind::Pacman
+        } else {
+            // Even if none of them exist, this gets a clear "apt-get is missing" error at startup
+            PackageManagerKind::Apt
+        }
+    });
+    match kind {
+        PackageManagerKind::Apt => {
+            Box::new(AptPackageManager { config, hold_cache: HoldCache::default() })
+        },
+        PackageManagerKind::Dnf => Box::new(DnfPackageManager { config }),
+        PackageManagerKind::Zypper => Box::new(ZypperPackageManager { config }),
+        PackageManagerKind::Pacman => Box::new(PacmanPackageManager { config }),
+    }
+}
+
+/// Check whether `option` is an APT configuration assignment of the form `Key::SubKey=Value`
+/// (as passed to `apt-get -o`), so `apt_extra_options` can't smuggle in other arguments
+///
+/// A trailing `::` (as in `Dpkg::Options::=--force-confold`) appends to a list and is allowed.
+pub fn is_apt_option(option: &str) -> bool {
+    let Some((key, value)) = option.split_once('=') else {
+        return false;
+    };
+    let key = key.strip_suffix("::").unwrap_or(key);
+    let is_word = |part: &str| {
+        part.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    };
+    key.contains("::") && key.split("::").all(is_word) && !value.contains(char::is_control)
+}
+
+/// Turn `apt_options` (`-o Key::SubKey=Value` pairs, or `-oKey::SubKey=Value`) into the
+/// assignments themselves, checking each one with [`is_apt_option`]
+///
+/// Anything else (eg. `--allow-unauthenticated`) is refused, so this can't pass arbitrary
+/// arguments to `apt-get` either.
+pub fn parse_apt_option_args(args: &[String]) -> Result<Vec<String>, String> {
+    let mut options = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let option = match arg.strip_prefix("-o") {
+            Some("") => args.next().ok_or("-o must be followed by Key::SubKey=Value")?,
+            Some(option) => option,
+            None => return Err(format!("only -o Key::SubKey=Value is allowed, not {:?}", arg)),
+        };
+        if !is_apt_option(option) {
+            return Err(format!("-o must be followed by Key::SubKey=Value, not {:?}", option));
+        }
+        options.push(option.to_owned());
+    }
+    Ok(options)
+}
+
+/// Check whether a package name is selected by `config.package_patterns` and neither excluded,
+/// listed in `config.ignore_packages`, nor only part of the driver variant `driver_variant`
+/// rules out
+pub fn is_eligible(config: &Config, name: &str) -> bool {
+    config.package_patterns.iter().any(|pat| glob_match(pat, name))
+        && !is_excluded(config, name)
+        && !config.ignore_packages.iter().any(|ignored| ignored == name)
+        && config.driver_variant.is_none_or(|variant| variant.includes(name))
+}
+
+/// Check whether a package name is matched by `config.exclude_patterns`
+fn is_excluded(config: &Config, name: &str) -> bool {
+    config.exclude_patterns.iter().any(|pat| glob_match(pat, name))
+}
+
+/// Parse `name version` pairs (one per line) from a query command's stdout
+fn parse_name_version_pairs(stdout: &str) -> BTreeMap<String, String> {
+    let mut results = BTreeMap::new();
+    for line in stdout.split('\n') {
+        let mut fields = line.split_whitespace();
+        if let (Some(pkgname), Some(pkgver)) = (fields.next(), fields.next()) {
+            results.insert(pkgname.to_owned(), pkgver.to_owned());
+        }
+    }
+    results
+}
+
+/// The `dpkg-query --showformat` that produces `<want> <flag> <status> name version` lines
+///
+/// Unlike the table `dpkg-query --list` prints, this never truncates long names or versions to
+/// fit a column.
+const DPKG_SHOW_FORMAT: &str = "${Status} ${Package} ${Version}\n";
+
+/// Parse `dpkg-query --show` output in [`DPKG_SHOW_FORMAT`], keeping only packages which are
+/// installed and meant to stay that way (`install ok installed` or `hold ok installed`)
+///
+/// Any `:<arch>` qualifiers are dropped from the names for consistency with `apt-mark` and the
+/// patterns. If a multi-arch package is installed for more than one architecture, the entries
+/// merge and the first-listed version is kept.
+fn parse_dpkg_show(stdout: &str) -> BTreeMap<String, String> {
+    let mut results = BTreeMap::new();
+    for line in stdout.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [want, _flag, status, name, version] = fields[..] else {
+            continue;
+        };
+        if matches!(want, "install" | "hold") && status == "installed" {
+            results.entry(strip_arch_suffix(name).to_owned()).or_insert_with(|| version.to_owned());
+        }
+    }
+    results
+}
+
+/// Remove a Debian `:<arch>` qualifier (eg. `:amd64` or `:i386`) from a package name
+///
+/// Debian package names can't contain colons, so anything after one is the architecture.
+fn strip_arch_suffix(name: &str) -> &str {
+    name.split_once(':').map_or(name, |(name, _arch)| name)
+}
+
+/// The names of the held packages, remembered from one query until it may have gone stale
+///
+/// A run checks the holds several times (before unholding, when re-holding, when verifying), but
+/// only holding, unholding, and `apt-get` itself can change them in the meantime.
+#[derive(Debug, Default)]
+struct HoldCache {
+    /// The result of the last query, or `None` if there hasn't been one since the last change
+    held: RefCell<Option<BTreeSet<String>>>,
+}
+
+impl HoldCache {
+    /// Return the cached holds, calling `query` to fill the cache first if it's empty
+    fn get_or_query(
+        &self,
+        query: impl FnOnce() -> Result<BTreeSet<String>, UpdateNvidiaError>,
+    ) -> Result<BTreeSet<String>, UpdateNvidiaError> {
+        if let Some(held) = &*self.held.borrow() {
+            return Ok(held.clone());
+        }
+        let held = query()?;
+        *self.held.borrow_mut() = Some(held.clone());
+        Ok(held)
+    }
+
+    /// Forget the cached holds, so the next lookup queries them again
+    fn invalidate(&self) {
+        self.held.take();
+    }
+}
+
+/// The original Debian-family backend
+pub struct AptPackageManager<'a> {
+    /// Source of the `apt-get`, `apt-mark`, and `dpkg-query` paths and the package patterns
+    config: &'a Config,
+    /// `apt-mark showhold`'s output, so it only runs again once something may have changed it
+    hold_cache: HoldCache,
+}
+
+impl AptPackageManager<'_> {
+    /// Start an `apt-get` command line, with any configured proxies, `sources_list`,
+    /// `apt_extra_options`, and `apt_options` passed in explicitly
+    ///
+    /// The `proxy`/`https_proxy` config keys take precedence over the `HTTP_PROXY` and
+    /// `HTTPS_PROXY` environment variables, since a boot-time service's environment is easy to
+    /// get wrong.
+    fn apt_get(&self) -> Command {
+        let mut cmd = Command::new(&self.config.apt_get_path);
+        let proxies = [
+            ("http", self.config.proxy.clone().or_else(|| env::var("HTTP_PROXY").ok())),
+            ("https", self.config.https_proxy.clone().or_else(|| env::var("HTTPS_PROXY").ok())),
+        ];
+        for (scheme, proxy) in proxies {
+            if let Some(url) = proxy.filter(|url| !url.is_empty()) {
+                cmd.arg("-o").arg(format!("Acquire::{}::Proxy={}", scheme, url));
+            }
+        }
+        if let Some(path) = &self.config.sources_list {
+            // Ignore `sources.list.d` too, and keep the index files for the sources left out so
+            // the next regular `apt-get update` doesn't have to download them all again
+            let mut dir_option = OsString::from("Dir::Etc::sourcelist=");
+            dir_option.push(path);
+            cmd.arg("-o").arg(dir_option);
+            cmd.args(["-o", "Dir::Etc::sourceparts=-", "-o", "APT::Get::List-Cleanup=0"]);
+        }
+        for option in &self.config.apt_extra_options {
+            cmd.arg("-o").arg(option);
+        }
+        cmd
+    }
+
+    /// Run an `apt-get` command via [`run_apt`], turning a non-success exit into an error
+    fn apt_get_call(&self, cmd: &mut Command) -> Result<AptOutput, UpdateNvidiaError> {
+        // Removing a held package (eg. as part of a dist-upgrade) also drops its hold
+        self.hold_cache.invalidate();
+        if !DRY_RUN.load(AtomicOrdering::Relaxed) {
+            wait_for_dpkg_lock(self.config.dpkg_lock_timeout)?;
+        }
+        let output = run_apt(cmd)?;
+        if !output.status.success() {
+            return Err(UpdateNvidiaError::AptGetFailed(CalledProcessError::new(
+                cmd,
+                output.status,
+                Some(&output.stderr),
+            )));
+        }
+        Ok(output)
+    }
+
+    /// Run `apt-get update`, warning if some sources couldn't be refreshed
+    fn apt_get_update(&self) -> Result<(), UpdateNvidiaError> {
+        let output = self.apt_get_call(self.apt_get().arg("update"))?;
+        // apt-get still succeeds if only some sources failed, but the index won't be retried
+        // until it's stale again, so make sure nobody mistakes this for a full refresh
+        if output.stderr.contains("Some index files failed to download") {
+            log!(
+                "WARNING: Some package sources could not be refreshed, so newer NVIDIA packages \
+                 may have been missed."
+            );
+        }
+        Ok(())
+    }
+
+    /// Retrieve the names of every held package, eligible or not, from `apt-mark showhold`
+    ///
+    /// Goes through the [`HoldCache`], so it's only run once between changes to the holds.
+    fn query_held(&self) -> Result<BTreeSet<String>, UpdateNvidiaError> {
+        self.hold_cache.get_or_query(|| {
+            let stdout = check_output!(
+                Command::new(&self.config.apt_mark_path).arg("showhold"),
+                UpdateNvidiaError::AptMarkFailed
+            )?;
+            Ok(stdout.lines().map(strip_arch_suffix).map(str::to_owned).collect())
+        })
+    }
+
+    /// Run `apt-mark <operation>` on `names`, at most `chunk_size` at a time
+    ///
+    /// Every name normally fits in one call, but splitting them up keeps a huge list (eg. from
+    /// broad `hold_patterns` on a CUDA development machine) under the kernel's `ARG_MAX` limit.
+    /// If a chunk fails, the ones after it aren't attempted.
+    fn batch_apt_mark(
+        &self,
+        operation: &str,
+        names: &[String],
+        chunk_size: usize,
+    ) -> Result<(), UpdateNvidiaError> {
+        for chunk in names.chunks(chunk_size.max(1)) {
+            let mut cmd = Command::new(&self.config.apt_mark_path);
+            cmd.arg(operation).arg("-qq").args(chunk);
+            if operation == "unhold" {
+                confirm_command(&cmd)?;
+            }
+            check_call!(cmd, UpdateNvidiaError::AptMarkFailed)?;
+        }
+        Ok(())
+    }
+
+    /// The `apt-get` subcommand which applies upgrades, as chosen by `no_dist_upgrade`
+    fn upgrade_subcommand(&self) -> &'static str {
+        if self.config.no_dist_upgrade {
+            "upgrade"
+        } else {
+            "dist-upgrade"
+        }
+    }
+
+    /// Retrieve a map from installed packages matching one `dpkg-query` pattern to their versions
+    fn query_installed(
+        &self,
+        pattern: &str,
+    ) -> Result<BTreeMap<String, String>, UpdateNvidiaError> {
+        // Use the fastest of the choices I found. No need to gratuitously extend boot times
+        let mut cmd = Command::new(&self.config.dpkg_query_path);
+        cmd.arg("--show").arg(format!("--showformat={}", DPKG_SHOW_FORMAT)).arg(pattern);
+        let cmd_result = run_output(&mut cmd)?;
+
+        if !cmd_result.status.success() {
+            // dpkg-query exits with 1 when nothing matches, which isn't an error for our purposes
+            let stderr = String::from_utf8_lossy(&cmd_result.stderr);
+            if cmd_result.status.code() == Some(1) && stderr.contains("no packages found matching")
+            {
+                return Ok(BTreeMap::new());
+            }
+            return Err(UpdateNvidiaError::PackageQueryFailed(CalledProcessError::from_output(
+                &cmd,
+                &cmd_result,
+            )));
+        }
+        Ok(parse_dpkg_show(&String::from_utf8(cmd_result.stdout)?))
+    }
+}
+
+impl PackageManager for AptPackageManager<'_> {
+    fn kind(&self) -> PackageManagerKind {
+        PackageManagerKind::Apt
+    }
+
+    fn get_nvidia_packages(&self) -> Result<BTreeMap<String, String>, UpdateNvidiaError> {
+        let mut results = BTreeMap::new();
+        for pattern in &self.config.package_patterns {
+            results.extend(self.query_installed(pattern)?);
+        }
+        results.retain(|name, _| is_eligible(self.config, name));
+        Ok(results)
+    }
+
+    fn get_packages_matching(
+        &self,
+        pattern: &str,
+    ) -> Result<BTreeMap<String, String>, UpdateNvidiaError> {
+        let mut results = self.query_installed(pattern)?;
+        results.retain(|name, _| !is_excluded(self.config, name));
+        Ok(results)
+    }
+
+    fn get_held_nvidia_packages(&self) -> Result<BTreeSet<String>, UpdateNvidiaError> {
+        let held = self.query_held()?;
+        Ok(held.into_iter().filter(|name| is_eligible(self.config, name)).collect())
+    }
+
+    fn get_held_packages(&self, names: &[String]) -> Result<Vec<String>, UpdateNvidiaError> {
+        let held = self.query_held()?;
+        Ok(names.iter().filter(|name| held.contains(*name)).cloned().collect())
+    }
+
+    fn hold_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError> {
+        self.hold_cache.invalidate();
+        self.batch_apt_mark("hold", names, self.config.apt_mark_chunk_size)
+    }
+
+    fn unhold_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError> {
+        self.hold_cache.invalidate();
+        self.batch_apt_mark("unhold", names, self.config.apt_mark_chunk_size)
+    }
+
+    fn get_auto_installed_nvidia_packages(&self) -> Result<BTreeSet<String>, UpdateNvidiaError> {
+        let stdout = check_output!(
+            Command::new(&self.config.apt_mark_path).arg("showauto"),
+            UpdateNvidiaError::AptMarkFailed
+        )?;
+        Ok(stdout
+            .lines()
+            .map(strip_arch_suffix)
+            .filter(|name| is_eligible(self.config, name))
+            .map(str::to_owned)
+            .collect())
+    }
+
+    fn mark_auto_installed(&self, names: &[String], auto: bool) -> Result<(), UpdateNvidiaError> {
+        let mark = if auto { "auto" } else { "manual" };
+        self.batch_apt_mark(mark, names, self.config.apt_mark_chunk_size)
+    }
+
+    /// Run `apt-get update` if the package index is stale (or `force_update` is set)
+    fn update_index(&self) -> Result<(), UpdateNvidiaError> {
+        let config = self.config;
+        if config.force_update {
+            log!("Updating the package index regardless of its age...");
+            return self.apt_get_update();
+        }
+
+        // Retrieve the mtime of the configured APT_UPDATE_MTIME_PATH.
+        // If we can't for some reason, report the failure and assume maximum staleness.
+        let stat = std::fs::metadata(&config.apt_update_mtime_path);
+        if let Err(e) = &stat {
+            log!("ERROR: Could not stat {}. ({:?})", config.apt_update_mtime_path.display(), e);
+        }
+        let last_update = stat.and_then(|stat| stat.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+
+        if SystemTime::now().duration_since(last_update)?.as_secs() > config.apt_update_interval {
+            log!("Package index is stale. Updating...");
+            self.apt_get_update()
+        } else {
+            log!("Package index is sufficiently fresh.");
+            Ok(())
+        }
+    }
+
+    fn upgrade_packages(&self) -> Result<(), UpdateNvidiaError> {
+        // Not the best solution, but quick and generally works
+        let mut cmd = self.apt_get();
+        cmd.arg(self.upgrade_subcommand()).arg("-y");
+        confirm_command(&cmd)?;
+        let output = self.apt_get_call(&mut cmd)?;
+        let kept_back: Vec<&str> = parse_apt_kept_back(&output.stdout)
+            .into_iter()
+            .filter(|name| is_eligible(self.config, name))
+            .collect();
+        if !kept_back.is_empty() {
+            log!(
+                "WARNING: apt-get {} kept back these packages: {}",
+                self.upgrade_subcommand(),
+                kept_back.join(" ")
+            );
+        }
+        Ok(())
+    }
+
+    fn fix_broken_packages(&self) -> Result<bool, UpdateNvidiaError> {
+        let output = self.apt_get_call(self.apt_get().arg("-f").arg("install").arg("-y"))?;
+        Ok(apt_made_changes(&output.stdout))
+    }
+
+    fn install_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError> {
+        self.apt_get_call(self.apt_get().arg("install").arg("-y").args(names))?;
+        Ok(())
+    }
+
+    fn install_versions(
+        &self,
+        versions: &BTreeMap<String, String>,
+    ) -> Result<(), UpdateNvidiaError> {
+        let specs = versions.iter().map(|(name, version)| format!("{}={}", name, version));
+        self.apt_get_call(
+            self.apt_get().arg("install").arg("-y").arg("--allow-downgrades").args(specs),
+        )?;
+        Ok(())
+    }
+
+    fn download_dir(&self) -> &'static Path {
+        Path::new("/var/cache/apt/archives")
+    }
+
+    fn clean_download_cache(&self) -> Result<(), UpdateNvidiaError> {
+        self.apt_get_call(self.apt_get().arg("clean"))?;
+        Ok(())
+    }
+
+    fn candidate_version(&self, names: &[String]) -> Result<Option<String>, UpdateNvidiaError> {
+        if names.is_empty() {
+            return Ok(None);
+        }
+        let stdout = check_output!(
+            Command::new(&self.config.apt_cache_path).arg("policy").args(names),
+            UpdateNvidiaError::AptCacheFailed
+        )?;
+        Ok(parse_apt_cache_policy(&stdout))
+    }
+
+    fn simulate_upgrade(&self) -> Result<Vec<PackageChange>, UpdateNvidiaError> {
+        // --ignore-hold, or the packages this tool cares about would never show up
+        let stdout = check_output!(
+            self.apt_get().arg(self.upgrade_subcommand()).arg("-s").arg("-y").arg("--ignore-hold"),
+            UpdateNvidiaError::AptGetFailed
+        )?;
+        Ok(parse_apt_simulate_output(&stdout))
+    }
+}
+
+/// The `rpm --queryformat` that produces `name [epoch:]version-release` lines
+const RPM_QUERY_FORMAT: &str = "%{NAME} %|EPOCH?{%{EPOCH}:}:{}|%{VERSION}-%{RELEASE}\\n";
+
+/// Retrieve a map from installed packages matching any of the `rpm -qa` patterns to their
+/// versions (for the RPM-based backends)
+fn rpm_query_all(
+    config: &Config,
+    patterns: &[impl AsRef<std::ffi::OsStr>],
+) -> Result<BTreeMap<String, String>, UpdateNvidiaError> {
+    let stdout = check_output!(
+        Command::new(&config.rpm_path)
+            .arg("--query")
+            .arg("--all")
+            .arg("--queryformat")
+            .arg(RPM_QUERY_FORMAT)
+            .args(patterns),
+        UpdateNvidiaError::RpmFailed
+    )?;
+    Ok(parse_name_version_pairs(&stdout))
+}
+
+/// Look up the installed versions of specific packages via `rpm`, omitting any which aren't
+/// installed
+fn rpm_installed_versions<'n>(
+    config: &Config,
+    names: impl IntoIterator<Item = &'n str>,
+) -> Result<BTreeMap<String, String>, UpdateNvidiaError> {
+    let names: BTreeSet<&str> = names.into_iter().collect();
+    let mut cmd = Command::new(&config.rpm_path);
+    let cmd_result =
+        run_output(cmd.arg("--query").arg("--queryformat").arg(RPM_QUERY_FORMAT).args(&names))?;
+    // rpm's exit code is the number of packages that weren't installed, and it reports them as
+    // `package <name> is not installed` on stdout, so neither can be taken at face value
+    let mut results = parse_name_version_pairs(&String::from_utf8(cmd_result.stdout)?);
+    results.retain(|name, _| names.contains(name.as_str()));
+    Ok(results)
+}
+
+/// Fedora/RHEL backend, using `dnf versionlock` in place of `apt-mark hold`
+///
+/// Requires the `versionlock` plugin (`python3-dnf-plugin-versionlock`) to be installed.
+pub struct DnfPackageManager<'a> {
+    /// Source of the `dnf` and `rpm` paths and the package patterns
+    config: &'a Config,
+}
+
+impl DnfPackageManager<'_> {
+    /// Retrieve the names of every version-locked package, eligible or not
+    fn query_held(&self) -> Result<BTreeSet<String>, UpdateNvidiaError> {
+        let stdout = check_output!(
+            Command::new(&self.config.dnf_path).arg("versionlock").arg("list").arg("--quiet"),
+            UpdateNvidiaError::DnfFailed
+        )?;
+        // Entries look like `name-epoch:version-release.*`, and names may contain hyphens
+        Ok(stdout
+            .lines()
+            .filter_map(|entry| entry.rsplit_once(':')?.0.rsplit_once('-'))
+            .map(|(name, _epoch)| name.to_owned())
+            .collect())
+    }
+}
+
+impl PackageManager for DnfPackageManager<'_> {
+    fn kind(&self) -> PackageManagerKind {
+        PackageManagerKind::Dnf
+    }
+
+    fn get_nvidia_packages(&self) -> Result<BTreeMap<String, String>, UpdateNvidiaError> {
+        let mut results = rpm_query_all(self.config, &self.config.package_patterns)?;
+        results.retain(|name, _| is_eligible(self.config, name));
+        Ok(results)
+    }
+
+    fn get_packages_matching(
+        &self,
+        pattern: &str,
+    ) -> Result<BTreeMap<String, String>, UpdateNvidiaError> {
+        let mut results = rpm_query_all(self.config, &[pattern])?;
+        results.retain(|name, _| !is_excluded(self.config, name));
+        Ok(results)
+    }
+
+    fn get_held_nvidia_packages(&self) -> Result<BTreeSet<String>, UpdateNvidiaError> {
+        let held = self.query_held()?;
+        Ok(held.into_iter().filter(|name| is_eligible(self.config, name)).collect())
+    }
+
+    fn get_held_packages(&self, names: &[String]) -> Result<Vec<String>, UpdateNvidiaError> {
+        let held = self.query_held()?;
+        Ok(names.iter().filter(|name| held.contains(*name)).cloned().collect())
+    }
+
+    fn hold_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError> {
+        check_call!(
+            Command::new(&self.config.dnf_path).arg("versionlock").arg("add").arg("-q").args(names),
+            UpdateNvidiaError::DnfFailed
+        )?;
+        Ok(())
+    }
+
+    fn unhold_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError> {
+        let mut cmd = Command::new(&self.config.dnf_path);
+        cmd.arg("versionlock").arg("delete").arg("-q").args(names);
+        confirm_command(&cmd)?;
+        check_call!(cmd, UpdateNvidiaError::DnfFailed)?;
+        Ok(())
+    }
+
+    /// Run `dnf check-update`, which refreshes any metadata older than dnf's own expiry setting
+    fn update_index(&self) -> Result<(), UpdateNvidiaError> {
+        log!("Refreshing package metadata...");
+        let mut cmd = Command::new(&self.config.dnf_path);
+        let (status, stderr) = run_status(cmd.arg("check-update").arg("-q"))?;
+        // check-update exits with 100 to signal that updates are available
+        if !matches!(status.code(), Some(0 | 100)) {
+            return Err(UpdateNvidiaError::DnfFailed(CalledProcessError::new(
+                &cmd,
+                status,
+                Some(&stderr),
+            )));
+        }
+        Ok(())
+    }
+
+    fn upgrade_packages(&self) -> Result<(), UpdateNvidiaError> {
+        let mut cmd = Command::new(&self.config.dnf_path);
+        cmd.arg("upgrade").arg("-y");
+        confirm_command(&cmd)?;
+        check_call!(cmd, UpdateNvidiaError::DnfFailed)?;
+        Ok(())
+    }
+
+    fn fix_broken_packages(&self) -> Result<bool, UpdateNvidiaError> {
+        // rpm transactions don't leave packages half-configured the way an interrupted dpkg run
+        // does, so there's nothing to repair
+        Ok(false)
+    }
+
+    fn install_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError> {
+        check_call!(
+            Command::new(&self.config.dnf_path).arg("install").arg("-y").args(names),
+            UpdateNvidiaError::DnfFailed
+        )?;
+        Ok(())
+    }
+
+    fn install_versions(
+        &self,
+        versions: &BTreeMap<String, String>,
+    ) -> Result<(), UpdateNvidiaError> {
+        // `name-[epoch:]version-release` is one of the NEVRA forms dnf accepts
+        let specs = versions.iter().map(|(name, version)| format!("{}-{}", name, version));
+        check_call!(
+            Command::new(&self.config.dnf_path).arg("downgrade").arg("-y").args(specs),
+            UpdateNvidiaError::DnfFailed
+        )?;
+        Ok(())
+    }
+
+    fn download_dir(&self) -> &'static Path {
+        Path::new("/var/cache/dnf")
+    }
+
+    fn clean_download_cache(&self) -> Result<(), UpdateNvidiaError> {
+        // Leave the metadata, so the next run doesn't have to download it all again
+        check_call!(
+            Command::new(&self.config.dnf_path).arg("clean").arg("packages").arg("-q"),
+            UpdateNvidiaError::DnfFailed
+        )?;
+        Ok(())
+    }
+
+    fn simulate_upgrade(&self) -> Result<Vec<PackageChange>, UpdateNvidiaError> {
+        let mut cmd = Command::new(&self.config.dnf_path);
+        let cmd_result =
+            run_output(cmd.arg("check-update").arg("-q").arg("--disableplugin=versionlock"))?;
+        if !matches!(cmd_result.status.code(), Some(0 | 100)) {
+            return Err(UpdateNvidiaError::DnfFailed(CalledProcessError::from_output(
+                &cmd,
+                &cmd_result,
+            )));
+        }
+        let mut changes = parse_dnf_check_update_output(&String::from_utf8(cmd_result.stdout)?);
+
+        // check-update only reports new versions, so fill in the old ones from rpm
+        let installed =
+            rpm_installed_versions(self.config, changes.iter().map(|c| c.name.as_str()))?;
+        for change in &mut changes {
+            change.old_version = installed.get(&change.name).cloned();
+        }
+        Ok(changes)
+    }
+
+    fn candidate_version(&self, _names: &[String]) -> Result<Option<String>, UpdateNvidiaError> {
+        // RPM versions sort differently and aren't tied to the driver series the same way
+        Err(UpdateNvidiaError::Unsupported("--max-version is only supported with apt"))
+    }
+
+    fn get_auto_installed_nvidia_packages(&self) -> Result<BTreeSet<String>, UpdateNvidiaError> {
+        Err(UpdateNvidiaError::Unsupported("auto_mark_policy is only supported with apt"))
+    }
+
+    fn mark_auto_installed(&self, _names: &[String], _auto: bool) -> Result<(), UpdateNvidiaError> {
+        Err(UpdateNvidiaError::Unsupported("auto_mark_policy is only supported with apt"))
+    }
+}
+
+/// openSUSE/SLES backend, using `zypper addlock` in place of `apt-mark hold`
+pub struct ZypperPackageManager<'a> {
+    /// Source of the `zypper` and `rpm` paths and the package patterns
+    config: &'a Config,
+}
+
+impl ZypperPackageManager<'_> {
+    /// Start a `zypper` command line which will never stop to ask questions
+    fn zypper(&self) -> Command {
+        let mut cmd = Command::new(&self.config.zypper_path);
+        cmd.arg("--non-interactive");
+        cmd
+    }
+
+    /// Run a `zypper` command which alters the system, treating its informational exit codes
+    /// (eg. 102 for "reboot needed") as success
+    fn zypper_call(&self, cmd: &mut Command) -> Result<(), UpdateNvidiaError> {
+        let (status, stderr) = run_status(cmd)?;
+        if !is_zypper_success(status.code()) {
+            return Err(UpdateNvidiaError::ZypperFailed(CalledProcessError::new(
+                cmd,
+                status,
+                Some(&stderr),
+            )));
+        }
+        Ok(())
+    }
+
+    /// Retrieve the names of every locked package, eligible or not
+    fn query_held(&self) -> Result<BTreeSet<String>, UpdateNvidiaError> {
+        let mut cmd = self.zypper();
+        let cmd_result = run_output(cmd.arg("--quiet").arg("locks"))?;
+        if !is_zypper_success(cmd_result.status.code()) {
+            return Err(UpdateNvidiaError::ZypperFailed(CalledProcessError::from_output(
+                &cmd,
+                &cmd_result,
+            )));
+        }
+        Ok(parse_zypper_table(&String::from_utf8(cmd_result.stdout)?)
+            .iter()
+            .filter_map(|row| row.get("Name").copied())
+            .map(str::to_owned)
+            .collect())
+    }
+}
+
+/// Check whether a `zypper` exit code means it did what was asked
+///
+/// 100 to 103 report that updates are available or that a reboot or service restart is needed
+/// afterward, none of which are failures.
+fn is_zypper_success(code: Option<i32>) -> bool {
+    matches!(code, Some(0 | 100..=103))
+}
+
+impl PackageManager for ZypperPackageManager<'_> {
+    fn kind(&self) -> PackageManagerKind {
+        PackageManagerKind::Zypper
+    }
+
+    fn get_nvidia_packages(&self) -> Result<BTreeMap<String, String>, UpdateNvidiaError> {
+        let mut results = rpm_query_all(self.config, &self.config.package_patterns)?;
+        results.retain(|name, _| is_eligible(self.config, name));
+        Ok(results)
+    }
+
+    fn get_packages_matching(
+        &self,
+        pattern: &str,
+    ) -> Result<BTreeMap<String, String>, UpdateNvidiaError> {
+        let mut results = rpm_query_all(self.config, &[pattern])?;
+        results.retain(|name, _| !is_excluded(self.config, name));
+        Ok(results)
+    }
+
+    fn get_held_nvidia_packages(&self) -> Result<BTreeSet<String>, UpdateNvidiaError> {
+        let held = self.query_held()?;
+        Ok(held.into_iter().filter(|name| is_eligible(self.config, name)).collect())
+    }
+
+    fn get_held_packages(&self, names: &[String]) -> Result<Vec<String>, UpdateNvidiaError> {
+        let held = self.query_held()?;
+        Ok(names.iter().filter(|name| held.contains(*name)).cloned().collect())
+    }
+
+    fn hold_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError> {
+        self.zypper_call(self.zypper().arg("--quiet").arg("addlock").args(names))
+    }
+
+    fn unhold_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError> {
+        let mut cmd = self.zypper();
+        cmd.arg("--quiet").arg("removelock").args(names);
+        confirm_command(&cmd)?;
+        self.zypper_call(&mut cmd)
+    }
+
+    /// Run `zypper refresh`, which only downloads the metadata of repositories that changed
+    fn update_index(&self) -> Result<(), UpdateNvidiaError> {
+        log!("Refreshing package metadata...");
+        self.zypper_call(self.zypper().arg("refresh"))
+    }
+
+    fn upgrade_packages(&self) -> Result<(), UpdateNvidiaError> {
+        let mut cmd = self.zypper();
+        cmd.arg("update");
+        confirm_command(&cmd)?;
+        self.zypper_call(&mut cmd)
+    }
+
+    fn fix_broken_packages(&self) -> Result<bool, UpdateNvidiaError> {
+        // As with dnf, rpm transactions leave nothing half-configured to repair
+        Ok(false)
+    }
+
+    fn install_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError> {
+        self.zypper_call(self.zypper().arg("install").args(names))
+    }
+
+    fn install_versions(
+        &self,
+        versions: &BTreeMap<String, String>,
+    ) -> Result<(), UpdateNvidiaError> {
+        let specs = versions.iter().map(|(name, version)| format!("{}={}", name, version));
+        self.zypper_call(self.zypper().arg("install").arg("--oldpackage").args(specs))
+    }
+
+    fn download_dir(&self) -> &'static Path {
+        Path::new("/var/cache/zypp/packages")
+    }
+
+    fn clean_download_cache(&self) -> Result<(), UpdateNvidiaError> {
+        // Without --metadata or --all, this only removes the cached packages
+        self.zypper_call(self.zypper().arg("clean"))
+    }
+
+    fn simulate_upgrade(&self) -> Result<Vec<PackageChange>, UpdateNvidiaError> {
+        // --all includes updates the solver can't currently install, such as locked packages
+        let mut cmd = self.zypper();
+        let cmd_result = run_output(cmd.arg("--quiet").arg("list-updates").arg("--all"))?;
+        if !is_zypper_success(cmd_result.status.code()) {
+            return Err(UpdateNvidiaError::ZypperFailed(CalledProcessError::from_output(
+                &cmd,
+                &cmd_result,
+            )));
+        }
+        let stdout = String::from_utf8(cmd_result.stdout)?;
+        let mut changes: Vec<PackageChange> = parse_zypper_table(&stdout)
+            .iter()
+            .filter_map(|row| {
+                Some(PackageChange {
+                    name: row.get("Name")?.to_string(),
+                    old_version: None,
+                    new_version: row.get("Available Version")?.to_string(),
+                })
+            })
+            .collect();
+
+        // `Current Version` is missing from some zypper versions' output, so ask rpm instead
+        let installed =
+            rpm_installed_versions(self.config, changes.iter().map(|c| c.name.as_str()))?;
+        for change in &mut changes {
+            change.old_version = installed.get(&change.name).cloned();
+        }
+        Ok(changes)
+    }
+
+    fn candidate_version(&self, _names: &[String]) -> Result<Option<String>, UpdateNvidiaError> {
+        // RPM versions sort differently and aren't tied to the driver series the same way
+        Err(UpdateNvidiaError::Unsupported("--max-version is only supported with apt"))
+    }
+
+    fn get_auto_installed_nvidia_packages(&self) -> Result<BTreeSet<String>, UpdateNvidiaError> {
+        Err(UpdateNvidiaError::Unsupported("auto_mark_policy is only supported with apt"))
+    }
+
+    fn mark_auto_installed(&self, _names: &[String], _auto: bool) -> Result<(), UpdateNvidiaError> {
+        Err(UpdateNvidiaError::Unsupported("auto_mark_policy is only supported with apt"))
+    }
+}
+
+/// The config fragment [`PacmanPackageManager`] keeps its holds in, as an `IgnorePkg` entry
+///
+/// pacman only reads it if it's included from the `[options]` section of [`PACMAN_CONF_PATH`].
+const PACMAN_HOLDS_PATH: &str = "/etc/pacman.d/update_nvidia.conf";
+
+/// pacman's main config file, which has to include [`PACMAN_HOLDS_PATH`]
+const PACMAN_CONF_PATH: &str = "/etc/pacman.conf";
+
+/// Arch Linux backend, using `IgnorePkg` entries in place of `apt-mark hold`
+///
+/// pacman has no hold command, so holding or unholding a package rewrites the `IgnorePkg` line
+/// in [`PACMAN_HOLDS_PATH`] and leaves the rest of the pacman configuration alone.
+pub struct PacmanPackageManager<'a> {
+    /// Source of the `pacman` path and the package patterns
+    config: &'a Config,
+}
+
+impl PacmanPackageManager<'_> {
+    /// Run `pacman --query` with `args`, returning its stdout
+    ///
+    /// pacman exits with 1 when a query matches nothing, so that's only a failure if it also
+    /// printed something.
+    fn query(&self, args: &[&str]) -> Result<String, UpdateNvidiaError> {
+        let mut cmd = Command::new(&self.config.pacman_path);
+        let cmd_result = run_output(cmd.arg("--query").args(args))?;
+        let nothing_matched = cmd_result.status.code() == Some(1)
+            && cmd_result.stdout.iter().all(u8::is_ascii_whitespace)
+            && cmd_result.stderr.is_empty();
+        if !(cmd_result.status.success() || nothing_matched) {
+            return Err(UpdateNvidiaError::PacmanFailed(CalledProcessError::from_output(
+                &cmd,
+                &cmd_result,
+            )));
+        }
+        Ok(String::from_utf8(cmd_result.stdout)?)
+    }
+
+    /// Retrieve a map from the installed packages (or, with `--foreign`, just the ones which
+    /// aren't in a sync database) to their versions
+    fn query_installed(
+        &self,
+        args: &[&str],
+    ) -> Result<BTreeMap<String, String>, UpdateNvidiaError> {
+        Ok(parse_name_version_pairs(&self.query(args)?))
+    }
+
+    /// Retrieve the names of every package in the `IgnorePkg` line of [`PACMAN_HOLDS_PATH`]
+    fn query_held(&self) -> Result<BTreeSet<String>, UpdateNvidiaError> {
+        match fs::read_to_string(PACMAN_HOLDS_PATH) {
+            Ok(contents) => Ok(parse_pacman_ignore_pkg(&contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace the contents of [`PACMAN_HOLDS_PATH`] so exactly `held` are ignored, or just
+    /// report it in `--dry-run` mode
+    fn write_held(&self, held: &BTreeSet<String>) -> Result<(), UpdateNvidiaError> {
+        let mut contents = format!(
+            "# Written by update_nvidia, which lifts these holds only while it upgrades them.\n\
+             # pacman reads this if the [options] section of {} has:\n\
+             #     Include = {}\n",
+            PACMAN_CONF_PATH, PACMAN_HOLDS_PATH
+        );
+        if !held.is_empty() {
+            let names: Vec<&str> = held.iter().map(String::as_str).collect();
+            contents.push_str(&format!("IgnorePkg = {}\n", names.join(" ")));
+        }
+        if DRY_RUN.load(AtomicOrdering::Relaxed) {
+            log!("[DRY RUN] would write {}:\n{}", PACMAN_HOLDS_PATH, contents);
+            return Ok(());
+        }
+        fs::write(PACMAN_HOLDS_PATH, contents)?;
+        Ok(())
+    }
+}
+
+/// Check whether pacman's main config includes [`PACMAN_HOLDS_PATH`], without which the holds
+/// written there have no effect
+fn pacman_conf_includes_holds() -> bool {
+    fs::read_to_string(PACMAN_CONF_PATH).is_ok_and(|contents| {
+        contents.lines().any(|line| {
+            line.split_once('=').is_some_and(|(key, value)| {
+                key.trim() == "Include" && value.trim() == PACMAN_HOLDS_PATH
+            })
+        })
+    })
+}
+
+/// Find the file for version `version` of package `name` in pacman's package cache
+///
+/// Cached packages are named `name-version-arch.pkg.tar.<compression>`, with a `.sig` file
+/// alongside if the signature was downloaded separately.
+fn pacman_cached_package(
+    cache_dir: &Path,
+    name: &str,
+    version: &str,
+) -> Result<PathBuf, UpdateNvidiaError> {
+    let prefix = format!("{}-{}-", name, version);
+    for entry in fs::read_dir(cache_dir)? {
+        let path = entry?.path();
+        let Some(arch_and_ext) =
+            path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_prefix(&prefix))
+        else {
+            continue;
+        };
+        // The architecture can't contain a hyphen, so this rules out other packages' files
+        if !arch_and_ext.contains('-')
+            && arch_and_ext.contains(".pkg.tar")
+            && !arch_and_ext.ends_with(".sig")
+        {
+            return Ok(path);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} {} is no longer in {}", name, version, cache_dir.display()),
+    )
+    .into())
+}
+
+impl PackageManager for PacmanPackageManager<'_> {
+    fn kind(&self) -> PackageManagerKind {
+        PackageManagerKind::Pacman
+    }
+
+    fn get_nvidia_packages(&self) -> Result<BTreeMap<String, String>, UpdateNvidiaError> {
+        let mut results = self.query_installed(&[])?;
+        results.retain(|name, _| is_eligible(self.config, name));
+        Ok(results)
+    }
+
+    fn get_packages_matching(
+        &self,
+        pattern: &str,
+    ) -> Result<BTreeMap<String, String>, UpdateNvidiaError> {
+        let mut results = self.query_installed(&[])?;
+        results.retain(|name, _| glob_match(pattern, name) && !is_excluded(self.config, name));
+        Ok(results)
+    }
+
+    fn get_held_nvidia_packages(&self) -> Result<BTreeSet<String>, UpdateNvidiaError> {
+        let held = self.query_held()?;
+        Ok(held.into_iter().filter(|name| is_eligible(self.config, name)).collect())
+    }
+
+    fn get_held_packages(&self, names: &[String]) -> Result<Vec<String>, UpdateNvidiaError> {
+        let held = self.query_held()?;
+        Ok(names.iter().filter(|name| held.contains(*name)).cloned().collect())
+    }
+
+    fn hold_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError> {
+        if !pacman_conf_includes_holds() {
+            log!(
+                "WARNING: {} doesn't include {}, so pacman won't honour the holds. Add \
+                 `Include = {}` to its [options] section.",
+                PACMAN_CONF_PATH,
+                PACMAN_HOLDS_PATH,
+                PACMAN_HOLDS_PATH
+            );
+        }
+        let mut held = self.query_held()?;
+        held.extend(names.iter().cloned());
+        self.write_held(&held)
+    }
+
+    fn unhold_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError> {
+        let mut held = self.query_held()?;
+        held.retain(|name| !names.contains(name));
+        self.write_held(&held)
+    }
+
+    /// Run `pacman -Sy` to refresh the sync databases
+    ///
+    /// On its own, this would set up a partial upgrade, but it's always followed by a full
+    /// `pacman -Syu` before anything is installed.
+    fn update_index(&self) -> Result<(), UpdateNvidiaError> {
+        log!("Refreshing the sync databases...");
+        check_call!(
+            Command::new(&self.config.pacman_path).arg("-Sy"),
+            UpdateNvidiaError::PacmanFailed
+        )?;
+        Ok(())
+    }
+
+    fn upgrade_packages(&self) -> Result<(), UpdateNvidiaError> {
+        // Packages built from the AUR (or installed from a file) aren't in any sync database
+        let mut foreign = self.query_installed(&["--foreign"])?;
+        foreign.retain(|name, _| is_eligible(self.config, name));
+        if !foreign.is_empty() {
+            let names: Vec<&str> = foreign.keys().map(String::as_str).collect();
+            log!(
+                "WARNING: {} didn't come from a sync database (eg. they're from the AUR), so \
+                 pacman won't upgrade them. Rebuild them with your AUR helper instead.",
+                names.join(", ")
+            );
+        }
+        let mut cmd = Command::new(&self.config.pacman_path);
+        cmd.arg("-Syu").arg("--noconfirm");
+        confirm_command(&cmd)?;
+        check_call!(cmd, UpdateNvidiaError::PacmanFailed)?;
+        Ok(())
+    }
+
+    fn fix_broken_packages(&self) -> Result<bool, UpdateNvidiaError> {
+        // pacman has no equivalent of a half-configured package to repair
+        Ok(false)
+    }
+
+    fn install_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError> {
+        check_call!(
+            Command::new(&self.config.pacman_path)
+                .arg("-S")
+                .arg("--needed")
+                .arg("--noconfirm")
+                .args(names),
+            UpdateNvidiaError::PacmanFailed
+        )?;
+        Ok(())
+    }
+
+    /// Reinstall the given versions from pacman's package cache, since the sync databases only
+    /// ever offer the newest one
+    fn install_versions(
+        &self,
+        versions: &BTreeMap<String, String>,
+    ) -> Result<(), UpdateNvidiaError> {
+        let files = versions
+            .iter()
+            .map(|(name, version)| pacman_cached_package(self.download_dir(), name, version))
+            .collect::<Result<Vec<_>, _>>()?;
+        check_call!(
+            Command::new(&self.config.pacman_path).arg("-U").arg("--noconfirm").args(files),
+            UpdateNvidiaError::PacmanFailed
+        )?;
+        Ok(())
+    }
+
+    fn download_dir(&self) -> &'static Path {
+        Path::new("/var/cache/pacman/pkg")
+    }
+
+    /// Run `pacman -Sc`, which keeps the installed versions but deletes every other one
+    fn clean_download_cache(&self) -> Result<(), UpdateNvidiaError> {
+        check_call!(
+            Command::new(&self.config.pacman_path).arg("-Sc").arg("--noconfirm"),
+            UpdateNvidiaError::PacmanFailed
+        )?;
+        Ok(())
+    }
+
+    /// List the upgrades `pacman -Syu` would make, going by the sync databases as they stand
+    ///
+    /// Held packages are still listed (marked `[ignored]` by pacman).
+    fn simulate_upgrade(&self) -> Result<Vec<PackageChange>, UpdateNvidiaError> {
+        Ok(parse_pacman_upgrades(&self.query(&["--upgrades"])?))
+    }
+
+    fn candidate_version(&self, _names: &[String]) -> Result<Option<String>, UpdateNvidiaError> {
+        // The sync databases only offer one version of each package, so there's nothing to cap
+        Err(UpdateNvidiaError::Unsupported("--max-version is only supported with apt"))
+    }
+
+    fn get_auto_installed_nvidia_packages(&self) -> Result<BTreeSet<String>, UpdateNvidiaError> {
+        Err(UpdateNvidiaError::Unsupported("auto_mark_policy is only supported with apt"))
+    }
+
+    fn mark_auto_installed(&self, _names: &[String], _auto: bool) -> Result<(), UpdateNvidiaError> {
+        Err(UpdateNvidiaError::Unsupported("auto_mark_policy is only supported with apt"))
+    }
+}
+
+/// Extract the highest `Candidate:` version from `apt-cache policy` output
+///
+/// Each package gets a block like `name:`, `  Installed: x`, `  Candidate: y`, followed by its
+/// version table. Packages with no installable version show a candidate of `(none)`.
+pub fn parse_apt_cache_policy(output: &str) -> Option<String> {
+    output
+        .lines()
+        .filter_map(|line| line.trim_start().strip_prefix("Candidate:"))
+        .map(str::trim)
+        .filter(|version| *version != "(none)")
+        .max_by(|a, b| dpkg_version_cmp(a, b))
+        .map(str::to_owned)
+}
+
+/// Check whether `candidate` is newer than `max`, an `[epoch:]upstream_version` limit
+///
+/// Like `apt`'s `520.*` pins, a limit of `520` still allows `520.61.05-0ubuntu1`, so users can
+/// cap the driver series without knowing the exact release numbers.
+pub fn exceeds_max_version(candidate: &str, max: &str) -> bool {
+    let (epoch, upstream, _) = split_version(candidate);
+    let (max_epoch, max_upstream) = match max.split_once(':') {
+        Some((epoch, rest)) => (epoch.parse().unwrap_or(0), rest),
+        None => (0, max),
+    };
+    if epoch != max_epoch {
+        return epoch > max_epoch;
+    }
+    let within_series = upstream
+        .strip_prefix(max_upstream)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'));
+    !within_series && verrevcmp(upstream, max_upstream) == Ordering::Greater
+}
+
+/// Extract the packages that would be installed or upgraded from `apt-get -s` output
+///
+/// The relevant lines look like `Inst name [old_version] (new_version repo [arch])`, with the
+/// `[old_version]` omitted for new installs.
+pub fn parse_apt_simulate_output(stdout: &str) -> Vec<PackageChange> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let rest = line.strip_prefix("Inst ")?;
+            let (name, rest) = rest.split_once(' ')?;
+            let old_version = rest
+                .strip_prefix('[')
+                .and_then(|rest| rest.split_once(']'))
+                .map(|(old, _)| old.to_owned());
+            let (_, new) = rest.split_once('(')?;
+            let new_version = new.split_whitespace().next()?.trim_end_matches(')').to_owned();
+            Some(PackageChange {
+                name: strip_arch_suffix(name).to_owned(),
+                old_version,
+                new_version,
+            })
+        })
+        .collect()
+}
+
+/// Split the rows of a `zypper` table (eg. `zypper locks`) into maps from column headings to
+/// cell contents
+///
+/// Tables look like a `# | Name | ...` heading row, a `--+-----+-...` separator, and then one
+/// `|`-separated row per entry. Anything before the heading (eg. "Loading repository data...")
+/// is skipped.
+fn parse_zypper_table(stdout: &str) -> Vec<BTreeMap<&str, &str>> {
+    let mut headings: Option<Vec<&str>> = None;
+    let mut rows = Vec::new();
+    for line in stdout.lines() {
+        if !line.contains('|') {
+            continue;
+        }
+        let cells: Vec<&str> = line.split('|').map(str::trim).collect();
+        match &headings {
+            None if cells.contains(&"Name") => headings = Some(cells),
+            None => {},
+            Some(_) if line.starts_with('-') => {},
+            Some(headings) => rows.push(headings.iter().copied().zip(cells).collect()),
+        }
+    }
+    rows
+}
+
+/// Extract the names under "The following packages have been kept back:" in `apt-get` output
+///
+/// They're listed on indented lines following that heading, several to a line.
+fn parse_apt_kept_back(stdout: &str) -> Vec<&str> {
+    stdout
+        .lines()
+        .skip_while(|line| !line.starts_with("The following packages have been kept back:"))
+        .skip(1)
+        .take_while(|line| line.starts_with(' '))
+        .flat_map(str::split_whitespace)
+        .map(strip_arch_suffix)
+        .collect()
+}
+
+/// Check whether `apt-get` output shows it installing, removing, or configuring anything
+///
+/// Packages left unconfigured by a failed `postinst` only show up as `Setting up` lines, since
+/// they don't count towards the "upgraded, newly installed" summary.
+fn apt_made_changes(stdout: &str) -> bool {
+    stdout.lines().any(|line| {
+        line.starts_with("Setting up ")
+            || line.starts_with("Removing ")
+            || (line.contains(" upgraded, ")
+                && !line.starts_with("0 upgraded, 0 newly installed, 0 to remove"))
+    })
+}
+
+/// Extract the packages that would be upgraded from `dnf check-update` output
+///
+/// Each update is listed as `name.arch version repo`. Old versions aren't included.
+fn parse_dnf_check_update_output(stdout: &str) -> Vec<PackageChange> {
+    stdout
+        .lines()
+        // Anything after this header describes obsoletions rather than upgrades
+        .take_while(|line| !line.starts_with("Obsoleting Packages"))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (name_arch, version, _repo) = (fields.next()?, fields.next()?, fields.next()?);
+            let (name, _arch) = name_arch.rsplit_once('.')?;
+            Some(PackageChange {
+                name: name.to_owned(),
+                old_version: None,
+                new_version: version.to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Extract the package names from the `IgnorePkg` lines of a pacman config file
+///
+/// Each line looks like `IgnorePkg = name1 name2`, and there may be more than one.
+fn parse_pacman_ignore_pkg(contents: &str) -> BTreeSet<String> {
+    contents
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .filter(|(key, _)| key.trim() == "IgnorePkg")
+        .flat_map(|(_, names)| names.split_whitespace())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Extract the packages that would be upgraded from `pacman --query --upgrades` output
+///
+/// Each upgrade is listed as `name old -> new`, followed by ` [ignored]` if it's held.
+fn parse_pacman_upgrades(stdout: &str) -> Vec<PackageChange> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (name, old_version) = (fields.next()?, fields.next()?);
+            if fields.next()? != "->" {
+                return None;
+            }
+            Some(PackageChange {
+                name: name.to_owned(),
+                old_version: Some(old_version.to_owned()),
+                new_version: fields.next()?.to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Match `name` against a shell-style glob where `*` matches any run of characters and `?`
+/// matches any single character
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // Where to resume if the current attempt fails: (index after the last `*`, name index)
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, n));
+            },
+            Some('?') => {
+                p += 1;
+                n += 1;
+            },
+            Some(&c) if c == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            _ => match backtrack {
+                // Let the last `*` swallow one more character and try again
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    backtrack = Some((star_p, star_n + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Compare two Debian version strings (`[epoch:]upstream_version[-debian_revision]`) the same
+/// way `dpkg --compare-versions` does
+pub fn dpkg_version_cmp(a: &str, b: &str) -> Ordering {
+    let (a_epoch, a_upstream, a_revision) = split_version(a);
+    let (b_epoch, b_upstream, b_revision) = split_version(b);
+    a_epoch
+        .cmp(&b_epoch)
+        .then_with(|| verrevcmp(a_upstream, b_upstream))
+        .then_with(|| verrevcmp(a_revision, b_revision))
+}
+
+/// Strip the epoch and Debian revision from a version string (eg. `1:535.104.05-0ubuntu1` becomes
+/// `535.104.05`)
+pub fn upstream_version(version: &str) -> &str {
+    split_version(version).1
+}
+
+/// Split a Debian version string into its epoch, upstream version, and revision
+fn split_version(version: &str) -> (u64, &str, &str) {
+    let (epoch, rest) = match version.split_once(':') {
+        Some((epoch, rest)) => (epoch.parse().unwrap_or(0), rest),
+        None => (0, version),
+    };
+    // The upstream version may itself contain hyphens, so only the last one is significant
+    let (upstream, revision) = rest.rsplit_once('-').unwrap_or((rest, ""));
+    (epoch, upstream, revision)
+}
+
+/// Sort weight of a character in the non-digit part of a version (`~` sorts before everything,
+/// even the end of the string, and letters sort before other punctuation)
+fn char_order(c: Option<u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(b'~') => -1,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => i32::from(c),
+        Some(c) => i32::from(c) + 256,
+    }
+}
+
+/// Port of dpkg's `verrevcmp`, which alternates between comparing runs of non-digits and runs of
+/// digits
+fn verrevcmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    while !a.is_empty() || !b.is_empty() {
+        // Compare the non-digit prefixes character by character
+        while a.first().is_some_and(|c| !c.is_ascii_digit())
+            || b.first().is_some_and(|c| !c.is_ascii_digit())
+        {
+            let order = char_order(a.first().copied()).cmp(&char_order(b.first().copied()));
+            if order != Ordering::Equal {
+                return order;
+            }
+            a = a.get(1..).unwrap_or_default();
+            b = b.get(1..).unwrap_or_default();
+        }
+
+        // Compare the digit runs numerically without risking overflow on absurdly long ones
+        let a_len = a.iter().take_while(|c| c.is_ascii_digit()).count();
+        let b_len = b.iter().take_while(|c| c.is_ascii_digit()).count();
+        let (a_num, b_num) = (trim_zeroes(&a[..a_len]), trim_zeroes(&b[..b_len]));
+        let order = a_num.len().cmp(&b_num.len()).then_with(|| a_num.cmp(b_num));
+        if order != Ordering::Equal {
+            return order;
+        }
+        a = &a[a_len..];
+        b = &b[b_len..];
+    }
+    Ordering::Equal
+}
+
+/// Strip leading zeroes from a run of ASCII digits
+fn trim_zeroes(digits: &[u8]) -> &[u8] {
+    let start = digits.iter().position(|&c| c != b'0').unwrap_or(digits.len());
+    &digits[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, CommandResult, ScriptedRunner};
+
+    /// `dpkg-query --show` output mixing qualified, unqualified, epoch, held, and uninstalled
+    /// entries
+    const DPKG_SHOW_FIXTURE: &str = "\
+hold ok installed libnvidia-gl-535:amd64 535.86.10-1
+hold ok installed libnvidia-gl-535:i386 535.86.10-1
+install ok installed libnvidia-compute-535:amd64 535.104.05-1
+install ok installed libnvidia-compute-535:i386 535.86.10-1
+install ok installed nvidia-driver-535 535.86.10-1
+install ok installed nvidia-settings 1:535.86.10-1
+deinstall ok config-files nvidia-driver-530 530.41.03-1
+deinstall ok installed nvidia-driver-525 525.125.06-1
+purge ok not-installed nvidia-driver-520 
+unknown ok not-installed nvidia-prime 
+";
+
+    #[test]
+    fn dpkg_show_parses_installed_packages() {
+        let packages = parse_dpkg_show(DPKG_SHOW_FIXTURE);
+        for (name, expected) in [
+            // Held packages count, with the `:<arch>` qualifier stripped
+            ("libnvidia-gl-535", Some("535.86.10-1")),
+            // The first architecture listed wins
+            ("libnvidia-compute-535", Some("535.104.05-1")),
+            ("nvidia-driver-535", Some("535.86.10-1")),
+            ("nvidia-settings", Some("1:535.86.10-1")),
+            // Removed, marked for removal, purged, and never installed packages don't
+            ("nvidia-driver-530", None),
+            ("nvidia-driver-525", None),
+            ("nvidia-driver-520", None),
+            ("nvidia-prime", None),
+            ("libnvidia-gl-535:i386", None),
+        ] {
+            assert_eq!(packages.get(name).map(String::as_str), expected, "{}", name);
+        }
+        assert_eq!(packages.len(), 4);
+    }
+
+    #[test]
+    fn dpkg_query_matching_nothing_is_not_an_error() {
+        let runner = ScriptedRunner::new()
+            .on(
+                "dpkg-query * cuda-compat-*",
+                CommandResult::failed(1, "dpkg-query: no packages found matching cuda-compat-*\n"),
+            )
+            .on(
+                "dpkg-query * nvidia-*",
+                CommandResult::failed(2, "dpkg-query: error: failed to open package info file\n"),
+            );
+        let _guard = mock::install(runner);
+        let config = Config::default();
+        let apt = AptPackageManager { config: &config, hold_cache: HoldCache::default() };
+
+        assert!(apt.get_packages_matching("cuda-compat-*").unwrap().is_empty());
+        assert!(apt.get_packages_matching("nvidia-*").is_err());
+    }
+
+    #[test]
+    fn dpkg_show_keeps_names_dpkg_list_truncates() {
+        // A row dpkg-query --list printed for the same package, with the name cut to fit its
+        // column, and the name the old parser took from it (the second whitespace-separated field)
+        let list_row = "ii  nvidia-driver-535-serv 535.104.05-0ubuntu0.22.04.1 amd64 NVIDIA";
+        let old_name = list_row.split_whitespace().nth(1);
+        assert_eq!(old_name, Some("nvidia-driver-535-serv"));
+
+        let packages = parse_dpkg_show(
+            "install ok installed nvidia-driver-535-server-open 535.104.05-0ubuntu0.22.04.1\n",
+        );
+        assert_eq!(packages["nvidia-driver-535-server-open"], "535.104.05-0ubuntu0.22.04.1");
+    }
+
+    #[test]
+    fn dpkg_show_handles_empty_output() {
+        assert!(parse_dpkg_show("").is_empty());
+    }
+
+    #[test]
+    fn glob_match_handles_wildcards_and_anchoring() {
+        for (pattern, name, expected) in [
+            ("*", "", true),
+            ("*", "nvidia-driver-535", true),
+            ("nvidia-*", "nvidia-driver-535", true),
+            ("*nvidia*", "libnvidia-gl-535", true),
+            ("*-535", "nvidia-driver-535", true),
+            ("*-535", "nvidia-driver-535-server", false),
+            ("nvidia-driver-53?", "nvidia-driver-535", true),
+            ("nvidia-driver-53?", "nvidia-driver-53", false),
+            ("nvidia-driver-53?", "nvidia-driver-5355", false),
+            ("?", "", false),
+            ("a*b*c", "a-b-b-c", true),
+            ("a*b*c", "a-c-b", false),
+            // Without wildcards, the whole name must match
+            ("nvidia", "nvidia", true),
+            ("nvidia", "libnvidia", false),
+            ("nvidia", "nvidia-settings", false),
+            ("", "", true),
+            ("", "nvidia", false),
+        ] {
+            assert_eq!(glob_match(pattern, name), expected, "{:?} vs {:?}", pattern, name);
+        }
+    }
+
+    #[test]
+    fn eligibility_applies_patterns_and_exclusions() {
+        let config = Config {
+            package_patterns: vec!["*nvidia*".to_owned()],
+            exclude_patterns: vec!["nvidia-prime".to_owned()],
+            ..Config::default()
+        };
+        assert!(is_eligible(&config, "nvidia-driver-535"));
+        assert!(is_eligible(&config, "libnvidia-gl-535"));
+        assert!(!is_eligible(&config, "nvidia-prime"));
+        assert!(!is_eligible(&config, "mesa-utils"));
+    }
+
+    #[test]
+    fn apt_options_must_be_key_value_assignments() {
+        assert!(is_apt_option("APT::Get::Always-Include-Phased-Updates=true"));
+        assert!(is_apt_option("Acquire::AllowInsecureRepositories=true"));
+        assert!(is_apt_option("Dpkg::Options::=--force-confold"));
+        assert!(!is_apt_option("--allow-unauthenticated"));
+        assert!(!is_apt_option("quiet=1"));
+        assert!(!is_apt_option("-o Acquire::Retries=3"));
+        assert!(!is_apt_option("Acquire::Retries"));
+        assert!(!is_apt_option("APT::Get::Show-Versions=true\n--yes"));
+    }
+
+    #[test]
+    fn apt_options_only_accept_dash_o_assignments() {
+        let parse = |args: &[&str]| {
+            parse_apt_option_args(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            parse(&["-o", "Dpkg::Options::=--force-confold", "-oAcquire::Retries=3"]),
+            Ok(vec!["Dpkg::Options::=--force-confold".to_owned(), "Acquire::Retries=3".to_owned()])
+        );
+        assert!(parse(&["-y", "--allow-unauthenticated"]).is_err());
+        assert!(parse(&["-o", "Acquire::Retries=3", "--allow-unauthenticated"]).is_err());
+        assert!(parse(&["-o", "--allow-unauthenticated"]).is_err());
+        assert!(parse(&["-o"]).is_err());
+    }
+
+    #[test]
+    fn eligibility_follows_the_driver_variant() {
+        let names = ["nvidia-dkms-575", "nvidia-dkms-575-open", "libnvidia-gl-575"];
+        let eligible = |variant| {
+            let config = Config { driver_variant: Some(variant), ..Config::default() };
+            names.into_iter().filter(|name| is_eligible(&config, name)).collect::<Vec<_>>()
+        };
+        assert_eq!(eligible(NvidiaVariant::Proprietary), ["nvidia-dkms-575", "libnvidia-gl-575"]);
+        assert_eq!(eligible(NvidiaVariant::Open), ["nvidia-dkms-575-open", "libnvidia-gl-575"]);
+
+        let installed: Vec<String> = names[1..].iter().map(|&name| name.to_owned()).collect();
+        assert_eq!(NvidiaVariant::detect(&installed), NvidiaVariant::Open);
+        assert_eq!(NvidiaVariant::detect(&installed[1..]), NvidiaVariant::Proprietary);
+    }
+
+    #[test]
+    fn eligibility_skips_ignored_packages_by_exact_name() {
+        let config = Config {
+            package_patterns: vec!["*nvidia*".to_owned()],
+            ignore_packages: vec!["nvidia-prime".to_owned()],
+            ..Config::default()
+        };
+        assert!(!is_eligible(&config, "nvidia-prime"));
+        assert!(is_eligible(&config, "nvidia-prime-applet"));
+    }
+
+    #[test]
+    fn strip_arch_suffix_leaves_unqualified_names_alone() {
+        assert_eq!(strip_arch_suffix("nvidia-driver-535:amd64"), "nvidia-driver-535");
+        assert_eq!(strip_arch_suffix("libnvidia-gl-535:i386"), "libnvidia-gl-535");
+        assert_eq!(strip_arch_suffix("nvidia-driver-535"), "nvidia-driver-535");
+    }
+
+    #[test]
+    fn apt_made_changes_detects_repairs() {
+        let nothing = "Reading package lists...\n\
+                       0 upgraded, 0 newly installed, 0 to remove and 3 not upgraded.\n";
+        let configured = "1 not fully installed or removed.\n\
+                          0 upgraded, 0 newly installed, 0 to remove and 3 not upgraded.\n\
+                          Setting up nvidia-dkms-535 (535.104.05-1) ...\n";
+        let installed = "0 upgraded, 1 newly installed, 0 to remove and 3 not upgraded.\n";
+        assert!(!apt_made_changes(nothing));
+        assert!(!apt_made_changes(""));
+        assert!(apt_made_changes(configured));
+        assert!(apt_made_changes(installed));
+    }
+
+    #[test]
+    fn apt_hold_cache_only_queries_again_after_a_change() {
+        let runner = ScriptedRunner::new()
+            .on("apt-mark showhold", CommandResult::ok("nvidia-driver-535\n"))
+            .on("apt-mark unhold *", CommandResult::ok(""));
+        let calls = runner.calls();
+        let _guard = mock::install(runner);
+        let config = Config::default();
+        let apt = AptPackageManager { config: &config, hold_cache: HoldCache::default() };
+        let names = vec!["libnvidia-gl-535".to_owned(), "nvidia-driver-535".to_owned()];
+
+        assert_eq!(apt.get_held_packages(&names).unwrap(), ["nvidia-driver-535"]);
+        assert_eq!(apt.get_held_packages(&names).unwrap(), ["nvidia-driver-535"]);
+        apt.unhold_packages(&names[1..]).unwrap();
+        apt.get_held_packages(&names).unwrap();
+        assert_eq!(
+            *calls.borrow(),
+            ["apt-mark showhold", "apt-mark unhold -qq nvidia-driver-535", "apt-mark showhold"]
+        );
+    }
+
+    #[test]
+    fn apt_mark_splits_long_lists_into_chunks() {
+        let runner = ScriptedRunner::new().on("apt-mark hold -qq *", CommandResult::ok(""));
+        let calls = runner.calls();
+        let _guard = mock::install(runner);
+        let config = Config { apt_mark_chunk_size: 2, ..Config::default() };
+        let apt = AptPackageManager { config: &config, hold_cache: HoldCache::default() };
+
+        let names: Vec<String> = ["a", "b", "c", "d", "e"].map(str::to_owned).into();
+        apt.hold_packages(&names).unwrap();
+        assert_eq!(
+            *calls.borrow(),
+            ["apt-mark hold -qq a b", "apt-mark hold -qq c d", "apt-mark hold -qq e"]
+        );
+    }
+
+    #[test]
+    fn failed_upgrade_error_names_the_command_and_reason() {
+        let runner = ScriptedRunner::new().on(
+            "apt-get *dist-upgrade -y",
+            CommandResult::failed(100, "E: Unable to fetch some archives\n"),
+        );
+        let _guard = mock::install(runner);
+        let config = Config::default();
+        let apt = AptPackageManager { config: &config, hold_cache: HoldCache::default() };
+
+        let message = apt.upgrade_packages().unwrap_err().to_string();
+        assert!(message.starts_with("apt-get "), "{}", message);
+        assert!(
+            message.ends_with(
+                "dist-upgrade -y returned exit code 100: E: Unable to fetch some archives"
+            ),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn pacman_ignore_pkg_merges_every_line() {
+        let contents = "# comment\nIgnorePkg = nvidia nvidia-utils\nIgnorePkg=lib32-nvidia-utils\n\
+                        IgnoreGroup = gnome\n";
+        let held: Vec<String> = parse_pacman_ignore_pkg(contents).into_iter().collect();
+        assert_eq!(held, ["lib32-nvidia-utils", "nvidia", "nvidia-utils"]);
+    }
+
+    #[test]
+    fn pacman_upgrades_include_ignored_packages() {
+        let stdout = "nvidia 535.86.10-1 -> 535.104.05-1 [ignored]\nlinux 6.5.1-1 -> 6.5.2-1\n";
+        let changes = parse_pacman_upgrades(stdout);
+        assert_eq!(
+            changes,
+            [
+                PackageChange {
+                    name: "nvidia".to_owned(),
+                    old_version: Some("535.86.10-1".to_owned()),
+                    new_version: "535.104.05-1".to_owned(),
+                },
+                PackageChange {
+                    name: "linux".to_owned(),
+                    old_version: Some("6.5.1-1".to_owned()),
+                    new_version: "6.5.2-1".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn dpkg_versions_compare_like_dpkg() {
+        use Ordering::{Equal, Greater, Less};
+        let cases = [
+            // A tilde sorts before anything, even the end of the string
+            ("1.0~rc1", "1.0", Less),
+            ("1.0~rc1", "1.0~rc2", Less),
+            ("1.0~~", "1.0~", Less),
+            // The epoch outweighs everything after it
+            ("1:1.0", "2.0", Greater),
+            ("0:2.0", "2.0", Equal),
+            // The revision only breaks ties
+            ("1.0-1", "1.0-2", Less),
+            ("1.0-10", "1.0-9", Greater),
+            ("1.1-1", "1.0-9", Greater),
+            // A missing revision sorts like an empty one
+            ("1.0", "1.0-0", Equal),
+            ("1.0", "1.0-1", Less),
+            // Numbers compare by value
+            ("1.01", "1.1", Equal),
+            ("535.104.05", "535.86.10", Greater),
+            // Letters sort before non-letters
+            ("1.0a", "1.0+", Less),
+            ("1.0a", "1.0", Greater),
+            ("535.104.05-0ubuntu0.22.04.1", "535.104.05-0ubuntu1", Less),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(dpkg_version_cmp(a, b), expected, "{} vs {}", a, b);
+            assert_eq!(dpkg_version_cmp(b, a), expected.reverse(), "{} vs {}", b, a);
+        }
+    }
+
+    /// `apt-cache policy nvidia-driver-535 nvidia-settings nvidia-driver-390` on Ubuntu 22.04
+    const APT_CACHE_POLICY_FIXTURE: &str = "\
+nvidia-driver-535:
+  Installed: 535.86.10-0ubuntu1
+  Candidate: 535.104.05-0ubuntu0.22.04.1
+  Version table:
+     535.104.05-0ubuntu0.22.04.1 500
+        500 http://archive.ubuntu.com/ubuntu jammy-updates/restricted amd64 Packages
+ *** 535.86.10-0ubuntu1 100
+        100 /var/lib/dpkg/status
+nvidia-settings:
+  Installed: 1:510.47.03-0ubuntu1
+  Candidate: 1:510.47.03-0ubuntu1
+  Version table:
+ *** 1:510.47.03-0ubuntu1 500
+        500 http://archive.ubuntu.com/ubuntu jammy/main amd64 Packages
+        100 /var/lib/dpkg/status
+nvidia-driver-390:
+  Installed: (none)
+  Candidate: (none)
+  Version table:
+";
+
+    #[test]
+    fn apt_cache_policy_picks_the_newest_candidate() {
+        // The epoch makes nvidia-settings newest, even though its upstream version is older
+        assert_eq!(
+            parse_apt_cache_policy(APT_CACHE_POLICY_FIXTURE).as_deref(),
+            Some("1:510.47.03-0ubuntu1")
+        );
+        let driver_only = APT_CACHE_POLICY_FIXTURE.split("nvidia-settings:").next().unwrap();
+        assert_eq!(
+            parse_apt_cache_policy(driver_only).as_deref(),
+            Some("535.104.05-0ubuntu0.22.04.1")
+        );
+    }
+
+    #[test]
+    fn apt_cache_policy_ignores_missing_candidates() {
+        let missing = APT_CACHE_POLICY_FIXTURE.split("nvidia-driver-390:").nth(1).unwrap();
+        assert_eq!(parse_apt_cache_policy(missing), None);
+        assert_eq!(parse_apt_cache_policy(""), None);
+    }
+
+    #[test]
+    fn max_version_allows_its_whole_series() {
+        let cases = [
+            // Equal to the limit
+            ("535-1", "535", false),
+            ("535.104.05-1", "535.104.05", false),
+            // Within the series the limit is a prefix of
+            ("535.104.05-0ubuntu1", "535", false),
+            ("535.104.05-0ubuntu1", "535.104", false),
+            // A prefix only counts at a `.` boundary
+            ("535.1040-1", "535.104", true),
+            // Older and newer series
+            ("530.41.03-1", "535", false),
+            ("550.54.14-1", "535", true),
+            ("535.113.01-1", "535.104", true),
+            // Epochs are compared first
+            ("1:520.61.05-1", "520", true),
+            ("1:520.61.05-1", "1:520", false),
+            ("520.61.05-1", "1:520", false),
+        ];
+        for (candidate, max, expected) in cases {
+            assert_eq!(exceeds_max_version(candidate, max), expected, "{} vs {}", candidate, max);
+        }
+    }
+
+    #[test]
+    fn apt_simulate_lists_installs_and_upgrades() {
+        let stdout = "\
+NOTE: This is only a simulation!
+Reading package lists...
+The following packages will be upgraded:
+  libnvidia-gl-535 nvidia-driver-535
+Remv nvidia-driver-530 [530.41.03-1]
+Inst libnvidia-gl-535:i386 [535.86.10-1] (535.104.05-1 Ubuntu:22.04/jammy-updates [i386])
+Inst nvidia-driver-535 [535.86.10-1] (535.104.05-1 Ubuntu:22.04/jammy-updates [amd64]) []
+Inst nvidia-firmware-535-535.104.05 (535.104.05-1 Ubuntu:22.04/jammy-updates [amd64])
+Conf libnvidia-gl-535:i386 (535.104.05-1 Ubuntu:22.04/jammy-updates [i386])
+Conf nvidia-driver-535 (535.104.05-1 Ubuntu:22.04/jammy-updates [amd64])
+";
+        let change = |name: &str, old: Option<&str>| PackageChange {
+            name: name.to_owned(),
+            old_version: old.map(str::to_owned),
+            new_version: "535.104.05-1".to_owned(),
+        };
+        // Only the `Inst` lines describe changes; `Remv` and `Conf` are skipped
+        assert_eq!(
+            parse_apt_simulate_output(stdout),
+            [
+                change("libnvidia-gl-535", Some("535.86.10-1")),
+                change("nvidia-driver-535", Some("535.86.10-1")),
+                change("nvidia-firmware-535-535.104.05", None),
+            ]
+        );
+    }
+}
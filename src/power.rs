@@ -0,0 +1,37 @@
+//! Power source detection, so laptops don't start an upgrade that a flat battery could interrupt
+
+use std::fs;
+use std::io;
+
+/// Where the kernel exposes AC adapters and batteries
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+/// Name prefixes the kernel's drivers use for AC adapters (eg. `AC`, `AC0`, or `ADP1`)
+const AC_ADAPTER_PREFIXES: &[&str] = &["AC", "ADP"];
+
+/// Check whether the system is running on mains power
+///
+/// Reads `/sys/class/power_supply/AC*/online` and `ADP*/online`. Systems with no AC adapter
+/// listed at all (eg. desktops) are assumed to be on mains power.
+pub fn check_ac_power() -> io::Result<bool> {
+    let entries = match fs::read_dir(POWER_SUPPLY_DIR) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        result => result?,
+    };
+    let mut found_adapter = false;
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let is_adapter = name
+            .to_str()
+            .is_some_and(|name| AC_ADAPTER_PREFIXES.iter().any(|prefix| name.starts_with(prefix)));
+        if !is_adapter {
+            continue;
+        }
+        found_adapter = true;
+        if fs::read_to_string(entry.path().join("online"))?.trim() == "1" {
+            return Ok(true);
+        }
+    }
+    Ok(!found_adapter)
+}
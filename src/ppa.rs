@@ -0,0 +1,91 @@
+//! Adding a Launchpad PPA (eg. `ppa:graphics-drivers/ppa`) for drivers newer than the distro
+//! provides (`--ppa`)
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::config::Config;
+use crate::error::UpdateNvidiaError;
+use crate::log::log;
+use crate::process::check_call;
+
+/// The main APT sources file
+const SOURCES_LIST_PATH: &str = "/etc/apt/sources.list";
+
+/// Where `add-apt-repository` and other packages drop additional APT sources
+const SOURCES_LIST_DIR: &str = "/etc/apt/sources.list.d";
+
+/// Split a `ppa:<user>/<name>` identifier into its user and name, if it's in that form
+pub fn parse_ppa(ppa: &str) -> Option<(&str, &str)> {
+    let (user, name) = ppa.strip_prefix("ppa:")?.split_once('/')?;
+    let is_valid = |part: &str| {
+        !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || "-.+_".contains(c))
+    };
+    (is_valid(user) && is_valid(name)).then_some((user, name))
+}
+
+/// The main APT sources file and every `.list` or `.sources` file beside it, whether or not
+/// they exist
+pub fn apt_source_paths() -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![PathBuf::from(SOURCES_LIST_PATH)];
+    match fs::read_dir(SOURCES_LIST_DIR) {
+        Ok(entries) => {
+            for entry in entries {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "list" || ext == "sources") {
+                    paths.push(path);
+                }
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e),
+    }
+    Ok(paths)
+}
+
+/// Check whether an enabled APT source (in either the one-line or the deb822 format) already
+/// points at the archive for `user/name`
+fn is_ppa_configured(user: &str, name: &str) -> io::Result<bool> {
+    // Matches both the old ppa.launchpad.net and the current ppa.launchpadcontent.net URLs
+    let archive_path = format!("/{}/{}/ubuntu", user, name);
+    for path in apt_source_paths()? {
+        let text = match fs::read_to_string(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            result => result?,
+        };
+        let found = text.lines().map(str::trim_start).any(|line| {
+            !line.starts_with('#') && line.contains("launchpad") && line.contains(&archive_path)
+        });
+        if found {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Add `ppa` with `add-apt-repository`, unless it's already among the APT sources
+///
+/// `add-apt-repository` is told not to refresh the package index itself, so it can be done with
+/// the proxy and retry settings. Returns whether the PPA was added, in which case the index needs
+/// refreshing regardless of how fresh it otherwise is.
+pub fn setup_ppa(config: &Config, ppa: &str) -> Result<bool, UpdateNvidiaError> {
+    let Some((user, name)) = parse_ppa(ppa) else {
+        return Err(UpdateNvidiaError::ParseError(format!(
+            "{:?} is not of the form ppa:<user>/<name>",
+            ppa
+        )));
+    };
+    if is_ppa_configured(user, name)? {
+        log!("{} is already among the APT sources.", ppa);
+        return Ok(false);
+    }
+
+    log!("Adding {}...", ppa);
+    check_call!(
+        Command::new(&config.add_apt_repository_path).arg("-y").arg("-n").arg(ppa),
+        UpdateNvidiaError::AddAptRepositoryFailed
+    )?;
+    Ok(true)
+}
@@ -0,0 +1,131 @@
+//! Faking the installed versions so the upgrade decision logic can be exercised without any
+//! real package changes (`--pretend-version`)
+//!
+//! Pretend mode always runs as `--dry-run` too, so everything but the faked versions still comes
+//! from (and is only reported to) the real package manager.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use crate::error::UpdateNvidiaError;
+use crate::packages::{PackageChange, PackageManager, PackageManagerKind};
+
+/// A [`PackageManager`] whose eligible packages are the `<from>` versions given to
+/// `--pretend-version` until an upgrade is "applied", then the `<to>` versions
+pub struct PretendPackageManager<'a> {
+    /// The real backend, which answers everything else
+    inner: Box<dyn PackageManager + 'a>,
+    /// What [`PackageManager::get_nvidia_packages`] reports before the upgrade
+    old_versions: BTreeMap<String, String>,
+    /// What [`PackageManager::get_nvidia_packages`] reports after the upgrade, minus anything
+    /// removed since
+    new_versions: RefCell<BTreeMap<String, String>>,
+    /// Whether [`PackageManager::upgrade_packages`] or [`PackageManager::install_versions`]
+    /// has been called yet
+    upgraded: Cell<bool>,
+}
+
+impl<'a> PretendPackageManager<'a> {
+    /// Wrap `inner`, reporting `old_versions` before the upgrade and `new_versions` after
+    pub fn new(
+        inner: Box<dyn PackageManager + 'a>,
+        old_versions: BTreeMap<String, String>,
+        new_versions: BTreeMap<String, String>,
+    ) -> Self {
+        Self {
+            inner,
+            old_versions,
+            new_versions: RefCell::new(new_versions),
+            upgraded: Cell::new(false),
+        }
+    }
+}
+
+impl PackageManager for PretendPackageManager<'_> {
+    fn kind(&self) -> PackageManagerKind {
+        self.inner.kind()
+    }
+
+    fn get_nvidia_packages(&self) -> Result<BTreeMap<String, String>, UpdateNvidiaError> {
+        Ok(if self.upgraded.get() {
+            self.new_versions.borrow().clone()
+        } else {
+            self.old_versions.clone()
+        })
+    }
+
+    fn get_packages_matching(
+        &self,
+        pattern: &str,
+    ) -> Result<BTreeMap<String, String>, UpdateNvidiaError> {
+        self.inner.get_packages_matching(pattern)
+    }
+
+    fn get_held_nvidia_packages(&self) -> Result<BTreeSet<String>, UpdateNvidiaError> {
+        self.inner.get_held_nvidia_packages()
+    }
+
+    fn get_held_packages(&self, names: &[String]) -> Result<Vec<String>, UpdateNvidiaError> {
+        self.inner.get_held_packages(names)
+    }
+
+    fn hold_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError> {
+        self.inner.hold_packages(names)
+    }
+
+    fn unhold_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError> {
+        self.inner.unhold_packages(names)
+    }
+
+    fn get_auto_installed_nvidia_packages(&self) -> Result<BTreeSet<String>, UpdateNvidiaError> {
+        self.inner.get_auto_installed_nvidia_packages()
+    }
+
+    fn mark_auto_installed(&self, names: &[String], auto: bool) -> Result<(), UpdateNvidiaError> {
+        self.inner.mark_auto_installed(names, auto)
+    }
+
+    fn update_index(&self) -> Result<(), UpdateNvidiaError> {
+        self.inner.update_index()
+    }
+
+    fn upgrade_packages(&self) -> Result<(), UpdateNvidiaError> {
+        self.inner.upgrade_packages()?;
+        self.upgraded.set(true);
+        Ok(())
+    }
+
+    fn fix_broken_packages(&self) -> Result<bool, UpdateNvidiaError> {
+        self.inner.fix_broken_packages()
+    }
+
+    fn install_packages(&self, names: &[String]) -> Result<(), UpdateNvidiaError> {
+        self.inner.install_packages(names)
+    }
+
+    fn install_versions(
+        &self,
+        versions: &BTreeMap<String, String>,
+    ) -> Result<(), UpdateNvidiaError> {
+        self.inner.install_versions(versions)?;
+        self.upgraded.set(true);
+        Ok(())
+    }
+
+    fn download_dir(&self) -> &'static Path {
+        self.inner.download_dir()
+    }
+
+    fn clean_download_cache(&self) -> Result<(), UpdateNvidiaError> {
+        self.inner.clean_download_cache()
+    }
+
+    fn simulate_upgrade(&self) -> Result<Vec<PackageChange>, UpdateNvidiaError> {
+        self.inner.simulate_upgrade()
+    }
+
+    fn candidate_version(&self, names: &[String]) -> Result<Option<String>, UpdateNvidiaError> {
+        self.inner.candidate_version(names)
+    }
+}
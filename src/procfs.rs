@@ -0,0 +1,388 @@
+//! Inspection of running processes and loaded kernel modules via `/proc`
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::log::log;
+
+/// A process which has one of the `/dev/nvidia*` device nodes open
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NvidiaUser {
+    /// Process ID
+    pub pid: u32,
+    /// Executable name, as reported by `/proc/<pid>/comm`
+    pub comm: String,
+    /// Effective user ID the process is running as
+    pub uid: u32,
+}
+
+impl std::fmt::Display for NvidiaUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match username(self.uid) {
+            Some(name) => write!(f, "PID {} ({}) running as {}", self.pid, self.comm, name),
+            None => write!(f, "PID {} ({}) running as UID {}", self.pid, self.comm, self.uid),
+        }
+    }
+}
+
+/// Find every process (other than this one) with a `/dev/nvidia*` device node open
+///
+/// Processes which exit mid-scan or whose file descriptors can't be read are skipped, so this
+/// needs to be run as `root` to get a complete answer.
+pub fn find_nvidia_users() -> Vec<NvidiaUser> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    let own_pid = std::process::id();
+    let mut users: Vec<_> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let pid: u32 = entry.file_name().to_str()?.parse().ok()?;
+            let proc_dir = entry.path();
+            if pid == own_pid || !has_nvidia_device_open(&proc_dir) {
+                return None;
+            }
+            let comm = fs::read_to_string(proc_dir.join("comm")).ok()?.trim_end().to_owned();
+            let uid = fs::metadata(&proc_dir).ok()?.uid();
+            Some(NvidiaUser { pid, comm, uid })
+        })
+        .collect();
+    users.sort_by_key(|user| user.pid);
+    users
+}
+
+/// Check whether any of the file descriptors under `/proc/<pid>/fd` point at `/dev/nvidia*`
+fn has_nvidia_device_open(proc_dir: &Path) -> bool {
+    let Ok(fds) = fs::read_dir(proc_dir.join("fd")) else {
+        return false;
+    };
+    fds.flatten().any(|fd| {
+        fs::read_link(fd.path())
+            .is_ok_and(|target| target.to_string_lossy().starts_with("/dev/nvidia"))
+    })
+}
+
+/// Process names of X servers and Wayland compositors which keep `nvidia_drm` in use
+const DISPLAY_SERVER_COMMS: &[&str] =
+    &["Xorg", "X", "Xwayland", "gnome-shell", "kwin_wayland", "sway"];
+
+/// A running X server or Wayland compositor, as found by [`detect_display_server_using_nvidia`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisplayServer {
+    /// Process ID
+    pub pid: u32,
+    /// Executable name, as reported by `/proc/<pid>/comm`
+    pub comm: String,
+}
+
+impl std::fmt::Display for DisplayServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (PID {})", self.comm, self.pid)
+    }
+}
+
+/// Find a running X server or Wayland compositor, which would be using the GPU
+///
+/// This goes by process name alone, since a compositor may only have `/dev/dri/*` open rather
+/// than any `/dev/nvidia*` node. The lowest PID wins if there are several.
+pub fn detect_display_server_using_nvidia() -> Option<DisplayServer> {
+    let entries = fs::read_dir("/proc").ok()?;
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let pid: u32 = entry.file_name().to_str()?.parse().ok()?;
+            let comm = fs::read_to_string(entry.path().join("comm")).ok()?.trim_end().to_owned();
+            DISPLAY_SERVER_COMMS
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(&comm))
+                .then_some(DisplayServer { pid, comm })
+        })
+        .min_by_key(|server| server.pid)
+}
+
+/// The environment needed to talk to a logged-in user's desktop session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesktopSession {
+    /// User ID the session belongs to
+    pub uid: u32,
+    /// Primary group ID of the session's processes
+    pub gid: u32,
+    /// The `DISPLAY`, `DBUS_SESSION_BUS_ADDRESS`, etc. variables the session's processes see
+    pub env: Vec<(String, String)>,
+}
+
+/// Environment variables copied from a desktop session process by [`find_desktop_session`]
+pub const SESSION_ENV_VARS: &[&str] =
+    &["DBUS_SESSION_BUS_ADDRESS", "DISPLAY", "WAYLAND_DISPLAY", "XAUTHORITY", "XDG_RUNTIME_DIR"];
+
+/// Find a non-`root` process with `DBUS_SESSION_BUS_ADDRESS` in its environment
+///
+/// The lowest PID wins, since that's most likely to be the session manager itself rather than
+/// something like a `sudo`'d terminal.
+pub fn find_desktop_session() -> Option<DesktopSession> {
+    let entries = fs::read_dir("/proc").ok()?;
+    let mut pids: Vec<u32> =
+        entries.flatten().filter_map(|entry| entry.file_name().to_str()?.parse().ok()).collect();
+    pids.sort_unstable();
+    pids.into_iter().find_map(|pid| {
+        let proc_dir = Path::new("/proc").join(pid.to_string());
+        let metadata = fs::metadata(&proc_dir).ok()?;
+        if metadata.uid() == 0 {
+            return None;
+        }
+        let environ = fs::read(proc_dir.join("environ")).ok()?;
+        let env: Vec<(String, String)> = environ
+            .split(|&byte| byte == 0)
+            .filter_map(|var| {
+                let (key, value) = std::str::from_utf8(var).ok()?.split_once('=')?;
+                SESSION_ENV_VARS.contains(&key).then(|| (key.to_owned(), value.to_owned()))
+            })
+            .collect();
+        env.iter().any(|(key, _)| key == "DBUS_SESSION_BUS_ADDRESS").then(|| DesktopSession {
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            env,
+        })
+    })
+}
+
+/// Check whether a process is still running (and not just a zombie awaiting reaping)
+pub fn is_running(pid: u32) -> bool {
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        // The state field comes after the parenthesized comm, which may itself contain spaces
+        Ok(stat) => stat.rsplit_once(')').is_some_and(|(_, rest)| !rest.starts_with(" Z")),
+        Err(_) => false,
+    }
+}
+
+/// Look up the name for a UID in `/etc/passwd`
+fn username(uid: u32) -> Option<String> {
+    let passwd = fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        let name = fields.next()?;
+        let entry_uid: u32 = fields.nth(1)?.parse().ok()?;
+        (entry_uid == uid).then(|| name.to_owned())
+    })
+}
+
+/// The effective UID of this process, from `/proc/self/status`
+pub fn effective_uid() -> std::io::Result<u32> {
+    let status = fs::read_to_string("/proc/self/status")?;
+    // The fields are the real, effective, saved, and filesystem UIDs
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))
+        .and_then(|uids| uids.split_whitespace().nth(1))
+        .and_then(|uid| uid.parse().ok())
+        .ok_or_else(|| std::io::Error::other("no effective UID in /proc/self/status"))
+}
+
+/// Check `/proc/modules` for whether the kernel module `name` is currently loaded
+pub fn is_module_loaded(name: &str) -> std::io::Result<bool> {
+    let modules = fs::read_to_string("/proc/modules")?;
+    Ok(modules.lines().any(|line| line.split(' ').next() == Some(name)))
+}
+
+/// The version of the loaded NVIDIA kernel module, or `None` if it isn't loaded
+///
+/// The first line of `/proc/driver/nvidia/version` looks like `NVRM version: NVIDIA UNIX x86_64
+/// Kernel Module  535.104.05  Sat Aug 19 01:15:15 UTC 2023` (with `Open Kernel Module for
+/// x86_64` for the open-source one), so the version is the first word made of digits and dots.
+pub fn get_loaded_nvidia_version() -> std::io::Result<Option<String>> {
+    let text = match fs::read_to_string("/proc/driver/nvidia/version") {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        result => result?,
+    };
+    let version =
+        text.lines().find_map(|line| line.strip_prefix("NVRM version:")).and_then(|line| {
+            line.split_whitespace().find(|word| {
+                word.contains('.') && word.chars().all(|c| c.is_ascii_digit() || c == '.')
+            })
+        });
+    match version {
+        Some(version) => Ok(Some(version.to_owned())),
+        None => Err(std::io::Error::other("no version in /proc/driver/nvidia/version")),
+    }
+}
+
+/// The PIDs of the processes holding a lock on the file with device `dev` and inode `ino`, per
+/// `/proc/locks`, with `None` for a lock with no owning process (eg. an open file description
+/// lock)
+///
+/// Covers both `flock(2)` and `fcntl(2)` locks. Processes still waiting for a lock are left out.
+pub fn lock_holders(dev: u64, ino: u64) -> std::io::Result<Vec<Option<u32>>> {
+    // Decode the device number the way glibc's `major()` and `minor()` do
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    let locks = fs::read_to_string("/proc/locks")?;
+    Ok(locks
+        .lines()
+        .filter(|line| !line.contains("->"))
+        .filter_map(|line| {
+            // eg. `1: POSIX  ADVISORY  WRITE 1234 08:02:1311 0 EOF`
+            let mut fields = line.split_whitespace().skip(4);
+            let pid = fields.next()?.parse().ok();
+            let mut id = fields.next()?.split(':');
+            let id_major = u64::from_str_radix(id.next()?, 16).ok()?;
+            let id_minor = u64::from_str_radix(id.next()?, 16).ok()?;
+            let id_ino: u64 = id.next()?.parse().ok()?;
+            ((id_major, id_minor, id_ino) == (major, minor, ino)).then_some(pid)
+        })
+        .collect())
+}
+
+/// The ID the kernel randomly picked for the current boot, which changes with every reboot
+pub fn boot_id() -> std::io::Result<String> {
+    Ok(fs::read_to_string("/proc/sys/kernel/random/boot_id")?.trim().to_owned())
+}
+
+/// A kernel module listed in `/proc/modules` (as printed by `lsmod`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadedModule {
+    pub name: String,
+    /// Size in memory, in bytes
+    pub size: u64,
+    /// How many references (from modules and open device nodes alike) keep it loaded
+    pub use_count: u32,
+    /// The loaded modules which depend on it
+    pub used_by: Vec<String>,
+}
+
+impl std::fmt::Display for LoadedModule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({} KiB, use count {}", self.name, self.size / 1024, self.use_count)?;
+        if !self.used_by.is_empty() {
+            write!(f, ", used by {}", self.used_by.join(", "))?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Read every loaded kernel module from `/proc/modules`
+///
+/// Lines which can't be parsed are skipped with a warning rather than failing the whole list.
+pub fn list_loaded_modules() -> std::io::Result<Vec<LoadedModule>> {
+    Ok(parse_modules(&fs::read_to_string("/proc/modules")?))
+}
+
+/// Read the loaded kernel modules whose names start with `nvidia` (eg. `nvidia_drm`)
+///
+/// This is only informational, so an unreadable `/proc/modules` is logged as a warning and
+/// treated as if nothing were loaded.
+pub fn list_loaded_nvidia_modules() -> Vec<LoadedModule> {
+    let mut modules = list_loaded_modules().unwrap_or_else(|e| {
+        log!("WARNING: Could not read /proc/modules. ({})", e);
+        Vec::new()
+    });
+    modules.retain(|module| module.name.starts_with("nvidia"));
+    modules
+}
+
+/// Parse the contents of `/proc/modules`, skipping unparseable lines with a warning
+fn parse_modules(modules: &str) -> Vec<LoadedModule> {
+    modules
+        .lines()
+        .filter_map(|line| {
+            let module = parse_module_line(line);
+            if module.is_none() {
+                log!("WARNING: Skipping unparseable /proc/modules line: {:?}", line);
+            }
+            module
+        })
+        .collect()
+}
+
+/// Parse a `/proc/modules` line like `nvidia_drm 90112 4 nvidia_modeset, Live 0x0000000000000000`
+fn parse_module_line(line: &str) -> Option<LoadedModule> {
+    let mut fields = line.split(' ');
+    let name = fields.next().filter(|name| !name.is_empty())?;
+    let size = fields.next()?.parse().ok()?;
+    // Modules which can't be unloaded at all have `-` in place of a use count
+    let use_count = match fields.next()? {
+        "-" => 0,
+        count => count.parse().ok()?,
+    };
+    // The "used by" column is a comma-terminated list, or `-` if nothing uses the module
+    let used_by = fields
+        .next()?
+        .split(',')
+        .filter(|user| !user.is_empty() && *user != "-")
+        .map(str::to_owned)
+        .collect();
+    Some(LoadedModule { name: name.to_owned(), size, use_count, used_by })
+}
+
+/// Send `SIGTERM` to a process
+pub fn terminate(pid: u32) -> std::io::Result<()> {
+    extern "C" {
+        fn kill(pid: i32, sig: i32) -> i32;
+    }
+    /// Signal number for `SIGTERM` on Linux
+    const SIGTERM: i32 = 15;
+
+    let pid = i32::try_from(pid).map_err(|_| std::io::ErrorKind::InvalidInput)?;
+    // SAFETY: kill(2) has no memory-safety preconditions. It only takes integers.
+    if unsafe { kill(pid, SIGTERM) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `/proc/modules` lines as printed for a typical NVIDIA driver setup
+    const PROC_MODULES_FIXTURE: &str = "\
+nvidia_uvm 1515520 0 - Live 0x0000000000000000 (POE)
+nvidia_drm 77824 4 - Live 0x0000000000000000 (POE)
+nvidia_modeset 1302528 8 nvidia_drm, Live 0x0000000000000000 (POE)
+nvidia 56823808 391 nvidia_uvm,nvidia_modeset, Live 0x0000000000000000 (POE)
+video 65536 2 nvidia_modeset,i915, Loading 0x0000000000000000
+snd_hda_intel 57344 - - Live 0x0000000000000000
+nvidia_peermem 16384
+";
+
+    #[test]
+    fn parses_proc_modules_lines() {
+        let modules = parse_modules(PROC_MODULES_FIXTURE);
+        let module = |name: &str, size, use_count, used_by: &[&str]| LoadedModule {
+            name: name.to_owned(),
+            size,
+            use_count,
+            used_by: used_by.iter().map(|&user| user.to_owned()).collect(),
+        };
+        assert_eq!(
+            modules,
+            [
+                // A `-` in the "used by" column means nothing depends on the module
+                module("nvidia_uvm", 1515520, 0, &[]),
+                module("nvidia_drm", 77824, 4, &[]),
+                // The "used by" list is comma-terminated
+                module("nvidia_modeset", 1302528, 8, &["nvidia_drm"]),
+                module("nvidia", 56823808, 391, &["nvidia_uvm", "nvidia_modeset"]),
+                // Modules still being loaded are listed like live ones
+                module("video", 65536, 2, &["nvidia_modeset", "i915"]),
+                // A `-` use count means the module can't be unloaded at all
+                module("snd_hda_intel", 57344, 0, &[]),
+                // ...and the truncated `nvidia_peermem` line is skipped
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_proc_modules_lines() {
+        for line in [
+            "",
+            " 77824 4 - Live 0x0000000000000000",
+            "nvidia_drm lots 4 - Live 0x0000000000000000",
+            "nvidia_drm 77824 many - Live 0x0000000000000000",
+            "nvidia_drm 77824 4",
+        ] {
+            assert_eq!(parse_module_line(line), None, "{:?}", line);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Writing the run's progress to [`STATUS_FILE_PATH`] on `SIGUSR1`, so monitoring scripts can
+//! poll it without parsing the journal
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::json::Json;
+use crate::log::{format_rfc3339, last_message, log};
+use crate::signal;
+use crate::timing::Phase;
+
+/// The directory [`STATUS_FILE_PATH`] is written in, created if it's missing
+const STATUS_DIR: &str = "/run/update_nvidia";
+
+/// Where the progress is written each time `SIGUSR1` arrives
+pub const STATUS_FILE_PATH: &str = "/run/update_nvidia/status.json";
+
+/// How often the writer thread checks whether `SIGUSR1` has arrived
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The timed step in progress and when it started, if any (see [`crate::timing::time`])
+static CURRENT_PHASE: Mutex<Option<(Phase, Instant)>> = Mutex::new(None);
+
+/// Record that `phase` has started, or (with `None`) that it's finished
+pub fn set_phase(phase: Option<Phase>) {
+    *CURRENT_PHASE.lock().unwrap_or_else(PoisonError::into_inner) =
+        phase.map(|phase| (phase, Instant::now()));
+}
+
+/// Answers `SIGUSR1` by writing [`STATUS_FILE_PATH`] until it's dropped, then removes the file
+///
+/// Since a signal handler can't safely write files, the handler only sets a flag, which a
+/// background thread polls.
+pub struct StatusDump {
+    /// Tells the writer thread to exit
+    stop: Arc<AtomicBool>,
+    /// The writer thread, joined on drop
+    writer: Option<JoinHandle<()>>,
+}
+
+impl StatusDump {
+    /// Start answering `SIGUSR1`, counting the run's elapsed time from `run_started`
+    pub fn start(run_started: Instant) -> io::Result<Self> {
+        fs::create_dir_all(STATUS_DIR)?;
+        signal::install_status_handler()?;
+        // Don't answer a request which arrived between `--check-interval` runs
+        signal::take_status_request();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let stop = Arc::clone(&stop);
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    if signal::take_status_request() {
+                        if let Err(e) = write_status(run_started) {
+                            log!("ERROR: Could not write {}: {}", STATUS_FILE_PATH, e);
+                        }
+                    }
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+            })
+        };
+        Ok(Self { stop, writer: Some(writer) })
+    }
+}
+
+impl Drop for StatusDump {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+        match fs::remove_file(STATUS_FILE_PATH) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                log!("ERROR: Could not remove {}: {}", STATUS_FILE_PATH, e)
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Write the current step, the elapsed times, and the last log message to
+/// [`STATUS_FILE_PATH`]
+///
+/// The step is `none` between the timed steps. The file is replaced atomically, so a reader
+/// never sees it half-written.
+fn write_status(run_started: Instant) -> io::Result<()> {
+    let millis =
+        |elapsed: Duration| Json::Integer(i64::try_from(elapsed.as_millis()).unwrap_or(i64::MAX));
+    let phase = *CURRENT_PHASE.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut fields = vec![
+        ("pid", Json::Integer(i64::from(std::process::id()))),
+        ("updated", format_rfc3339(SystemTime::now()).as_str().into()),
+        ("operation", phase.map_or("none", |(phase, _)| phase.as_str()).into()),
+    ];
+    if let Some((_, started)) = phase {
+        fields.push(("operation_elapsed_ms", millis(started.elapsed())));
+    }
+    fields.push(("elapsed_ms", millis(run_started.elapsed())));
+    fields.push(("last_message", last_message().as_str().into()));
+
+    let temp_path = Path::new(STATUS_DIR).join(".status.json.tmp");
+    fs::write(&temp_path, format!("{}\n", Json::object(fields)))?;
+    fs::rename(&temp_path, STATUS_FILE_PATH)
+}
@@ -0,0 +1,100 @@
+//! Pausing before each destructive command so someone at the terminal can abort (`--interactive`)
+//!
+//! With `--timeout`, an unanswered prompt counts down and then continues on its own, so an
+//! interactive run left unattended still finishes (and re-holds the packages).
+
+use std::io::{BufRead, IsTerminal};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::error::UpdateNvidiaError;
+use crate::log::log;
+use crate::process::{self, format_command, DRY_RUN};
+use crate::signal;
+
+/// Set by `--interactive` to make [`confirm_command`] ask before running anything
+pub static INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// How often a waiting prompt checks for `SIGINT` and updates its countdown
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Under `--interactive` (and not `--dry-run`), ask before running `cmd` via [`prompt_user`]
+pub fn confirm_command(cmd: &Command) -> Result<(), UpdateNvidiaError> {
+    if !INTERACTIVE.load(Ordering::Relaxed) || DRY_RUN.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    prompt_user(&format_command(cmd))
+}
+
+/// Print "About to run: `action`" and wait for Enter, or (with `--timeout`) until it runs out
+///
+/// Without a terminal on stdin, nobody could answer, so this fails rather than blocking a
+/// service. `SIGINT` (Ctrl-C) or the end of stdin abort instead, by returning an error, which
+/// lets the caller put the holds back.
+pub fn prompt_user(action: &str) -> Result<(), UpdateNvidiaError> {
+    if !std::io::stdin().is_terminal() {
+        return Err(UpdateNvidiaError::InteractiveWithoutTerminal);
+    }
+    let lines = stdin_lines().lock().unwrap_or_else(PoisonError::into_inner);
+    // Don't let Enter pressed while the previous command was running answer this prompt
+    while lines.try_recv().is_ok() {}
+
+    eprintln!("About to run: {}. Press Enter to continue, Ctrl-C to abort.", action);
+    let timeout = process::timeout();
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut shown_secs = None;
+    loop {
+        if signal::terminate_requested() {
+            eprintln!();
+            return Err(UpdateNvidiaError::PromptAborted(action.to_owned()));
+        }
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                eprintln!();
+                log!(
+                    "No answer after {}s. Continuing (--timeout).",
+                    timeout.unwrap_or_default().as_secs()
+                );
+                return Ok(());
+            }
+            let secs = remaining.as_secs() + 1;
+            if shown_secs != Some(secs) {
+                eprint!("\rContinuing automatically in {}s... ", secs);
+                shown_secs = Some(secs);
+            }
+        }
+        match lines.recv_timeout(POLL_INTERVAL) {
+            Ok(()) => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(UpdateNvidiaError::PromptAborted(action.to_owned()))
+            },
+        }
+    }
+}
+
+/// A channel which receives a message each time a line is read from stdin
+///
+/// A blocking read can't be given a timeout, so a single background thread does the reading for
+/// every prompt. It exits (disconnecting the channel) at the end of stdin.
+fn stdin_lines() -> &'static Mutex<Receiver<()>> {
+    static LINES: OnceLock<Mutex<Receiver<()>>> = OnceLock::new();
+    LINES.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut stdin = std::io::stdin().lock();
+            let mut line = String::new();
+            while matches!(stdin.read_line(&mut line), Ok(len) if len > 0) {
+                line.clear();
+                if sender.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+        Mutex::new(receiver)
+    })
+}
@@ -0,0 +1,153 @@
+//! Installing and removing systemd units to run this tool at boot or daily (`--schedule` and
+//! `--unschedule`)
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::Ordering;
+
+use crate::config::Config;
+use crate::error::UpdateNvidiaError;
+use crate::log::log;
+use crate::process::{check_call, DRY_RUN};
+
+/// Where locally-installed systemd units go
+const SYSTEMD_UNIT_DIR: &str = "/etc/systemd/system";
+
+/// The name of the service unit which runs the upgrade
+const SERVICE_NAME: &str = "update_nvidia.service";
+
+/// The name of the timer unit which triggers [`SERVICE_NAME`] daily
+const TIMER_NAME: &str = "update_nvidia.timer";
+
+/// Write the units for running at boot (`on_boot`), daily (`daily`), or both, then enable them
+///
+/// The service runs this same executable (with `config_path`, if one was given), so it doesn't
+/// matter where it was installed. Only the timer is started right away, since starting the
+/// service would upgrade the driver immediately.
+pub fn install(
+    config: &Config,
+    config_path: Option<&Path>,
+    on_boot: bool,
+    daily: bool,
+) -> Result<(), UpdateNvidiaError> {
+    let exe = std::env::current_exe()?;
+    let mut command = format!("{} --assume-yes --systemd-notify", exe.display());
+    if let Some(path) = config_path {
+        // systemd runs the service from /, so a relative path wouldn't point at the same file
+        let path = fs::canonicalize(path)?;
+        command.push_str(&format!(" --config \"{}\"", path.display()));
+    }
+    write_unit(SERVICE_NAME, &service_unit(&command, on_boot))?;
+    if daily {
+        write_unit(TIMER_NAME, TIMER_UNIT)?;
+    }
+    systemctl(config, &["daemon-reload"])?;
+    if on_boot {
+        systemctl(config, &["enable", SERVICE_NAME])?;
+    }
+    if daily {
+        systemctl(config, &["enable", "--now", TIMER_NAME])?;
+    }
+    let when = match (on_boot, daily) {
+        (true, true) => "at boot and daily",
+        (true, false) => "at boot",
+        _ => "daily",
+    };
+    log!("Scheduled update_nvidia to run {}.", when);
+    Ok(())
+}
+
+/// Disable and remove whichever of the units [`install`] wrote are present
+pub fn uninstall(config: &Config) -> Result<(), UpdateNvidiaError> {
+    let mut removed = false;
+    for (name, stop) in [(TIMER_NAME, true), (SERVICE_NAME, false)] {
+        let path = unit_path(name);
+        if !path.exists() {
+            continue;
+        }
+        if stop {
+            systemctl(config, &["disable", "--now", name])?;
+        } else {
+            systemctl(config, &["disable", name])?;
+        }
+        if DRY_RUN.load(Ordering::Relaxed) {
+            log!("[DRY RUN] would remove {}", path.display());
+        } else {
+            log!("Removing {}", path.display());
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {},
+            }
+        }
+        removed = true;
+    }
+    if !removed {
+        log!("Neither {} nor {} is installed. Nothing to remove.", SERVICE_NAME, TIMER_NAME);
+        return Ok(());
+    }
+    systemctl(config, &["daemon-reload"])?;
+    Ok(())
+}
+
+/// The service unit, running `command` and (if `on_boot`) installed to run at every boot
+///
+/// Unlike the `update_nvidia.service` shipped alongside the source, this doesn't set
+/// `RemainAfterExit=yes`, since the timer couldn't re-trigger a unit which stays active.
+fn service_unit(command: &str, on_boot: bool) -> String {
+    let install = if on_boot { "\n[Install]\nWantedBy=multi-user.target\n" } else { "" };
+    format!(
+        "[Unit]\n\
+         Description=Update nvidia drivers only when the kernel module can be reloaded\n\
+         ConditionACPower=true\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         Before=display-manager.service\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         NotifyAccess=main\n\
+         ExecStart={}\n{}",
+        command, install
+    )
+}
+
+/// The timer unit, which runs the service once a day (or at the next boot, if a day was missed
+/// while the system was off)
+const TIMER_UNIT: &str = "[Unit]\n\
+    Description=Update nvidia drivers daily\n\
+    \n\
+    [Timer]\n\
+    OnCalendar=daily\n\
+    Persistent=true\n\
+    RandomizedDelaySec=1h\n\
+    \n\
+    [Install]\n\
+    WantedBy=timers.target\n";
+
+/// The path unit `name` is installed at
+fn unit_path(name: &str) -> PathBuf {
+    Path::new(SYSTEMD_UNIT_DIR).join(name)
+}
+
+/// Write `contents` to the unit file for `name`, or just report it in `--dry-run` mode
+fn write_unit(name: &str, contents: &str) -> Result<(), UpdateNvidiaError> {
+    let path = unit_path(name);
+    if DRY_RUN.load(Ordering::Relaxed) {
+        log!("[DRY RUN] would write {}:\n{}", path.display(), contents);
+        return Ok(());
+    }
+    log!("Writing {}", path.display());
+    fs::write(&path, contents)?;
+    Ok(())
+}
+
+/// Run `systemctl` with `args`
+fn systemctl(config: &Config, args: &[&str]) -> Result<(), UpdateNvidiaError> {
+    check_call!(
+        Command::new(&config.systemctl_path).args(args),
+        UpdateNvidiaError::SystemctlFailed
+    )?;
+    Ok(())
+}
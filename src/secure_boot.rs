@@ -0,0 +1,127 @@
+//! Secure Boot detection, since the kernel will refuse to load a DKMS-built module unless it was
+//! signed with a key enrolled in the MOK list
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::Config;
+use crate::error::{CalledProcessError, UpdateNvidiaError};
+use crate::log::log;
+use crate::process::run_output;
+
+/// Only present when the system was booted via EFI
+const EFI_DIR: &str = "/sys/firmware/efi";
+
+/// The `SecureBoot` variable under the EFI global variable GUID
+const SECURE_BOOT_EFIVAR_PATH: &str =
+    "/sys/firmware/efi/efivars/SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+/// The DKMS config file which may point `mok_signing_key` at a custom key
+const DKMS_FRAMEWORK_CONF_PATH: &str = "/etc/dkms/framework.conf";
+
+/// Where DKMS looks for a signing key if `framework.conf` doesn't say (DKMS 3.x's own default,
+/// then the one Ubuntu's `shim-signed` generates)
+const DEFAULT_MOK_KEY_PATHS: &[&str] =
+    &["/var/lib/dkms/mok.key", "/var/lib/shim-signed/mok/MOK.priv"];
+
+/// Whether the firmware will only boot (and the kernel only load) signed code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecureBootStatus {
+    /// Unsigned (or wrongly signed) modules will be refused
+    Enabled,
+    /// Secure Boot is supported but turned off
+    Disabled,
+    /// Booted via legacy BIOS, so Secure Boot doesn't apply
+    NotEfi,
+}
+
+/// Read the `SecureBoot` EFI variable, falling back to `mokutil --sb-state` if `efivarfs` isn't
+/// mounted
+pub fn check_secure_boot_status(config: &Config) -> Result<SecureBootStatus, UpdateNvidiaError> {
+    if !Path::new(EFI_DIR).exists() {
+        return Ok(SecureBootStatus::NotEfi);
+    }
+    // 4 bytes of attributes, followed by the 1-byte value
+    if let Ok(data) = fs::read(SECURE_BOOT_EFIVAR_PATH) {
+        if let Some(&value) = data.get(4) {
+            return Ok(if value == 1 {
+                SecureBootStatus::Enabled
+            } else {
+                SecureBootStatus::Disabled
+            });
+        }
+    }
+
+    let mut cmd = Command::new(&config.mokutil_path);
+    cmd.arg("--sb-state");
+    let output = run_output(&mut cmd)?;
+    // Firmware without Secure Boot support makes it exit non-zero, so check the message first
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    if text.contains("SecureBoot enabled") {
+        Ok(SecureBootStatus::Enabled)
+    } else if text.contains("SecureBoot disabled") || text.contains("doesn't support Secure Boot") {
+        Ok(SecureBootStatus::Disabled)
+    } else if !output.status.success() {
+        Err(UpdateNvidiaError::MokutilFailed(CalledProcessError::from_output(&cmd, &output)))
+    } else {
+        Err(UpdateNvidiaError::ParseError(format!("mokutil --sb-state: {:?}", text.trim())))
+    }
+}
+
+/// The key DKMS will sign modules with, if one exists
+fn find_signing_key() -> Option<PathBuf> {
+    let configured = fs::read_to_string(DKMS_FRAMEWORK_CONF_PATH).ok().and_then(|conf| {
+        conf.lines().find_map(|line| {
+            let value = line.trim().strip_prefix("mok_signing_key=")?;
+            Some(PathBuf::from(value.trim_matches('"')))
+        })
+    });
+    configured
+        .into_iter()
+        .chain(DEFAULT_MOK_KEY_PATHS.iter().map(PathBuf::from))
+        .find(|path| path.is_file())
+}
+
+/// On DKMS installs with Secure Boot enabled, make sure there's a key to sign the rebuilt module
+/// with, warning (or failing, with `secure_boot_check`) if there isn't
+///
+/// If Secure Boot's state can't be determined, that's only logged.
+pub fn check_secure_boot(
+    config: &Config,
+    old_versions: &BTreeMap<String, String>,
+) -> Result<(), UpdateNvidiaError> {
+    // Prebuilt modules come signed by the distro, so this only matters for DKMS
+    if !old_versions.keys().any(|name| name.contains("dkms")) {
+        return Ok(());
+    }
+    match check_secure_boot_status(config) {
+        Ok(SecureBootStatus::Enabled) => {},
+        Ok(SecureBootStatus::Disabled | SecureBootStatus::NotEfi) => return Ok(()),
+        Err(e) => {
+            log!("ERROR: Could not determine whether Secure Boot is enabled: {}", e);
+            return Ok(());
+        },
+    }
+
+    match find_signing_key() {
+        Some(key) => log!(
+            "Secure Boot is enabled. DKMS should sign the rebuilt module with {}, which must be \
+             enrolled (eg. with mokutil --import) for it to load.",
+            key.display()
+        ),
+        None if config.secure_boot_check => return Err(UpdateNvidiaError::SecureBootKeyMissing),
+        None => log!(
+            "WARNING: Secure Boot is enabled but no DKMS signing key was found, so the kernel may \
+             refuse to load the rebuilt module. Generate a key, enroll it with mokutil --import, \
+             and set mok_signing_key in {}.",
+            DKMS_FRAMEWORK_CONF_PATH
+        ),
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! Safety copies of GPU settings files which driver upgrades have been known to reset
+
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use crate::error::UpdateNvidiaError;
+use crate::log::{format_rfc3339, log};
+use crate::process::DRY_RUN;
+
+/// Default directory under which each run's backup gets its own timestamped subdirectory
+pub const BACKUP_SETTINGS_DIR: &str = "/var/lib/update_nvidia/backups";
+
+/// System-wide X.org configuration, which `nvidia-xconfig` and friends may have written to
+const XORG_CONF_PATH: &str = "/etc/X11/xorg.conf";
+
+/// Per-user `nvidia-settings` state (fan curves, clock offsets, etc.), relative to the home dir
+const NVIDIA_SETTINGS_RC: &str = ".nvidia-settings-rc";
+
+/// A set of settings files copied aside before an upgrade
+pub struct SettingsBackup {
+    /// The timestamped directory holding this run's copies
+    dir: PathBuf,
+    /// The original locations of the files that were copied
+    files: Vec<PathBuf>,
+}
+
+impl SettingsBackup {
+    /// Copy back every backed-up file which the upgrade changed or removed
+    pub fn restore(&self) -> Result<(), UpdateNvidiaError> {
+        if DRY_RUN.load(Ordering::Relaxed) {
+            log!("[DRY RUN] would restore any settings the upgrade changed from the backup");
+            return Ok(());
+        }
+        for original in &self.files {
+            let saved = backup_path(&self.dir, original);
+            if fs::read(original).ok() != Some(fs::read(&saved)?) {
+                log!("Restoring {} from {}", original.display(), saved.display());
+                copy_preserving_owner(&saved, original)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Copy every `nvidia-settings` rc file and the X.org config (whichever exist) into a new
+/// timestamped subdirectory of `backup_dir`
+///
+/// Since this runs as `root`, "every" means the rc file in each home directory listed in
+/// `/etc/passwd`. Returns `None` if there was nothing to back up.
+pub fn backup_nvidia_settings(
+    backup_dir: &Path,
+) -> Result<Option<SettingsBackup>, UpdateNvidiaError> {
+    let files = settings_files();
+    if files.is_empty() {
+        return Ok(None);
+    }
+
+    let dir = backup_dir.join(format_rfc3339(SystemTime::now()));
+    if DRY_RUN.load(Ordering::Relaxed) {
+        for original in &files {
+            let saved = backup_path(&dir, original);
+            log!("[DRY RUN] would copy {} to {}", original.display(), saved.display());
+        }
+        return Ok(Some(SettingsBackup { dir, files }));
+    }
+    for original in &files {
+        let saved = backup_path(&dir, original);
+        if let Some(parent) = saved.parent() {
+            // The copies include other users' files, so keep the tree root-only
+            DirBuilder::new().recursive(true).mode(0o700).create(parent)?;
+        }
+        copy_preserving_owner(original, &saved)?;
+    }
+    log!("Backed up {} settings file(s) to {}", files.len(), dir.display());
+    Ok(Some(SettingsBackup { dir, files }))
+}
+
+/// Every settings file which currently exists
+fn settings_files() -> Vec<PathBuf> {
+    let mut candidates = vec![PathBuf::from(XORG_CONF_PATH)];
+    if let Ok(passwd) = fs::read_to_string("/etc/passwd") {
+        candidates.extend(
+            passwd
+                .lines()
+                .filter_map(|line| line.split(':').nth(5))
+                .filter(|home| home.starts_with('/'))
+                .map(|home| Path::new(home).join(NVIDIA_SETTINGS_RC)),
+        );
+    }
+    candidates.sort();
+    candidates.dedup();
+    candidates.retain(|path| path.is_file());
+    candidates
+}
+
+/// Where the copy of `original` lives inside the backup directory `dir`
+fn backup_path(dir: &Path, original: &Path) -> PathBuf {
+    dir.join(original.strip_prefix("/").unwrap_or(original))
+}
+
+/// Copy a file's contents and permissions and, unlike `fs::copy`, its owner and group too
+fn copy_preserving_owner(from: &Path, to: &Path) -> std::io::Result<()> {
+    fs::copy(from, to)?;
+    let metadata = fs::metadata(from)?;
+    std::os::unix::fs::chown(to, Some(metadata.uid()), Some(metadata.gid()))
+}
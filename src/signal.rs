@@ -0,0 +1,82 @@
+//! Stopping `--check-interval` mode cleanly on `SIGTERM` or `SIGINT`, and requesting a status
+//! dump with `SIGUSR1`
+//!
+//! The handlers only set flags, so a signal which arrives mid-run lets that run finish (and
+//! re-hold the packages) before the loop exits.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Set by the signal handler once `SIGTERM` or `SIGINT` has been received
+static TERMINATE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Set by the signal handler when `SIGUSR1` is received, until [`take_status_request`]
+static STATUS_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// How often [`sleep_unless_terminated`] checks whether it should stop
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Replace the default (exit immediately) action for `SIGTERM` and `SIGINT` with setting a flag
+pub fn install_termination_handler() -> std::io::Result<()> {
+    /// Signal numbers for `SIGINT` and `SIGTERM` on Linux
+    const SIGNALS: [i32; 2] = [2, 15];
+
+    extern "C" fn on_signal(_signum: i32) {
+        // Atomic stores are async-signal-safe, unlike nearly everything else
+        TERMINATE_REQUESTED.store(true, Ordering::Relaxed);
+    }
+
+    SIGNALS.into_iter().try_for_each(|signum| set_flag_handler(signum, on_signal))
+}
+
+/// Replace the default (exit immediately) action for `SIGUSR1` with requesting a status dump
+pub fn install_status_handler() -> std::io::Result<()> {
+    /// Signal number for `SIGUSR1` on Linux (x86 and ARM)
+    const SIGUSR1: i32 = 10;
+
+    extern "C" fn on_signal(_signum: i32) {
+        STATUS_REQUESTED.store(true, Ordering::Relaxed);
+    }
+
+    set_flag_handler(SIGUSR1, on_signal)
+}
+
+/// Install `handler` for `signum`, which must do nothing more than set an atomic flag
+fn set_flag_handler(signum: i32, handler: extern "C" fn(i32)) -> std::io::Result<()> {
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+    /// `SIG_ERR` from `signal.h`, which is `(sighandler_t) -1`
+    const SIG_ERR: usize = usize::MAX;
+
+    // SAFETY: Every handler passed in only performs an atomic store, which is
+    // async-signal-safe, and glibc's signal(3) installs it with `SA_RESTART`, so interrupted
+    // syscalls are resumed.
+    if unsafe { signal(signum, handler) } == SIG_ERR {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether `SIGUSR1` has been received since the last call, clearing the request
+pub fn take_status_request() -> bool {
+    STATUS_REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+/// Whether `SIGTERM` or `SIGINT` has been received since the handler was installed
+pub fn terminate_requested() -> bool {
+    TERMINATE_REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Sleep for `duration`, returning early with `false` if a termination signal arrives
+pub fn sleep_unless_terminated(duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while !terminate_requested() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return true;
+        }
+        std::thread::sleep(remaining.min(POLL_INTERVAL));
+    }
+    false
+}
@@ -0,0 +1,119 @@
+//! A record of the last completed run, for monitoring systems and the `status` subcommand
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::json::Json;
+use crate::output::RunSummary;
+use crate::procfs;
+
+/// Default path for the state file
+pub const STATE_FILE_PATH: &str = "/var/lib/update_nvidia/state.json";
+
+/// What the state file records about the last completed run
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+    /// When the run finished, as an RFC 3339 UTC timestamp
+    pub last_run_utc: String,
+    /// Whether any eligible package was changed
+    pub upgraded: bool,
+    /// Eligible packages and their versions before the upgrade
+    pub old_versions: BTreeMap<String, String>,
+    /// Eligible packages and their versions after the upgrade
+    pub new_versions: BTreeMap<String, String>,
+    /// What was done to make the upgrade take effect, as in `--json` output
+    pub action_taken: String,
+    /// Whether the upgraded module is still waiting for a reboot (`--no-module-reload`)
+    pub reboot_required: bool,
+    /// [`crate::procfs::boot_id`] at the time of the run, so a later run can tell whether the
+    /// reboot has happened since
+    pub boot_id: Option<String>,
+}
+
+impl State {
+    /// Capture the parts of a run's summary worth persisting
+    pub fn from_summary(
+        summary: &RunSummary,
+        last_run_utc: String,
+        boot_id: Option<String>,
+    ) -> Self {
+        Self {
+            last_run_utc,
+            upgraded: summary.upgraded,
+            old_versions: summary.old_versions.clone(),
+            new_versions: summary.new_versions.clone(),
+            action_taken: summary.action.as_str().to_owned(),
+            reboot_required: summary.reboot_required,
+            boot_id,
+        }
+    }
+
+    /// Whether [`State::reboot_required`] still applies, because the system hasn't rebooted since
+    /// (ie. the boot ID still matches)
+    pub fn reboot_pending(&self) -> bool {
+        self.reboot_required && self.boot_id.is_some() && self.boot_id == procfs::boot_id().ok()
+    }
+
+    /// Render the state as a single JSON object
+    pub fn to_json(&self) -> Json {
+        let mut fields = vec![
+            ("last_run_utc", self.last_run_utc.as_str().into()),
+            ("upgraded", self.upgraded.into()),
+            ("old_versions", (&self.old_versions).into()),
+            ("new_versions", (&self.new_versions).into()),
+            ("action_taken", self.action_taken.as_str().into()),
+            ("reboot_required", self.reboot_required.into()),
+        ];
+        if let Some(boot_id) = &self.boot_id {
+            fields.push(("boot_id", boot_id.as_str().into()));
+        }
+        Json::object(fields)
+    }
+
+    /// The inverse of [`State::to_json`]
+    pub fn from_json(json: &Json) -> Result<Self, String> {
+        let field = |key| json.get(key).ok_or_else(|| format!("missing field {:?}", key));
+        let string = |key| {
+            field(key)?
+                .as_str()
+                .map(str::to_owned)
+                .ok_or_else(|| format!("{:?} isn't a string", key))
+        };
+        let versions =
+            |key| field(key)?.to_string_map().ok_or_else(|| format!("invalid {:?}", key));
+        Ok(Self {
+            last_run_utc: string("last_run_utc")?,
+            upgraded: field("upgraded")?.as_bool().ok_or("\"upgraded\" isn't a boolean")?,
+            old_versions: versions("old_versions")?,
+            new_versions: versions("new_versions")?,
+            action_taken: string("action_taken")?,
+            // Neither is in state files written before `--no-module-reload` existed
+            reboot_required: json.get("reboot_required").and_then(Json::as_bool).unwrap_or(false),
+            boot_id: json.get("boot_id").and_then(Json::as_str).map(str::to_owned),
+        })
+    }
+
+    /// Load the state file at `path`
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Json::parse(&text)
+            .and_then(|json| Self::from_json(&json))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Replace the state file at `path`, creating its parent directory if necessary
+    ///
+    /// The new contents are written to a temporary file and renamed into place so a monitoring
+    /// system can never see a half-written file.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        fs::write(&temp_path, format!("{}\n", self.to_json()))?;
+        fs::rename(&temp_path, path)
+    }
+}
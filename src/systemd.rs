@@ -0,0 +1,45 @@
+//! Progress reporting to systemd via the `sd_notify` protocol (`--systemd-notify`)
+//!
+//! Implemented by sending datagrams to `$NOTIFY_SOCKET` directly rather than linking
+//! `libsystemd`, to keep this tool free of non-std dependencies.
+
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::log::log;
+
+/// Set by `--systemd-notify` to make [`notify`] actually send anything
+pub static SYSTEMD_NOTIFY: AtomicBool = AtomicBool::new(false);
+
+/// Tell systemd what the service is currently doing, as shown by `systemctl status`
+pub fn status(message: &str) {
+    notify(&format!("STATUS={}", message));
+}
+
+/// Send `state` (eg. `READY=1`) to the service manager, if `--systemd-notify` was given
+///
+/// Does nothing if `NOTIFY_SOCKET` isn't set (ie. not running under systemd). Progress reports
+/// aren't worth failing the upgrade over, so the first send error is logged and then further
+/// notifications are disabled.
+pub fn notify(state: &str) {
+    if !SYSTEMD_NOTIFY.load(Ordering::Relaxed) {
+        return;
+    }
+    let Some(socket_path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        // A leading `@` denotes a socket in the abstract namespace rather than the filesystem
+        let addr = match socket_path.as_encoded_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&socket_path)?,
+        };
+        socket.send_to_addr(state.as_bytes(), &addr)
+    });
+    if let Err(e) = result {
+        log!("ERROR: Could not notify systemd. Disabling --systemd-notify. ({})", e);
+        SYSTEMD_NOTIFY.store(false, Ordering::Relaxed);
+    }
+}
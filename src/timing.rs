@@ -0,0 +1,95 @@
+//! Wall-clock timing of the slow parts of a run, since anything this adds to boot time is worth
+//! being able to pin on a particular step
+
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use crate::progress;
+
+/// Measures the wall-clock time since it was started
+#[derive(Debug, Clone, Copy)]
+pub struct Stopwatch {
+    started: Instant,
+}
+
+impl Stopwatch {
+    /// Start timing from now
+    pub fn start() -> Self {
+        Self { started: Instant::now() }
+    }
+
+    /// How long it's been since [`Stopwatch::start`]
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// A step of the run which is timed separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Refreshing the package index (eg. `apt-get update`)
+    IndexUpdate,
+    /// Checking for and removing the holds on the packages
+    Unhold,
+    /// Applying the upgrade (eg. `apt-get dist-upgrade`)
+    Upgrade,
+    /// Unloading and reloading the kernel module
+    ModuleReload,
+}
+
+impl Phase {
+    /// The identifier used for this phase in machine-readable output
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::IndexUpdate => "index_update",
+            Phase::Unhold => "unhold",
+            Phase::Upgrade => "upgrade",
+            Phase::ModuleReload => "module_reload",
+        }
+    }
+
+    /// How this phase is described in the human-readable summary
+    fn label(self) -> &'static str {
+        match self {
+            Phase::IndexUpdate => "index update",
+            Phase::Unhold => "unhold",
+            Phase::Upgrade => "upgrade",
+            Phase::ModuleReload => "module reload",
+        }
+    }
+}
+
+thread_local! {
+    /// Every phase timed so far, in the order they finished
+    static TIMINGS: RefCell<Vec<(Phase, Duration)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Call `f`, recording how long it took as `phase` whether or not it succeeded
+///
+/// The phase is also reported as the current operation in the `SIGUSR1` status dump while `f`
+/// runs.
+pub fn time<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    let stopwatch = Stopwatch::start();
+    progress::set_phase(Some(phase));
+    let result = f();
+    progress::set_phase(None);
+    TIMINGS.with(|timings| timings.borrow_mut().push((phase, stopwatch.elapsed())));
+    result
+}
+
+/// Remove and return every timing recorded so far
+pub fn take_timings() -> Vec<(Phase, Duration)> {
+    TIMINGS.with(|timings| timings.take())
+}
+
+/// Render the phases and the total as one line (eg. `index update: 4.2s; upgrade: 12.1s; module
+/// reload: 0.8s; total: 17.1s`)
+pub fn format_timings(timings: &[(Phase, Duration)], total: Duration) -> String {
+    timings
+        .iter()
+        .map(|(phase, elapsed)| (phase.label(), *elapsed))
+        .chain([("total", total)])
+        .map(|(label, elapsed)| format!("{}: {:.1}s", label, elapsed.as_secs_f64()))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
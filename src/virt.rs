@@ -0,0 +1,98 @@
+//! Virtual machine and container detection, since reloading the kernel module is meaningless
+//! (or impossible) without an NVIDIA GPU and a kernel of one's own
+
+use std::fs;
+use std::path::Path;
+
+/// Where the CPU flags (including `hypervisor` in a VM) are listed
+const CPUINFO_PATH: &str = "/proc/cpuinfo";
+
+/// The hardware vendor reported by the firmware, which names the hypervisor in a VM
+const DMI_SYS_VENDOR_PATH: &str = "/sys/class/dmi/id/sys_vendor";
+
+/// Written by systemd-nspawn, LXC, podman, and others with the container manager's name
+const SYSTEMD_CONTAINER_PATH: &str = "/run/systemd/container";
+
+/// Created by Docker at the root of every container
+const DOCKERENV_PATH: &str = "/.dockerenv";
+
+/// Created by podman in every container
+const CONTAINERENV_PATH: &str = "/run/.containerenv";
+
+/// Where PCI devices are listed, to find GPUs passed through to a VM
+const PCI_DEVICES_DIR: &str = "/sys/bus/pci/devices";
+
+/// The PCI vendor ID assigned to NVIDIA, as written in `/sys/bus/pci/devices/*/vendor`
+const NVIDIA_PCI_VENDOR_ID: &str = "0x10de";
+
+/// What kind of machine this is running on, as far as the kernel module is concerned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VirtualizationKind {
+    /// Bare metal
+    Physical,
+    /// A virtual machine, with the hypervisor's vendor name (if known)
+    Vm(String),
+    /// A container sharing the host's kernel, with the container manager's name
+    Container(String),
+}
+
+impl std::fmt::Display for VirtualizationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Physical => write!(f, "physical hardware"),
+            Self::Vm(vendor) => write!(f, "a virtual machine ({})", vendor),
+            Self::Container(manager) => write!(f, "a container ({})", manager),
+        }
+    }
+}
+
+/// Work out whether this is a VM, a container, or bare metal
+///
+/// Containers are checked for first, since they see the host's `/proc/cpuinfo`.
+pub fn detect_virtualization() -> VirtualizationKind {
+    if let Ok(manager) = fs::read_to_string(SYSTEMD_CONTAINER_PATH) {
+        return VirtualizationKind::Container(manager.trim().to_owned());
+    }
+    if Path::new(DOCKERENV_PATH).exists() {
+        return VirtualizationKind::Container("docker".to_owned());
+    }
+    if Path::new(CONTAINERENV_PATH).exists() {
+        return VirtualizationKind::Container("podman".to_owned());
+    }
+
+    let has_hypervisor_flag = fs::read_to_string(CPUINFO_PATH).is_ok_and(|cpuinfo| {
+        cpuinfo
+            .lines()
+            .filter(|line| line.starts_with("flags"))
+            .any(|line| line.split_whitespace().any(|flag| flag == "hypervisor"))
+    });
+    if has_hypervisor_flag {
+        let vendor = fs::read_to_string(DMI_SYS_VENDOR_PATH)
+            .map(|vendor| vendor.trim().to_owned())
+            .unwrap_or_else(|_| "unknown hypervisor".to_owned());
+        return VirtualizationKind::Vm(vendor);
+    }
+    VirtualizationKind::Physical
+}
+
+/// Check whether an NVIDIA PCI device is visible (eg. a GPU passed through to a VM)
+fn has_nvidia_pci_device() -> bool {
+    let Ok(entries) = fs::read_dir(PCI_DEVICES_DIR) else {
+        return false;
+    };
+    entries.filter_map(Result::ok).any(|entry| {
+        fs::read_to_string(entry.path().join("vendor"))
+            .is_ok_and(|vendor| vendor.trim() == NVIDIA_PCI_VENDOR_ID)
+    })
+}
+
+/// If this is a container, or a VM without a GPU passed through, say which
+///
+/// In either case, there's no kernel module for this system to reload (or reboot to load).
+pub fn reload_blocker() -> Option<VirtualizationKind> {
+    match detect_virtualization() {
+        VirtualizationKind::Physical => None,
+        VirtualizationKind::Vm(_) if has_nvidia_pci_device() => None,
+        kind => Some(kind),
+    }
+}